@@ -0,0 +1,135 @@
+use std::{env, fs, path::PathBuf};
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use marksage::{
+    archive::{archive_mdast, ArchiveOptions},
+    format_files::{format_document, format_files, FormatOptions},
+    markdown_file::MdastDocument,
+    util::WalkOptions,
+};
+use rayon::prelude::*;
+
+const SMALL: &str = include_str!("fixtures/small.md");
+
+/// A todo note built from `sections` sections of mixed content, with deeply nested lists
+fn generated_document(sections: usize) -> String {
+    let mut document = String::from("---\ncreated: 2023-08-01\n---\n#todo\n\n# Projects\n\n");
+    for section in 0..sections {
+        document.push_str(&format!("## Section {section}\n\n"));
+        document.push_str(
+            "Some notes about this section -- with *emphasis*, `code` and a [[link]].\n\n",
+        );
+        for item in 0..10 {
+            let checked = if (section + item) % 3 == 0 { 'x' } else { ' ' };
+            document.push_str(&format!("- [{checked}] task {section}.{item}\n"));
+            for sub in 0..3 {
+                let checked = if (item + sub) % 2 == 0 { 'x' } else { ' ' };
+                document.push_str(&format!(
+                    "    - [{checked}] subtask {section}.{item}.{sub}\n"
+                ));
+            }
+        }
+        document.push_str("\n1. first\n2. second\n\n| a | b |\n| - | - |\n| 1 | 2 |\n\n");
+    }
+    document
+}
+
+/// The number of notes in the synthetic vault, `MARKSAGE_BENCH_VAULT_SIZE` or 500 by default
+fn vault_size() -> usize {
+    env::var("MARKSAGE_BENCH_VAULT_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(500)
+}
+
+/// A vault of small notes spread over a few folders, with a large note in every folder
+fn synthetic_vault() -> tempfile::TempDir {
+    let vault = tempfile::tempdir().unwrap();
+    let large = generated_document(200);
+    for folder in 0..10 {
+        let folder_path = vault.path().join(format!("folder {folder}"));
+        fs::create_dir_all(&folder_path).unwrap();
+        fs::write(folder_path.join("large.md"), &large).unwrap();
+    }
+    for note in 0..vault_size() {
+        fs::write(
+            vault
+                .path()
+                .join(format!("folder {}", note % 10))
+                .join(format!("note {note}.md")),
+            SMALL,
+        )
+        .unwrap();
+    }
+    vault
+}
+
+/// Notes of increasing size, from the small fixture to a generated note of a few hundred kb
+fn documents() -> [(&'static str, String); 4] {
+    [
+        ("small", SMALL.to_string()),
+        ("medium", generated_document(10)),
+        ("large", generated_document(50)),
+        ("huge", generated_document(200)),
+    ]
+}
+
+fn parse(c: &mut Criterion) {
+    for (name, content) in documents() {
+        c.bench_function(&format!("parse {name}"), |b| {
+            b.iter(|| MdastDocument::parse(black_box(&content)))
+        });
+    }
+}
+
+fn render(c: &mut Criterion) {
+    for (name, content) in documents() {
+        let document = MdastDocument::parse(&content);
+        c.bench_function(&format!("render {name}"), |b| {
+            b.iter(|| black_box(&document).render())
+        });
+    }
+}
+
+fn archive(c: &mut Criterion) {
+    let options = ArchiveOptions::default();
+    for (name, content) in documents() {
+        let document = MdastDocument::parse(&content);
+        c.bench_function(&format!("archive {name}"), |b| {
+            b.iter(|| archive_mdast(black_box(&document.root), &options))
+        });
+    }
+}
+
+fn format(c: &mut Criterion) {
+    let options = FormatOptions::default();
+    for (name, content) in documents() {
+        let document = MdastDocument::parse(&content);
+        c.bench_function(&format!("format {name}"), |b| {
+            b.iter_batched(
+                || MdastDocument {
+                    root: document.root.clone(),
+                },
+                |document| format_document(document, &options).render(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+}
+
+fn vault(c: &mut Criterion) {
+    let vault = synthetic_vault();
+    let vault_path = PathBuf::from(vault.path());
+    let walk = WalkOptions::default();
+    let options = FormatOptions::default();
+
+    let mut group = c.benchmark_group("vault");
+    group.sample_size(10);
+    group.bench_function("format", |b| {
+        b.iter(|| format_files(&vault_path, &walk, &options).count())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse, render, archive, format, vault);
+criterion_main!(benches);
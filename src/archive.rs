@@ -1,10 +1,420 @@
+use chrono::{Datelike, NaiveDate};
+use clap::{Args, ValueEnum};
 use markdown::mdast::{self, Node};
 use rayon::iter::ParallelIterator;
-use std::path::PathBuf;
+use regex::Regex;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
-use crate::{markdown_file::MdastDocument, util::iterate_tagged_markdown_files};
+use crate::{
+    config::Configs,
+    error::{Error, Result},
+    markdown_file::{list_item_text, split_block_id, MdastDocument, RenderOptions},
+    util::{iterate_tagged_markdown_files, note_link, text_contains_tag, DateOptions, WalkOptions},
+};
 
-fn archive_mdast(mdast: &mdast::Root) -> Option<mdast::Root> {
+#[derive(Args, Debug, Default, Clone)]
+pub struct ArchiveOptions {
+    /// Only archive top level items that are checked, ignoring their children
+    #[arg(long, default_value = "false")]
+    pub archive_strict: bool,
+
+    /// Move archived todos to the end of this file, relative to the vault, instead of an Archived section
+    #[arg(long, value_name = "FILE")]
+    pub to_file: Option<PathBuf>,
+
+    /// Don't archive anything, exit with 1 if any file has todos to archive and 0 otherwise
+    #[arg(long, default_value = "false")]
+    pub check: bool,
+
+    /// Add a link back to the note each todo was archived from, like `(from [[note]])`
+    #[arg(long, default_value = "false", requires = "to_file")]
+    pub backlink: bool,
+
+    /// Merge every Archived section after the first into the first
+    #[arg(long, default_value = "false")]
+    pub merge_duplicate_archives: bool,
+
+    /// Only archive from the Nth list, counting from 1, or the lists under the heading with this
+    /// title
+    #[arg(long, value_name = "N|HEADING", value_parser = parse_list_selector)]
+    pub list_selector: Option<ListSelector>,
+
+    /// Archive the todos under each top level heading, or under each heading below a lone title,
+    /// into an Archived heading one level deeper in that section, instead of one Archived section
+    /// for the whole note
+    #[arg(long, default_value = "false", conflicts_with = "to_file")]
+    pub archive_per_section: bool,
+
+    /// Where newly archived todos go in an Archived section that already has some, the top unless
+    /// `archive.append` in `marksage.toml` says otherwise
+    #[arg(long, value_enum, value_name = "WHERE")]
+    pub archive_append: Option<ArchivePlacement>,
+
+    /// How many blank lines go before and after the Archived heading, one unless
+    /// `archive.blank_lines` in `marksage.toml` says otherwise
+    #[arg(long, value_name = "N")]
+    pub archive_blank_lines: Option<usize>,
+
+    /// Group newly archived todos under a subheading of the Archived section for the day, ISO
+    /// week or month they were archived in, like `### 2024-W03`
+    #[arg(long, value_enum, default_value_t = GroupBy::None, conflicts_with = "to_file")]
+    pub group_by: GroupBy,
+
+    /// Only archive into an Archived section the note already has, leaving notes without one alone
+    #[arg(long, default_value = "false", conflicts_with = "to_file")]
+    pub require_existing_section: bool,
+
+    /// Nest archived todos under an item named for the heading of the list they came from, so
+    /// todos from the same list stay together
+    #[arg(long, default_value = "false", conflicts_with = "to_file")]
+    pub archive_group_by_source: bool,
+
+    /// Keep checked todos whose text contains this marker where they are, can be given more than
+    /// once, with a tag like `#pinned` matched as a whole tag
+    #[arg(
+        long = "pin-marker",
+        value_name = "MARKER",
+        value_parser = parse_pin_marker,
+        default_values = ["📌", "#pinned"]
+    )]
+    pub pin_markers: Vec<PinMarker>,
+
+    /// Stop after archiving this many todos in the whole run, leaving the rest for later runs
+    #[arg(long, value_name = "N")]
+    pub max_items: Option<usize>,
+
+    /// How many todos have been archived from the file so far
+    #[arg(skip)]
+    pub archived: Arc<AtomicUsize>,
+
+    /// The day todos are archived on, for `--group-by`, today unless set
+    #[arg(skip)]
+    pub date: Option<NaiveDate>,
+
+    /// How the headings of `--group-by` show the day or month
+    #[arg(skip)]
+    pub dates: DateOptions,
+
+    /// The configs of the vault, applied to each file archived
+    #[arg(skip)]
+    pub configs: Configs,
+}
+
+/// The period archived todos are grouped by
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum GroupBy {
+    /// `2024-01-17`, or in the `--date-format`
+    Day,
+    /// `2024-W03`, by ISO week, so the year is that of the week's Thursday
+    Week,
+    /// `2024-01`, or with a `--locale` the month's name, like `Januar 2024`
+    Month,
+    /// Directly in the Archived section
+    #[default]
+    None,
+}
+
+impl GroupBy {
+    /// The title of the subheading for todos archived on `date`
+    fn key(self, date: NaiveDate, dates: &DateOptions) -> Option<String> {
+        match self {
+            GroupBy::Day => Some(dates.format_date(date)),
+            GroupBy::Week => {
+                let week = date.iso_week();
+                Some(format!("{}-W{:02}", week.year(), week.week()))
+            }
+            GroupBy::Month => Some(dates.format_month(date)),
+            GroupBy::None => None,
+        }
+    }
+}
+
+/// Where moved items go in a section's existing list
+#[derive(ValueEnum, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ArchivePlacement {
+    /// Before the items already there, so the most recent are first
+    #[default]
+    Top,
+    /// After the items already there, so they accumulate in the order they were archived
+    Bottom,
+}
+
+/// Which of the lists before the Archived section to archive from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListSelector {
+    /// The Nth top level list, counting from 1
+    Index(usize),
+    /// Every list whose closest heading above it has this title
+    Heading(String),
+}
+
+fn parse_list_selector(arg: &str) -> Result<ListSelector> {
+    match arg.parse::<usize>() {
+        Ok(0) => Err(Error::Parse("lists are counted from 1".to_string())),
+        Ok(index) => Ok(ListSelector::Index(index)),
+        Err(_) if arg.trim().is_empty() => Err(Error::Parse(
+            "expected a list number or heading".to_string(),
+        )),
+        Err(_) => Ok(ListSelector::Heading(arg.trim().to_string())),
+    }
+}
+
+impl ListSelector {
+    /// Whether the list at `index` of `children` is selected
+    fn selects(&self, children: &[Node], index: usize) -> bool {
+        match self {
+            ListSelector::Index(n) => {
+                children[..index]
+                    .iter()
+                    .filter(|node| matches!(node, Node::List(_)))
+                    .count()
+                    + 1
+                    == *n
+            }
+            ListSelector::Heading(title) => children[..index]
+                .iter()
+                .rev()
+                .find_map(|node| match node {
+                    Node::Heading(heading) => Some(heading),
+                    _ => None,
+                })
+                .map_or(false, |heading| {
+                    matches!(heading.children.first(), Some(Node::Text(text)) if &text.value == title)
+                }),
+        }
+    }
+}
+
+/// A marker that keeps a checked todo where it is
+#[derive(Debug, Clone)]
+pub enum PinMarker {
+    /// A tag, like `#pinned`, which longer tags like `#pinned-later` don't match
+    Tag(Regex),
+    /// Any other text, like `📌`
+    Text(String),
+}
+
+fn parse_pin_marker(arg: &str) -> Result<PinMarker> {
+    match arg.strip_prefix('#') {
+        Some(tag)
+            if !tag.is_empty()
+                && tag
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '/')) =>
+        {
+            Ok(PinMarker::Tag(text_contains_tag(tag)?))
+        }
+        _ => Ok(PinMarker::Text(arg.to_string())),
+    }
+}
+
+impl PinMarker {
+    fn is_in(&self, text: &str) -> bool {
+        match self {
+            PinMarker::Tag(is_tag) => is_tag.is_match(text),
+            PinMarker::Text(marker) => text.contains(marker.as_str()),
+        }
+    }
+}
+
+/// How moved items are added to the list of a section
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Insertion {
+    placement: ArchivePlacement,
+    /// Nest the items under an item named for the heading above the list they came from
+    by_source: bool,
+}
+
+impl ArchiveOptions {
+    /// Count a todo as archived, or return false if `max_items` have been already
+    fn claim_item(&self) -> bool {
+        self.archived
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                self.max_items.map_or(true, |max| n < max).then_some(n + 1)
+            })
+            .is_ok()
+    }
+
+    /// These options for archiving a single file, counting its todos from zero and stopping after
+    /// `max_items` of them
+    fn for_file(&self, max_items: Option<usize>) -> ArchiveOptions {
+        ArchiveOptions {
+            max_items,
+            archived: Arc::default(),
+            ..self.clone()
+        }
+    }
+
+    /// How to render a note todos were archived from, spacing its Archived headings
+    fn render_options(&self) -> RenderOptions<'static> {
+        RenderOptions {
+            heading_spacing: self
+                .archive_blank_lines
+                .map(|blank_lines| ("Archived", blank_lines)),
+            ..Default::default()
+        }
+    }
+
+    fn insertion(&self) -> Insertion {
+        Insertion {
+            placement: self.archive_append.unwrap_or_default(),
+            by_source: self.archive_group_by_source,
+        }
+    }
+
+    /// The subheading to archive todos under, if they're grouped
+    fn group_heading(&self) -> Option<String> {
+        self.group_by.key(
+            self.date
+                .unwrap_or_else(|| chrono::Local::now().date_naive()),
+            &self.dates,
+        )
+    }
+
+    /// Whether the item's own text, not that of the items nested in it, has a pin marker
+    fn is_pinned(&self, list_item: &mdast::ListItem) -> bool {
+        let text = list_item
+            .children
+            .iter()
+            .filter(|node| !matches!(node, Node::List(_)))
+            .map(Node::to_string)
+            .collect::<String>();
+        self.pin_markers.iter().any(|marker| marker.is_in(&text))
+    }
+
+    fn selects_list(&self, children: &[Node], index: usize) -> bool {
+        self.list_selector
+            .as_ref()
+            .map_or(true, |selector| selector.selects(children, index))
+    }
+}
+
+pub fn archive_mdast(mdast: &mdast::Root, options: &ArchiveOptions) -> Option<mdast::Root> {
+    if options.archive_per_section {
+        return archive_per_section(mdast, options);
+    }
+    archive_into_section(
+        mdast,
+        2,
+        |children, index| options.selects_list(children, index),
+        options,
+    )
+}
+
+/// Archive into the Archived heading of `depth`, merging its duplicates first if asked
+fn archive_into_section(
+    mdast: &mdast::Root,
+    depth: u8,
+    is_selected: impl Fn(&[Node], usize) -> bool,
+    options: &ArchiveOptions,
+) -> Option<mdast::Root> {
+    if options.require_existing_section
+        && find_section(&mdast.children, "Archived", depth).is_none()
+    {
+        return None;
+    }
+    let merged = options
+        .merge_duplicate_archives
+        .then(|| merge_duplicate_sections(mdast, "Archived", depth))
+        .flatten();
+    let group = options.group_heading();
+    let archive = |mdast: &mdast::Root| match &group {
+        Some(group) => move_selected_list_items_grouped(
+            mdast,
+            "Archived",
+            depth,
+            group,
+            options.insertion(),
+            &is_selected,
+            |node| is_archivable(node, options) && options.claim_item(),
+        ),
+        None => move_selected_list_items(
+            mdast,
+            "Archived",
+            depth,
+            options.insertion(),
+            &is_selected,
+            |node| is_archivable(node, options) && options.claim_item(),
+        ),
+    };
+    match merged {
+        Some(merged) => Some(archive(&merged).unwrap_or(merged)),
+        None => archive(mdast),
+    }
+}
+
+/// Archive within the scope of each heading at the shallowest depth with more than one, so the
+/// sections under a lone title heading are split, into an Archived heading one level deeper,
+/// leaving anything before the first of them alone
+///
+/// Sections under a depth 6 heading can't have a deeper one, so those notes are archived into a
+/// single Archived section as usual.
+fn archive_per_section(mdast: &mdast::Root, options: &ArchiveOptions) -> Option<mdast::Root> {
+    let mut counts = [0; 6];
+    for node in &mdast.children {
+        if let Node::Heading(heading) = node {
+            counts[usize::from(heading.depth) - 1] += 1;
+        }
+    }
+    // without a depth that has several headings, split at the deepest of the lone ones
+    let index = counts
+        .iter()
+        .position(|&count| count > 1)
+        .or_else(|| counts.iter().rposition(|&count| count > 0))?;
+    let depth = u8::try_from(index + 1).unwrap();
+    if depth == 6 {
+        return archive_into_section(
+            mdast,
+            2,
+            |children, index| options.selects_list(children, index),
+            options,
+        );
+    }
+    let starts = mdast
+        .children
+        .iter()
+        .enumerate()
+        .filter(|(_, node)| matches!(node, Node::Heading(heading) if heading.depth == depth))
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+
+    let mut children = mdast.children[..starts[0]].to_vec();
+    let mut archived = false;
+    for (n, &start) in starts.iter().enumerate() {
+        let end = starts.get(n + 1).copied().unwrap_or(mdast.children.len());
+        let section = mdast::Root {
+            children: mdast.children[start + 1..end].to_vec(),
+            position: None,
+        };
+        children.push(mdast.children[start].clone());
+        // lists keep their index into the section, so select them by their place in the note
+        let is_selected =
+            |_: &[Node], index: usize| options.selects_list(&mdast.children, start + 1 + index);
+        match archive_into_section(&section, depth + 1, is_selected, options) {
+            Some(section) => {
+                archived = true;
+                children.extend(section.children);
+            }
+            None => children.extend(section.children),
+        }
+    }
+
+    archived.then_some(mdast::Root {
+        children,
+        position: None,
+    })
+}
+
+fn is_archivable(node: &Node, options: &ArchiveOptions) -> bool {
     enum Assessment {
         Is(bool),
         Maybe,
@@ -39,163 +449,651 @@ fn archive_mdast(mdast: &mdast::Root) -> Option<mdast::Root> {
         }
     }
 
-    fn should_archive(node: &Node) -> Assessment {
+    fn should_archive(node: &Node, options: &ArchiveOptions) -> Assessment {
         match node {
+            Node::ListItem(list_item) if options.is_pinned(list_item) => Assessment::Is(false),
             Node::ListItem(list_item) => match list_item.checked {
                 Some(true) => list_item
                     .children
                     .iter()
-                    .map(should_archive)
+                    .map(|child| should_archive(child, options))
                     .collect::<Assessment>()
                     .bias(Assessment::Is(true)),
                 None => list_item
                     .children
                     .iter()
-                    .map(should_archive)
+                    .map(|child| should_archive(child, options))
                     .collect::<Assessment>(),
                 Some(false) => Assessment::Is(false),
             },
-            Node::List(list) => list
-                .children
-                .iter()
-                .map(should_archive)
-                .collect::<Assessment>(),
-            _ => Assessment::Maybe,
+            // todos can also be nested in quotes and other containers within an item
+            _ => node.children().map_or(Assessment::Maybe, |children| {
+                children
+                    .iter()
+                    .map(|child| should_archive(child, options))
+                    .collect::<Assessment>()
+            }),
+        }
+    }
+
+    match node {
+        Node::ListItem(list_item) if options.archive_strict => {
+            list_item.checked == Some(true) && !options.is_pinned(list_item)
+        }
+        Node::ListItem(_) => should_archive(node, options).definitively(),
+        _ => false,
+    }
+}
+
+/// The depth of the node if it's a heading titled `title`
+fn section_depth(node: &Node, title: &str) -> Option<u8> {
+    match node {
+        Node::Heading(heading) if matches!(heading.children.first(), Some(Node::Text(text)) if text.value == title) => {
+            Some(heading.depth)
         }
+        _ => None,
     }
+}
 
-    let mut new_mdast: Vec<Node> = mdast.children.clone();
+fn is_section(node: &Node, title: &str, depth: u8) -> bool {
+    section_depth(node, title) == Some(depth)
+}
 
-    // find or create the archived section
-    let archived_section = new_mdast
+/// Find the heading of `depth` titled `title`, or reuse the first one titled `title` at another
+/// depth rather than adding a second section
+fn find_section(children: &[Node], title: &str, depth: u8) -> Option<usize> {
+    children
         .iter()
-        .enumerate()
-        .find(|(_, node)| match node {
-            Node::Heading(heading) => heading.depth == 2 && matches!(heading.children.first(), Some(Node::Text(text)) if text.value == "Archived"),
-            _ => false,
-        })
-        .map(|(index, _)| index)
-        .unwrap_or_else(|| {
-            let archived_heading = mdast::Heading {
-                depth: 2,
-                children: vec![Node::Text(mdast::Text {
-                    value: "Archived".to_string(),
-                    position: None,
-                })],
-                position: None,
-            };
-            // find the last list
-            let last_list = new_mdast
+        .position(|node| is_section(node, title, depth))
+        .or_else(|| {
+            children
                 .iter()
-                .enumerate()
-                .rev()
-                .find(|(_, node)| matches!(node, Node::List(_)))
-                .map_or_else(|| new_mdast.len(), |(index, _)| index + 1);
+                .position(|node| section_depth(node, title).is_some())
+        })
+}
+
+/// The depth of the section titled `title` that `find_section` reuses, if it isn't `depth`
+fn mismatched_section_depth(children: &[Node], title: &str, depth: u8) -> Option<u8> {
+    find_section(children, title, depth)
+        .and_then(|index| section_depth(&children[index], title))
+        .filter(|&found| found != depth)
+}
+
+/// Remove every heading titled `title` after the one `find_section` finds and of the same depth,
+/// moving the list directly under each to the end of the first section's list
+fn merge_duplicate_sections(mdast: &mdast::Root, title: &str, depth: u8) -> Option<mdast::Root> {
+    let first = find_section(&mdast.children, title, depth)?;
+    let depth = section_depth(&mdast.children[first], title)?;
+    let mut children = mdast.children.clone();
+    let mut lists = vec![];
+    let mut merged = false;
 
-            if last_list == new_mdast.len() {
-                new_mdast.push(Node::Heading(archived_heading));
-            } else {
-                new_mdast.insert(last_list, Node::Heading(archived_heading));
+    let mut index = first + 1;
+    while index < children.len() {
+        if !is_section(&children[index], title, depth) {
+            index += 1;
+            continue;
+        }
+        children.remove(index);
+        merged = true;
+        if matches!(children.get(index), Some(Node::List(_))) {
+            if let Node::List(list) = children.remove(index) {
+                lists.push(list);
             }
+        }
+    }
+    if !merged {
+        return None;
+    }
 
-            last_list
-        });
+    for list in lists {
+        match children.get_mut(first + 1) {
+            Some(Node::List(section_list)) => section_list.children.extend(list.children),
+            _ => children.insert(first + 1, Node::List(list)),
+        }
+    }
 
-    let mut to_delete = vec![];
-    for (i, node) in mdast.children.iter().take(archived_section).enumerate() {
-        if let Node::List(list) = node {
-            let archived_children: Vec<_> = list
-                .children
-                .iter()
-                .enumerate()
-                .filter_map(|(j, node)| match node {
-                    Node::ListItem(list_item) if should_archive(node).definitively() => {
-                        Some((j, Node::ListItem(list_item.clone())))
-                    }
-                    _ => None,
-                })
-                .collect();
+    Some(mdast::Root {
+        children,
+        position: None,
+    })
+}
 
-            if archived_children.is_empty() {
-                continue;
-            }
+fn heading(title: &str, depth: u8) -> mdast::Heading {
+    mdast::Heading {
+        depth,
+        children: vec![Node::Text(mdast::Text {
+            value: title.to_string(),
+            position: None,
+        })],
+        position: None,
+    }
+}
 
-            for (j, _) in &archived_children {
-                to_delete.push((i, *j));
-            }
+/// Find the heading of `depth` titled `title`, or create one after the last list
+fn find_or_create_section(children: &mut Vec<Node>, title: &str, depth: u8) -> usize {
+    find_section(children, title, depth).unwrap_or_else(|| {
+        let heading = heading(title, depth);
+        // find the last list
+        let last_list = children
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, node)| matches!(node, Node::List(_)))
+            .map_or_else(|| children.len(), |(index, _)| index + 1);
 
-            let mut new_children: Vec<_> = archived_children
-                .into_iter()
-                .map(|(_, node)| node)
-                .collect();
+        if last_list == children.len() {
+            children.push(Node::Heading(heading));
+        } else {
+            children.insert(last_list, Node::Heading(heading));
+        }
 
-            if new_children.is_empty() {
-                continue;
-            }
+        last_list
+    })
+}
+
+/// Items taken out of a single list, along with that list so its type can be reused
+struct TakenItems {
+    list: mdast::List,
+    items: Vec<Node>,
+    /// The text of the closest heading above the list
+    source: Option<String>,
+}
 
-            match new_mdast.get(archived_section + 1) {
-                Some(Node::List(list)) => {
-                    let mut list = list.clone();
-                    new_children.append(&mut list.children);
-                    list.children = new_children;
-                    new_mdast[archived_section + 1] = Node::List(list);
+/// Remove the items that `should_move` accepts from the top level lists before index `end` that
+/// `is_selected` accepts, dropping lists that end up empty
+///
+/// Returns the remaining nodes and the index `end` has moved to.
+fn take_list_items(
+    children: Vec<Node>,
+    end: usize,
+    is_selected: impl Fn(&[Node], usize) -> bool,
+    should_move: impl Fn(&Node) -> bool,
+) -> (Vec<Node>, usize, Vec<TakenItems>) {
+    let mut taken = vec![];
+    let mut new_end = end;
+    let mut remaining = Vec::with_capacity(children.len());
+    let selected = (0..children.len())
+        .map(|i| is_selected(&children, i))
+        .collect::<Vec<_>>();
+
+    let mut source = None;
+    for (i, node) in children.into_iter().enumerate() {
+        if let Node::Heading(_) = node {
+            source = Some(node.to_string());
+        }
+        match node {
+            Node::List(list) if i < end && selected[i] => {
+                let (items, kept): (Vec<Node>, Vec<Node>) =
+                    list.children.iter().cloned().partition(|n| should_move(n));
+                if items.is_empty() {
+                    remaining.push(Node::List(list));
+                    continue;
                 }
-                _ => {
-                    new_mdast.insert(
-                        archived_section + 1,
-                        Node::List(mdast::List {
-                            children: new_children,
-                            ..list.clone()
-                        }),
-                    );
+                if kept.is_empty() {
+                    new_end -= 1;
+                } else {
+                    remaining.push(Node::List(mdast::List {
+                        children: kept,
+                        ..list.clone()
+                    }));
                 }
+                taken.push(TakenItems {
+                    list,
+                    items,
+                    source: source.clone(),
+                });
             }
+            node => remaining.push(node),
         }
     }
 
-    for (i, j) in to_delete.iter().rev() {
-        if let Node::List(list) = &mut new_mdast[*i] {
-            let mut new_children = list.children.clone();
-            new_children.remove(*j);
-            if new_children.is_empty() {
-                new_mdast.remove(*i);
-            } else {
-                list.children = new_children;
-            }
-        } else {
-            panic!("to_delete target should have been a list");
-        }
+    (remaining, new_end, taken)
+}
+
+/// Move the top level list items before the `section` heading that `should_move` accepts into
+/// the list under that heading, creating the section if needed
+///
+/// Returns `None` if no items were moved.
+pub(crate) fn move_list_items(
+    mdast: &mdast::Root,
+    section: &str,
+    should_move: impl Fn(&Node) -> bool,
+) -> Option<mdast::Root> {
+    move_selected_list_items(
+        mdast,
+        section,
+        2,
+        Insertion::default(),
+        |_, _| true,
+        should_move,
+    )
+}
+
+/// Like `move_list_items`, but into a heading of `depth`, adding the items to those already in
+/// the section as `insertion` says, and only from the lists `is_selected` accepts, given the top
+/// level nodes and the index of the list
+fn move_selected_list_items(
+    mdast: &mdast::Root,
+    section: &str,
+    depth: u8,
+    insertion: Insertion,
+    is_selected: impl Fn(&[Node], usize) -> bool,
+    should_move: impl Fn(&Node) -> bool,
+) -> Option<mdast::Root> {
+    let mut children: Vec<Node> = mdast.children.clone();
+    let section_index = find_or_create_section(&mut children, section, depth);
+    let (mut children, section_index, taken) =
+        take_list_items(children, section_index, is_selected, should_move);
+
+    if taken.is_empty() {
+        return None;
+    }
+    add_to_section_list(&mut children, section_index, taken, insertion);
+
+    Some(mdast::Root {
+        children,
+        position: None,
+    })
+}
+
+/// Like `move_selected_list_items`, but into a subheading titled `group` one level deeper within
+/// the section, found or created at the top or bottom of the section as `insertion` says
+///
+/// A depth 6 section can't have a deeper subheading, so the items go straight into it.
+fn move_selected_list_items_grouped(
+    mdast: &mdast::Root,
+    section: &str,
+    depth: u8,
+    group: &str,
+    insertion: Insertion,
+    is_selected: impl Fn(&[Node], usize) -> bool,
+    should_move: impl Fn(&Node) -> bool,
+) -> Option<mdast::Root> {
+    let mut children: Vec<Node> = mdast.children.clone();
+    let section_index = find_or_create_section(&mut children, section, depth);
+    let depth = section_depth(&children[section_index], section).unwrap_or(depth);
+    if depth == 6 {
+        return move_selected_list_items(
+            mdast,
+            section,
+            depth,
+            insertion,
+            is_selected,
+            should_move,
+        );
     }
+    let (mut children, section_index, taken) =
+        take_list_items(children, section_index, is_selected, should_move);
 
-    if to_delete.is_empty() {
+    if taken.is_empty() {
         return None;
     }
 
+    let section_end = children[section_index + 1..]
+        .iter()
+        .position(|node| matches!(node, Node::Heading(heading) if heading.depth <= depth))
+        .map_or(children.len(), |end| section_index + 1 + end);
+    let group_depth = depth + 1;
+    let group_index = children[section_index + 1..section_end]
+        .iter()
+        .position(|node| is_section(node, group, group_depth))
+        .map(|index| section_index + 1 + index)
+        .unwrap_or_else(|| {
+            // todos archived before grouping stay above the first group, outside of it
+            let index = match insertion.placement {
+                ArchivePlacement::Top => children[section_index + 1..section_end]
+                    .iter()
+                    .position(|node| matches!(node, Node::Heading(_)))
+                    .map_or(section_end, |index| section_index + 1 + index),
+                ArchivePlacement::Bottom => section_end,
+            };
+            children.insert(index, Node::Heading(heading(group, group_depth)));
+            index
+        });
+    add_to_section_list(&mut children, group_index, taken, insertion);
+
     Some(mdast::Root {
-        children: new_mdast,
+        children,
         position: None,
     })
 }
 
+/// Add items to a list, before or after those already in it
+fn add_items(list: &mut Vec<Node>, mut items: Vec<Node>, placement: ArchivePlacement) {
+    match placement {
+        ArchivePlacement::Top => {
+            items.append(list);
+            *list = items;
+        }
+        ArchivePlacement::Bottom => list.append(&mut items),
+    }
+}
+
+/// The list nested in the item of `list` named `source`, adding the item if there isn't one
+fn source_list<'a>(
+    list: &'a mut mdast::List,
+    source: &str,
+    placement: ArchivePlacement,
+) -> &'a mut Vec<Node> {
+    let index = list
+        .children
+        .iter()
+        .position(|item| {
+            matches!(item, Node::ListItem(item) if item.checked.is_none() && list_item_text(item) == source)
+        })
+        .unwrap_or_else(|| {
+            let item = Node::ListItem(mdast::ListItem {
+                children: vec![Node::Paragraph(mdast::Paragraph {
+                    children: vec![Node::Text(mdast::Text {
+                        value: source.to_string(),
+                        position: None,
+                    })],
+                    position: None,
+                })],
+                position: None,
+                spread: false,
+                checked: None,
+            });
+            add_items(&mut list.children, vec![item], placement);
+            match placement {
+                ArchivePlacement::Top => 0,
+                ArchivePlacement::Bottom => list.children.len() - 1,
+            }
+        });
+    let Node::ListItem(item) = &mut list.children[index] else {
+        unreachable!()
+    };
+    if !matches!(item.children.last(), Some(Node::List(_))) {
+        item.children.push(Node::List(mdast::List {
+            children: vec![],
+            position: None,
+            ordered: list.ordered,
+            start: None,
+            spread: false,
+        }));
+    }
+    match item.children.last_mut() {
+        Some(Node::List(nested)) => &mut nested.children,
+        _ => unreachable!(),
+    }
+}
+
+/// Add taken items to the list directly under the heading at `section_index`, starting one with
+/// the type of the first list they were taken from if there isn't one
+fn add_to_section_list(
+    children: &mut Vec<Node>,
+    section_index: usize,
+    mut taken: Vec<TakenItems>,
+    insertion: Insertion,
+) {
+    let placement = insertion.placement;
+    if insertion.by_source && placement == ArchivePlacement::Top {
+        // each list is added above the last, so add them backwards to keep them in order
+        taken.reverse();
+    }
+    for TakenItems {
+        list,
+        items,
+        source,
+    } in taken
+    {
+        if !matches!(children.get(section_index + 1), Some(Node::List(_))) {
+            children.insert(
+                section_index + 1,
+                Node::List(mdast::List {
+                    children: vec![],
+                    ..list
+                }),
+            );
+        }
+        let Some(Node::List(section_list)) = children.get_mut(section_index + 1) else {
+            unreachable!()
+        };
+        match source {
+            Some(source) if insertion.by_source => {
+                add_items(
+                    source_list(section_list, &source, placement),
+                    items,
+                    placement,
+                );
+            }
+            _ => add_items(&mut section_list.children, items, placement),
+        }
+    }
+}
+
+/// Remove the archivable items from a document without adding them anywhere, for archiving to
+/// another file
+fn take_archivable(
+    mdast: &mdast::Root,
+    options: &ArchiveOptions,
+) -> Option<(mdast::Root, Vec<Node>)> {
+    let end = find_section(&mdast.children, "Archived", 2).unwrap_or(mdast.children.len());
+    let (children, _, taken) = take_list_items(
+        mdast.children.clone(),
+        end,
+        |children, index| options.selects_list(children, index),
+        |node| is_archivable(node, options) && options.claim_item(),
+    );
+
+    if taken.is_empty() {
+        return None;
+    }
+
+    Some((
+        mdast::Root {
+            children,
+            position: None,
+        },
+        taken.into_iter().flat_map(|t| t.items).collect(),
+    ))
+}
+
+/// Add `(from link)` to the end of the item's text, before its block id
+fn with_backlink(item: Node, link: &str) -> Node {
+    let Node::ListItem(mut item) = item else {
+        return item;
+    };
+    let suffix = format!(" (from {link})");
+    match item.children.first_mut() {
+        Some(Node::Paragraph(paragraph)) => match paragraph.children.last_mut() {
+            Some(Node::Text(text)) => {
+                let (content, block_id) = split_block_id(&text.value);
+                text.value = format!("{content}{suffix}{block_id}");
+                text.position = None;
+            }
+            _ => paragraph.children.push(Node::Text(mdast::Text {
+                value: suffix,
+                position: None,
+            })),
+        },
+        _ => item.children.insert(
+            0,
+            Node::Paragraph(mdast::Paragraph {
+                children: vec![Node::Text(mdast::Text {
+                    value: suffix.trim_start().to_string(),
+                    position: None,
+                })],
+                position: None,
+            }),
+        ),
+    }
+    Node::ListItem(item)
+}
+
+/// Append items to an archive file, continuing its last list if it ends with one
+fn append_to_archive(content: &str, mut items: Vec<Node>) -> String {
+    let mut document = MdastDocument::parse(content);
+    match document.root.children.last_mut() {
+        Some(Node::List(list)) if !list.ordered => list.children.append(&mut items),
+        _ => document.root.children.push(Node::List(mdast::List {
+            children: items,
+            position: None,
+            ordered: false,
+            start: None,
+            spread: false,
+        })),
+    }
+    document.render()
+}
+
+/// How many todos each file may archive under `--max-items`, given out to the files in order of
+/// their paths so every run archives the same todos, or `None` without a limit
+fn item_budgets(
+    vault_path: &Path,
+    walk: &WalkOptions,
+    options: &ArchiveOptions,
+) -> Option<HashMap<PathBuf, usize>> {
+    let mut remaining = options.max_items?;
+    let mut counts = iterate_tagged_markdown_files(&vault_path.to_path_buf(), walk, "todo")
+        .map(|file| {
+            let options = file_options(options, &None, &file.path);
+            archive_mdast(&MdastDocument::parse(&file.content).root, &options);
+            (file.path, options.archived.load(Ordering::SeqCst))
+        })
+        .collect::<Vec<_>>();
+    counts.sort();
+    Some(
+        counts
+            .into_iter()
+            .map(|(path, count)| {
+                let budget = count.min(remaining);
+                remaining -= budget;
+                (path, budget)
+            })
+            .collect(),
+    )
+}
+
+/// The options for archiving the file at `path`, with the configs of its folders and its share of
+/// `--max-items`
+fn file_options(
+    options: &ArchiveOptions,
+    budgets: &Option<HashMap<PathBuf, usize>>,
+    path: &Path,
+) -> ArchiveOptions {
+    let max_items = budgets
+        .as_ref()
+        .map(|budgets| budgets.get(path).copied().unwrap_or_default());
+    options
+        .configs
+        .archive_options(path, options)
+        .for_file(max_items)
+}
+
+/// Archive todos from every tagged file into the `target` file, relative to the vault
+///
+/// Unlike `archive`, this has to see every file before the target can be written, so it returns
+/// all the changes at once, the target last.
+#[must_use]
+pub fn archive_to_file(
+    vault_path: &Path,
+    walk: &WalkOptions,
+    options: &ArchiveOptions,
+    target: &Path,
+) -> Vec<(PathBuf, String)> {
+    let target_path = vault_path.join(target);
+    let budgets = item_budgets(vault_path, walk, options);
+    let mut archived = iterate_tagged_markdown_files(&vault_path.to_path_buf(), walk, "todo")
+        .filter(|file| file.path != target_path)
+        .filter_map(|file| {
+            let options = file_options(options, &budgets, &file.path);
+            take_archivable(&MdastDocument::parse(file.content.as_str()).root, &options).map(
+                |(mdast, mut items)| {
+                    if options.backlink {
+                        let link = note_link(vault_path, &file.path);
+                        items = items
+                            .into_iter()
+                            .map(|item| with_backlink(item, &link))
+                            .collect();
+                    }
+                    let content =
+                        MdastDocument { root: mdast }.render_with(options.render_options());
+                    (file.path, content, items)
+                },
+            )
+        })
+        .collect::<Vec<_>>();
+
+    if archived.is_empty() {
+        return vec![];
+    }
+    archived.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut items = vec![];
+    let mut changes = archived
+        .into_iter()
+        .map(|(path, content, mut taken)| {
+            items.append(&mut taken);
+            (path, content)
+        })
+        .collect::<Vec<_>>();
+    let existing = walk.encoding.read(&target_path).unwrap_or_default();
+    changes.push((target_path, append_to_archive(&existing, items)));
+    changes
+}
+
 #[must_use]
-pub fn archive(vault_path: &PathBuf) -> impl ParallelIterator<Item = (PathBuf, String)> {
-    iterate_tagged_markdown_files(vault_path, "todo")
+pub fn archive<'a>(
+    vault_path: &PathBuf,
+    walk: &WalkOptions,
+    options: &'a ArchiveOptions,
+) -> impl ParallelIterator<Item = (PathBuf, String)> + 'a {
+    let budgets = item_budgets(vault_path, walk, options);
+    iterate_tagged_markdown_files(vault_path, walk, "todo")
         .map(|file| (file.path, MdastDocument::parse(file.content.as_str())))
-        .filter_map(|(path, document)| {
-            archive_mdast(&document.root)
-                .map(|mdast| (path, MdastDocument { root: mdast }.render()))
+        .filter_map(move |(path, document)| {
+            let options = file_options(options, &budgets, &path);
+            let mdast = archive_mdast(&document.root, &options)?;
+            if !options.archive_per_section {
+                if let Some(depth) =
+                    mismatched_section_depth(&document.root.children, "Archived", 2)
+                {
+                    eprintln!(
+                        "warning: archiving into the depth {depth} Archived heading of {}",
+                        path.display()
+                    );
+                }
+            }
+            let content = MdastDocument { root: mdast }.render_with(options.render_options());
+            Some((path, content))
+        })
+}
+
+/// Print every file with todos to archive, returning the exit code for `--check`
+///
+/// Every file with todos to archive is printed, whether or not `--max-items` would leave them for
+/// a later run.
+#[must_use]
+pub fn check_archive(vault_path: &PathBuf, walk: &WalkOptions, options: &ArchiveOptions) -> i32 {
+    let mut paths = iterate_tagged_markdown_files(vault_path, walk, "todo")
+        .filter(|file| {
+            let options = file_options(options, &None, &file.path);
+            archive_mdast(&MdastDocument::parse(&file.content).root, &options).is_some()
         })
+        .map(|file| file.path)
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    for path in &paths {
+        println!("{} has todos to archive", path.display());
+    }
+    i32::from(!paths.is_empty())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::fs;
+
+    use chrono::Locale;
     use indoc::indoc;
     use pretty_assertions::assert_eq;
 
     macro_rules! test_archive {
-      ($($name:ident $input:expr => $expected:expr)*) => {
+      ($($name:ident $(where $options:expr;)? $input:expr => $expected:expr)*) => {
         $(
             #[test]
             fn $name() {
@@ -204,9 +1102,12 @@ mod tests {
                 let input_document = MdastDocument::parse(input);
                 let expected = indoc!($expected);
                 println!("expected: \n{}", expected);
-                match archive_mdast(&input_document.root) {
+                #[allow(unused_variables)]
+                let options = ArchiveOptions::default();
+                $(let options = $options;)?
+                match archive_mdast(&input_document.root, &options) {
                     Some(actual_mdast) => {
-                        let actual = MdastDocument::of(actual_mdast).render();
+                        let actual = MdastDocument::of(actual_mdast).render_with(options.render_options());
                         println!("actual: \n{}", actual);
                         assert_eq!(actual, expected);
                         assert_ne!(input, expected);
@@ -344,5 +1245,1044 @@ mod tests {
             - [x] a3.1
         - [x] a4
         "#
+
+        archive_nested_recursive r#"
+        - [x] item 1
+            - [ ] item 1.1
+        - [x] item 2
+            - [x] item 2.1
+        - [ ] item 3
+        "# => r#"
+        - [x] item 1
+            - [ ] item 1.1
+        - [ ] item 3
+
+        ## Archived
+
+        - [x] item 2
+            - [x] item 2.1
+        "#
+
+        do_not_archive_checked_parent_with_deep_unchecked_todo r#"
+        - [x] parent
+            - notes
+                - [ ] grandchild
+        - [x] done parent
+            - notes
+                - context
+                - [x] grandchild
+        "# => r#"
+        - [x] parent
+            - notes
+                - [ ] grandchild
+
+        ## Archived
+
+        - [x] done parent
+            - notes
+                - context
+                - [x] grandchild
+        "#
+
+        do_not_archive_checked_parent_with_unchecked_todo_after_checked_ones r#"
+        - [x] parent
+            - [x] child 1
+                - [x] grandchild 1
+                - [ ] grandchild 2
+            - [x] child 2
+        "# => r#"
+        - [x] parent
+            - [x] child 1
+                - [x] grandchild 1
+                - [ ] grandchild 2
+            - [x] child 2
+        "#
+
+        do_not_archive_checked_parent_with_unchecked_todo_in_quote r#"
+        - [x] parent
+            > - [ ] quoted todo
+        "# => r#"
+        - [x] parent
+            > - [ ] quoted todo
+        "#
+
+        archive_checked_parent_of_only_non_todo_items r#"
+        - [x] parent
+            - note
+                - more notes
+        "# => r#"
+        ## Archived
+
+        - [x] parent
+            - note
+                - more notes
+        "#
+
+        archive_nested_strict where ArchiveOptions { archive_strict: true, ..Default::default() }; r#"
+        - [x] item 1
+            - [ ] item 1.1
+        - [x] item 2
+            - [x] item 2.1
+        - [ ] item 3
+        "# => r#"
+        - [ ] item 3
+
+        ## Archived
+
+        - [x] item 1
+            - [ ] item 1.1
+        - [x] item 2
+            - [x] item 2.1
+        "#
+
+        archive_strict_ignores_unchecked_parent_of_checked_children where ArchiveOptions { archive_strict: true, ..Default::default() }; r#"
+        - collection
+            - [x] item 1.1
+        - [ ] item 2
+            - [x] item 2.1
+        "# => r#"
+        - collection
+            - [x] item 1.1
+        - [ ] item 2
+            - [x] item 2.1
+        "#
+
+        archive_merges_duplicate_sections where ArchiveOptions { merge_duplicate_archives: true, ..Default::default() }; r#"
+        - [x] item 1
+        - [ ] item 2
+
+        ## Archived
+
+        - [x] item 0
+
+        ## Notes
+
+        some notes
+
+        ## Archived
+
+        - [x] item -1
+        "# => r#"
+        - [ ] item 2
+
+        ## Archived
+
+        - [x] item 1
+        - [x] item 0
+        - [x] item -1
+
+        ## Notes
+
+        some notes
+        "#
+
+        archive_merges_duplicate_sections_with_nothing_to_archive where ArchiveOptions { merge_duplicate_archives: true, ..Default::default() }; r#"
+        - [ ] item 1
+
+        ## Archived
+
+        ## Archived
+
+        - [x] item 0
+        "# => r#"
+        - [ ] item 1
+
+        ## Archived
+
+        - [x] item 0
+        "#
+
+        archive_leaves_duplicate_sections_by_default r#"
+        - [x] item 1
+
+        ## Archived
+
+        ## Archived
+
+        - [x] item 0
+        "# => r#"
+        ## Archived
+
+        - [x] item 1
+
+        ## Archived
+
+        - [x] item 0
+        "#
+
+        archive_appends_to_top_of_existing_archive_by_default r#"
+        - [x] new 1
+        - [ ] open
+        - [x] new 2
+
+        ## Archived
+
+        - [x] old 1
+        - [x] old 2
+        "# => r#"
+        - [ ] open
+
+        ## Archived
+
+        - [x] new 1
+        - [x] new 2
+        - [x] old 1
+        - [x] old 2
+        "#
+
+        archive_appends_to_bottom_of_existing_archive where ArchiveOptions { archive_append: Some(ArchivePlacement::Bottom), ..Default::default() }; r#"
+        - [x] new 1
+        - [ ] open
+        - [x] new 2
+
+        ## Archived
+
+        - [x] old 1
+        - [x] old 2
+        "# => r#"
+        - [ ] open
+
+        ## Archived
+
+        - [x] old 1
+        - [x] old 2
+        - [x] new 1
+        - [x] new 2
+        "#
+
+        archive_keeps_pinned_items where ArchiveOptions { pin_markers: vec![parse_pin_marker("📌").unwrap(), parse_pin_marker("#pinned").unwrap()], ..Default::default() }; r#"
+        - [x] shipped v1 📌
+        - [x] milestone #pinned
+        - [x] done
+        - [x] parent
+            - [x] pinned child 📌
+        - [x] later #pinned-later
+        - [x] release #pinned/release
+        "# => r#"
+        - [x] shipped v1 📌
+        - [x] milestone #pinned
+        - [x] parent
+            - [x] pinned child 📌
+        - [x] release #pinned/release
+
+        ## Archived
+
+        - [x] done
+        - [x] later #pinned-later
+        "#
+
+        archive_strict_keeps_pinned_items where ArchiveOptions { archive_strict: true, pin_markers: vec![parse_pin_marker("!keep").unwrap()], ..Default::default() }; r#"
+        - [x] keep me !keep
+        - [x] parent
+            - [x] child !keep
+        "# => r#"
+        - [x] keep me !keep
+
+        ## Archived
+
+        - [x] parent
+            - [x] child !keep
+        "#
+
+        archive_groups_by_source where ArchiveOptions { archive_group_by_source: true, ..Default::default() }; r#"
+        - [x] loose
+
+        # Work
+
+        - [x] report
+        - [ ] open
+        - [x] review
+
+        # Home
+
+        - [x] dishes
+        "# => r#"
+        # Work
+
+        - [ ] open
+
+        # Home
+
+        ## Archived
+
+        - [x] loose
+        - Work
+            - [x] report
+            - [x] review
+        - Home
+            - [x] dishes
+        "#
+
+        archive_groups_by_source_into_existing_items where ArchiveOptions { archive_group_by_source: true, ..Default::default() }; r#"
+        # Work
+
+        - [x] new report
+
+        # Home
+
+        - [x] dishes
+
+        ## Archived
+
+        - Work
+            - [x] old report
+        - [x] ungrouped
+        "# => r#"
+        # Work
+
+        # Home
+
+        ## Archived
+
+        - Home
+            - [x] dishes
+        - Work
+            - [x] new report
+            - [x] old report
+        - [x] ungrouped
+        "#
+
+        archive_into_existing_section_only where ArchiveOptions { require_existing_section: true, ..Default::default() }; r#"
+        - [x] done
+
+        ## Archived
+
+        - [x] old
+        "# => r#"
+        ## Archived
+
+        - [x] done
+        - [x] old
+        "#
+
+        archive_without_existing_section_is_untouched where ArchiveOptions { require_existing_section: true, ..Default::default() }; r#"
+        - [x] done
+        - [ ] open
+        "# => r#"
+        - [x] done
+        - [ ] open
+        "#
+
+        archive_per_section_into_existing_sections_only where ArchiveOptions { require_existing_section: true, archive_per_section: true, ..Default::default() }; r#"
+        # Work
+
+        - [x] report
+
+        ## Archived
+
+        # Home
+
+        - [x] dishes
+        "# => r#"
+        # Work
+
+        ## Archived
+
+        - [x] report
+
+        # Home
+
+        - [x] dishes
+        "#
+
+        archive_stops_at_max_items where ArchiveOptions { max_items: Some(2), ..Default::default() }; r#"
+        - [x] one
+        - [ ] open
+        - [x] two
+            - [x] nested items move with their parent
+        - [x] three
+
+        # Later
+
+        - [x] four
+        "# => r#"
+        - [ ] open
+        - [x] three
+
+        # Later
+
+        - [x] four
+
+        ## Archived
+
+        - [x] one
+        - [x] two
+            - [x] nested items move with their parent
+        "#
+
+        archive_groups_by_week where ArchiveOptions { group_by: GroupBy::Week, date: NaiveDate::from_ymd_opt(2024, 1, 17), ..Default::default() }; r#"
+        - [x] new
+        - [ ] open
+        "# => r#"
+        - [ ] open
+
+        ## Archived
+
+        ### 2024-W03
+
+        - [x] new
+        "#
+
+        archive_into_a_depth_6_section_without_groups where ArchiveOptions { group_by: GroupBy::Week, date: NaiveDate::from_ymd_opt(2024, 1, 17), ..Default::default() }; r#"
+        - [x] new
+
+        ###### Archived
+
+        - [x] old
+        "# => r#"
+        ###### Archived
+
+        - [x] new
+        - [x] old
+        "#
+
+        archive_merges_into_the_same_week where ArchiveOptions { group_by: GroupBy::Week, date: NaiveDate::from_ymd_opt(2024, 1, 21), ..Default::default() }; r#"
+        - [x] new
+
+        ## Archived
+
+        ### 2024-W03
+
+        - [x] monday
+
+        ### 2024-W02
+
+        - [x] last week
+        "# => r#"
+        ## Archived
+
+        ### 2024-W03
+
+        - [x] new
+        - [x] monday
+
+        ### 2024-W02
+
+        - [x] last week
+        "#
+
+        archive_starts_a_new_week_above_older_ones where ArchiveOptions { group_by: GroupBy::Week, date: NaiveDate::from_ymd_opt(2024, 1, 22), ..Default::default() }; r#"
+        - [x] new
+
+        ## Archived
+
+        - [x] before grouping
+
+        ### 2024-W03
+
+        - [x] monday
+
+        # Notes
+        "# => r#"
+        ## Archived
+
+        - [x] before grouping
+
+        ### 2024-W04
+
+        - [x] new
+
+        ### 2024-W03
+
+        - [x] monday
+
+        # Notes
+        "#
+
+        archive_starts_a_new_month_below_older_ones where ArchiveOptions { group_by: GroupBy::Month, date: NaiveDate::from_ymd_opt(2024, 2, 1), archive_append: Some(ArchivePlacement::Bottom), ..Default::default() }; r#"
+        - [x] new
+
+        ## Archived
+
+        ### 2024-01
+
+        - [x] january
+
+        # Notes
+        "# => r#"
+        ## Archived
+
+        ### 2024-01
+
+        - [x] january
+
+        ### 2024-02
+
+        - [x] new
+
+        # Notes
+        "#
+
+        archive_groups_by_month_in_the_locale where ArchiveOptions { group_by: GroupBy::Month, date: NaiveDate::from_ymd_opt(2024, 3, 5), dates: DateOptions { locale: Some(Locale::de_DE), ..Default::default() }, ..Default::default() }; r#"
+        - [x] new
+        - [ ] open
+        "# => r#"
+        - [ ] open
+
+        ## Archived
+
+        ### März 2024
+
+        - [x] new
+        "#
+
+        archive_groups_by_day_in_the_date_format where ArchiveOptions { group_by: GroupBy::Day, date: NaiveDate::from_ymd_opt(2024, 3, 5), dates: DateOptions { date_format: Some("%A %-d %B".to_string()), locale: Some(Locale::fr_FR) }, ..Default::default() }; r#"
+        - [x] new
+        - [ ] open
+        "# => r#"
+        - [ ] open
+
+        ## Archived
+
+        ### mardi 5 mars
+
+        - [x] new
+        "#
+
+        archive_only_selected_list_by_index where ArchiveOptions { list_selector: Some(ListSelector::Index(2)), ..Default::default() }; r#"
+        - [x] first list
+
+        # Work
+
+        - [x] second list
+        - [ ] open
+        "# => r#"
+        - [x] first list
+
+        # Work
+
+        - [ ] open
+
+        ## Archived
+
+        - [x] second list
+        "#
+
+        archive_only_selected_list_by_heading where ArchiveOptions { list_selector: Some(ListSelector::Heading("Home".to_string())), ..Default::default() }; r#"
+        # Work
+
+        - [x] work item
+
+        # Home
+
+        - [x] home item
+        - [ ] open home item
+
+        ## Archived
+
+        - [x] old item
+        "# => r#"
+        # Work
+
+        - [x] work item
+
+        # Home
+
+        - [ ] open home item
+
+        ## Archived
+
+        - [x] home item
+        - [x] old item
+        "#
+
+        archive_with_unmatched_list_selector where ArchiveOptions { list_selector: Some(ListSelector::Heading("Missing".to_string())), ..Default::default() }; r#"
+        # Work
+
+        - [x] work item
+        "# => r#"
+        # Work
+
+        - [x] work item
+        "#
+
+        archive_per_section where ArchiveOptions { archive_per_section: true, ..Default::default() }; r#"
+        intro
+
+        - [x] before any section
+
+        ## Work
+
+        - [x] work item
+        - [ ] open work item
+
+        ## Home
+
+        - [ ] open home item
+        - [x] home item
+
+        ### Archived
+
+        - [x] old home item
+        "# => r#"
+        intro
+
+        - [x] before any section
+
+        ## Work
+
+        - [ ] open work item
+
+        ### Archived
+
+        - [x] work item
+
+        ## Home
+
+        - [ ] open home item
+
+        ### Archived
+
+        - [x] home item
+        - [x] old home item
+        "#
+
+        archive_per_section_of_depth_6_headings_into_one_section where ArchiveOptions { archive_per_section: true, ..Default::default() }; r#"
+        ###### Work
+
+        - [x] work item
+
+        ###### Home
+
+        - [x] home item
+        "# => r#"
+        ###### Work
+
+        ###### Home
+
+        ## Archived
+
+        - [x] home item
+        - [x] work item
+        "#
+
+        archive_per_section_under_a_title where ArchiveOptions { archive_per_section: true, ..Default::default() }; r#"
+        # Title
+
+        ## Work
+
+        - [x] work item
+        "# => r#"
+        # Title
+
+        ## Work
+
+        ### Archived
+
+        - [x] work item
+        "#
+
+        archive_per_section_under_a_title_with_several_sections where ArchiveOptions { archive_per_section: true, ..Default::default() }; r#"
+        # Title
+
+        ## Work
+
+        - [x] work item
+
+        ## Home
+
+        - [x] home item
+        "# => r#"
+        # Title
+
+        ## Work
+
+        ### Archived
+
+        - [x] work item
+
+        ## Home
+
+        ### Archived
+
+        - [x] home item
+        "#
+
+        archive_per_section_with_list_selector where ArchiveOptions { archive_per_section: true, list_selector: Some(ListSelector::Index(2)), ..Default::default() }; r#"
+        ## Work
+
+        - [x] work item
+
+        ## Home
+
+        - [x] home item
+        "# => r#"
+        ## Work
+
+        - [x] work item
+
+        ## Home
+
+        ### Archived
+
+        - [x] home item
+        "#
+
+        archive_reuses_section_of_another_depth r#"
+        # Todo
+
+        - [x] item 1
+        - [ ] item 2
+
+        ### Archived
+
+        - [x] item 0
+        "# => r#"
+        # Todo
+
+        - [ ] item 2
+
+        ### Archived
+
+        - [x] item 1
+        - [x] item 0
+        "#
+
+        archive_prefers_section_of_the_configured_depth r#"
+        - [x] item 1
+
+        ## Archived
+
+        ### Archived
+
+        - [x] item 0
+        "# => r#"
+        ## Archived
+
+        - [x] item 1
+
+        ### Archived
+
+        - [x] item 0
+        "#
+
+        archive_merges_duplicate_sections_of_another_depth where ArchiveOptions { merge_duplicate_archives: true, ..Default::default() }; r#"
+        - [x] item 1
+
+        ### Archived
+
+        - [x] item 0
+
+        ### Archived
+
+        - [x] item -1
+        "# => r#"
+        ### Archived
+
+        - [x] item 1
+        - [x] item 0
+        - [x] item -1
+        "#
+
+        archive_creates_section_with_one_blank_line_around_heading r#"
+        # Todo
+        - [ ] item 1
+        - [x] item 2
+
+        Some notes after the list.
+        "# => r#"
+        # Todo
+
+        - [ ] item 1
+
+        ## Archived
+
+        - [x] item 2
+
+        Some notes after the list.
+        "#
+
+        archive_to_tight_existing_section_adds_blank_lines r#"
+        - [x] item 1
+        - [ ] item 2
+        ## Archived
+        - [x] item 0
+        "# => r#"
+        - [ ] item 2
+
+        ## Archived
+
+        - [x] item 1
+        - [x] item 0
+        "#
+
+        archive_without_blank_lines_around_the_section where ArchiveOptions { archive_blank_lines: Some(0), ..Default::default() }; r#"
+        # Todo
+        - [ ] item 1
+        - [x] item 2
+
+        Some notes after the list.
+        "# => r#"
+        # Todo
+
+        - [ ] item 1
+        ## Archived
+        - [x] item 2
+
+        Some notes after the list.
+        "#
+
+        archive_with_two_blank_lines_around_an_existing_section where ArchiveOptions { archive_blank_lines: Some(2), ..Default::default() }; r#"
+        - [x] item 1
+        - [ ] item 2
+        ## Archived
+        - [x] item 0
+        "# => r#"
+        - [ ] item 2
+
+
+        ## Archived
+
+
+        - [x] item 1
+        - [x] item 0
+        "#
+
+        archive_to_loose_existing_section_collapses_blank_lines r#"
+        - [x] item 1
+        - [ ] item 2
+
+
+
+        ## Archived
+
+
+
+        - [x] item 0
+        "# => r#"
+        - [ ] item 2
+
+        ## Archived
+
+        - [x] item 1
+        - [x] item 0
+        "#
+    }
+
+    #[test]
+    fn list_selector_parsing() {
+        assert_eq!(parse_list_selector("2").unwrap(), ListSelector::Index(2));
+        assert_eq!(
+            parse_list_selector(" Work ").unwrap(),
+            ListSelector::Heading("Work".to_string())
+        );
+        assert!(matches!(parse_list_selector("0"), Err(Error::Parse(_))));
+        assert!(matches!(parse_list_selector(" "), Err(Error::Parse(_))));
+    }
+
+    #[test]
+    fn mismatched_section_depth_is_reported() {
+        let depth = |input: &str| {
+            mismatched_section_depth(&MdastDocument::parse(input).root.children, "Archived", 2)
+        };
+        assert_eq!(depth("### Archived\n"), Some(3));
+        assert_eq!(depth("### Archived\n\n## Archived\n"), None);
+        assert_eq!(depth("# Todo\n"), None);
+    }
+
+    #[test]
+    fn todos_outside_lists_are_not_archived() {
+        let document = MdastDocument::parse(indoc! {r#"
+            #todo
+
+            [x] done, but written as a paragraph
+
+            # Heading
+
+            > [x] quoted
+        "#});
+        assert_eq!(
+            archive_mdast(&document.root, &ArchiveOptions::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn archive_to_file_moves_items_out_of_each_file() {
+        let vault = tempfile::tempdir().unwrap();
+        fs::write(
+            vault.path().join("a.md"),
+            "#todo\n\n- [x] done a\n- [ ] open a\n",
+        )
+        .unwrap();
+        fs::write(vault.path().join("b.md"), "#todo\n\n- [x] done b\n").unwrap();
+        fs::write(vault.path().join("Archive.md"), "# Archive\n\n- [x] old\n").unwrap();
+
+        let changes = archive_to_file(
+            vault.path(),
+            &WalkOptions::default(),
+            &ArchiveOptions::default(),
+            Path::new("Archive.md"),
+        );
+
+        assert_eq!(
+            changes,
+            vec![
+                (
+                    vault.path().join("a.md"),
+                    "#todo\n\n- [ ] open a\n".to_string()
+                ),
+                (vault.path().join("b.md"), "#todo\n".to_string()),
+                (
+                    vault.path().join("Archive.md"),
+                    "# Archive\n\n- [x] old\n- [x] done a\n- [x] done b\n".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn archive_to_file_adds_backlinks() {
+        let vault = tempfile::tempdir().unwrap();
+        fs::create_dir(vault.path().join("projects")).unwrap();
+        fs::write(
+            vault.path().join("projects/garden.md"),
+            "#todo\n\n- [x] done a ^block-id\n- [x] **bold**\n    - [x] nested\n",
+        )
+        .unwrap();
+        fs::write(vault.path().join("Issue #1.md"), "#todo\n\n- [x] done b\n").unwrap();
+
+        let changes = archive_to_file(
+            vault.path(),
+            &WalkOptions::default(),
+            &ArchiveOptions {
+                backlink: true,
+                ..Default::default()
+            },
+            Path::new("Archive.md"),
+        );
+
+        assert_eq!(
+            changes.last().unwrap().1,
+            indoc! {r#"
+            - [x] done b (from [Issue #1](<Issue #1.md>))
+            - [x] done a (from [[projects/garden]]) ^block-id
+            - [x] **bold** (from [[projects/garden]])
+                - [x] nested
+            "#}
+        );
+    }
+
+    #[test]
+    fn txt_notes_are_archived_with_ext() {
+        let vault = tempfile::tempdir().unwrap();
+        let vault_path = vault.path().to_path_buf();
+        fs::write(
+            vault.path().join("todo.txt"),
+            "#todo\n\n- [x] done\n- [ ] open\n",
+        )
+        .unwrap();
+        fs::write(vault.path().join("notes.txt"), "- [x] untagged\n").unwrap();
+        let options = ArchiveOptions::default();
+
+        let changes = archive(&vault_path, &WalkOptions::default(), &options).collect::<Vec<_>>();
+        assert_eq!(changes, vec![]);
+
+        let walk = WalkOptions {
+            extensions: vec!["txt".to_string()],
+            ..Default::default()
+        };
+        let changes = archive(&vault_path, &walk, &options).collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            vec![(
+                vault.path().join("todo.txt"),
+                "#todo\n\n- [ ] open\n\n## Archived\n\n- [x] done\n".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn check_archive_exit_code() {
+        let vault = tempfile::tempdir().unwrap();
+        let vault_path = vault.path().to_path_buf();
+        fs::write(vault.path().join("open.md"), "#todo\n\n- [ ] open\n").unwrap();
+
+        assert_eq!(
+            check_archive(
+                &vault_path,
+                &WalkOptions::default(),
+                &ArchiveOptions::default()
+            ),
+            0
+        );
+
+        fs::write(vault.path().join("done.md"), "#todo\n\n- [x] done\n").unwrap();
+
+        assert_eq!(
+            check_archive(
+                &vault_path,
+                &WalkOptions::default(),
+                &ArchiveOptions::default()
+            ),
+            1
+        );
+        assert_eq!(
+            fs::read_to_string(vault.path().join("done.md")).unwrap(),
+            "#todo\n\n- [x] done\n"
+        );
+    }
+
+    #[test]
+    fn group_keys_use_iso_weeks_across_year_boundaries() {
+        let key = |group_by: GroupBy, y, m, d| {
+            group_by.key(
+                NaiveDate::from_ymd_opt(y, m, d).unwrap(),
+                &DateOptions::default(),
+            )
+        };
+        assert_eq!(key(GroupBy::Week, 2024, 1, 17).unwrap(), "2024-W03");
+        // the first days of january can be in the last week of the year before
+        assert_eq!(key(GroupBy::Week, 2021, 1, 3).unwrap(), "2020-W53");
+        assert_eq!(key(GroupBy::Week, 2023, 1, 1).unwrap(), "2022-W52");
+        // and the last days of december in the first week of the next
+        assert_eq!(key(GroupBy::Week, 2024, 12, 30).unwrap(), "2025-W01");
+        assert_eq!(key(GroupBy::Week, 2025, 12, 29).unwrap(), "2026-W01");
+        assert_eq!(key(GroupBy::Day, 2024, 12, 30).unwrap(), "2024-12-30");
+        assert_eq!(key(GroupBy::Month, 2024, 12, 30).unwrap(), "2024-12");
+        assert_eq!(key(GroupBy::None, 2024, 12, 30), None);
+    }
+
+    #[test]
+    fn max_items_caps_the_whole_run() {
+        let vault = tempfile::tempdir().unwrap();
+        for name in ["a", "b", "c"] {
+            fs::write(
+                vault.path().join(format!("{name}.md")),
+                "#todo\n- [x] one\n- [x] two\n",
+            )
+            .unwrap();
+        }
+        let options = ArchiveOptions {
+            max_items: Some(3),
+            ..Default::default()
+        };
+
+        let vault_path = vault.path().to_path_buf();
+
+        // checking doesn't use up any of the todos the run may archive
+        assert_eq!(
+            check_archive(&vault_path, &WalkOptions::default(), &options),
+            1
+        );
+        for _ in 0..2 {
+            let mut archived = archive(&vault_path, &WalkOptions::default(), &options)
+                .map(|(path, content)| {
+                    let count = content
+                        .split("## Archived")
+                        .nth(1)
+                        .unwrap()
+                        .matches("- [x]")
+                        .count();
+                    (path.strip_prefix(&vault_path).unwrap().to_path_buf(), count)
+                })
+                .collect::<Vec<_>>();
+            archived.sort();
+            // the files are archived from in order of their paths, the same every run
+            assert_eq!(
+                archived,
+                vec![(PathBuf::from("a.md"), 2), (PathBuf::from("b.md"), 1)]
+            );
+        }
     }
 }
@@ -0,0 +1,44 @@
+use std::path::Path;
+
+use crate::markdown_file::{Encoding, File, MdastDocument};
+
+/// Render the parsed mdast tree of a markdown string, for debugging the formatter
+pub fn dump_ast(content: &str) -> String {
+    format!("{:#?}", MdastDocument::parse(content).root)
+}
+
+pub fn print_ast(path: &Path, encoding: Encoding) -> Option<i32> {
+    match File::at_path(path.to_path_buf(), encoding) {
+        Ok(file) => {
+            println!("{}", dump_ast(&file.content));
+            None
+        }
+        Err(e) => {
+            eprintln!("Failed to read {}: {e}", path.display());
+            Some(1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use indoc::indoc;
+
+    #[test]
+    fn ast_dump_contains_node_types() {
+        let dump = dump_ast(indoc! {r#"
+            # Heading
+
+            - [x] item with `code`
+        "#});
+
+        for node_type in ["Root", "Heading", "List", "ListItem", "Text", "InlineCode"] {
+            assert!(
+                dump.contains(node_type),
+                "expected {node_type} in ast dump:\n{dump}"
+            );
+        }
+    }
+}
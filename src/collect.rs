@@ -0,0 +1,262 @@
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use markdown::mdast::{self, Node};
+use rayon::prelude::*;
+
+use crate::{
+    markdown_file::MdastDocument,
+    util::{iterate_tagged_markdown_files, note_link, WalkOptions},
+};
+
+#[derive(Args, Debug, Default, Clone)]
+pub struct CollectOptions {
+    /// Collect the open todos of files with this tag, or `todo`
+    #[arg(long)]
+    pub tag: Option<String>,
+
+    /// The note to write every open todo into, relative to the vault, replacing its content
+    #[arg(long, value_name = "FILE")]
+    pub out: PathBuf,
+
+    /// Only include the nested items of each todo down to this many levels, instead of all of them
+    #[arg(long, value_name = "N")]
+    pub child_depth: Option<usize>,
+}
+
+impl CollectOptions {
+    /// The tag of the files to collect from
+    pub fn tag(&self) -> &str {
+        self.tag.as_deref().unwrap_or("todo")
+    }
+}
+
+/// Remove the lists nested more than `depth` levels below a list item, returning them
+fn truncate_children(children: &mut Vec<Node>, depth: usize) -> Vec<Node> {
+    if depth == 0 {
+        let (lists, rest) = children
+            .drain(..)
+            .partition(|child| matches!(child, Node::List(_)));
+        *children = rest;
+        return lists;
+    }
+    let mut removed = vec![];
+    for child in children.iter_mut() {
+        if let Node::List(list) = child {
+            for item in list.children.iter_mut() {
+                if let Node::ListItem(item) = item {
+                    removed.extend(truncate_children(&mut item.children, depth - 1));
+                }
+            }
+        }
+    }
+    removed
+}
+
+/// Every open todo that isn't already inside another open todo, with its nested items down to
+/// `depth` levels
+///
+/// Open todos nested too deep to be included with their parent are collected on their own.
+fn open_tasks(nodes: &[Node], depth: Option<usize>, tasks: &mut Vec<mdast::ListItem>) {
+    for node in nodes {
+        match node {
+            Node::ListItem(list_item) if list_item.checked == Some(false) => {
+                let mut task = list_item.clone();
+                let removed = depth
+                    .map(|depth| truncate_children(&mut task.children, depth))
+                    .unwrap_or_default();
+                tasks.push(task);
+                open_tasks(&removed, depth, tasks);
+            }
+            _ => {
+                if let Some(children) = node.children() {
+                    open_tasks(children, depth, tasks);
+                }
+            }
+        }
+    }
+}
+
+/// A note with a heading linking to each source file, followed by its open todos
+fn collected_note(vault_path: &Path, mut sources: Vec<(PathBuf, Vec<mdast::ListItem>)>) -> String {
+    sources.sort_by(|a, b| a.0.cmp(&b.0));
+    let children = sources
+        .into_iter()
+        .flat_map(|(path, tasks)| {
+            [
+                Node::Heading(mdast::Heading {
+                    depth: 1,
+                    children: vec![Node::Text(mdast::Text {
+                        value: note_link(vault_path, &path),
+                        position: None,
+                    })],
+                    position: None,
+                }),
+                Node::List(mdast::List {
+                    ordered: false,
+                    start: None,
+                    spread: false,
+                    children: tasks.into_iter().map(Node::ListItem).collect(),
+                    position: None,
+                }),
+            ]
+        })
+        .collect();
+    MdastDocument {
+        root: mdast::Root {
+            children,
+            position: None,
+        },
+    }
+    .render()
+}
+
+/// Gather the open todos of every tagged file into the `out` note, grouped by file
+///
+/// The note is regenerated from scratch each time, so running this again with nothing changed
+/// leaves it the same.
+#[must_use]
+pub fn collect(
+    vault_path: &Path,
+    walk: &WalkOptions,
+    options: &CollectOptions,
+) -> Vec<(PathBuf, String)> {
+    let out_path = vault_path.join(&options.out);
+    let sources = iterate_tagged_markdown_files(&vault_path.to_path_buf(), walk, options.tag())
+        .filter(|file| file.path != out_path)
+        .filter_map(|file| {
+            let mut tasks = vec![];
+            open_tasks(
+                &MdastDocument::parse(&file.content).root.children,
+                options.child_depth,
+                &mut tasks,
+            );
+            (!tasks.is_empty()).then_some((file.path, tasks))
+        })
+        .collect::<Vec<_>>();
+
+    vec![(out_path, collected_note(vault_path, sources))]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    use crate::util::vault_with;
+
+    #[test]
+    fn open_todos_are_collected_with_links_back() {
+        let vault = vault_with(&[
+            (
+                "projects/garden.md",
+                indoc! {r#"
+                #todo
+                - [ ] buy seeds
+                - [x] dig beds
+                - [ ] water
+                    - [ ] morning
+                "#},
+            ),
+            (
+                "home.md",
+                indoc! {r#"
+                #todo
+
+                # Chores
+                - [x] laundry
+                    - [ ] fold
+                - [ ] dishes
+                "#},
+            ),
+            ("untagged.md", "- [ ] not collected\n"),
+        ]);
+        let options = CollectOptions {
+            tag: None,
+            out: PathBuf::from("Tasks.md"),
+            child_depth: None,
+        };
+
+        let changes = collect(vault.path(), &WalkOptions::default(), &options);
+        assert_eq!(
+            changes,
+            vec![(
+                vault.path().join("Tasks.md"),
+                indoc! {r#"
+                # [[home]]
+
+                - [ ] fold
+                - [ ] dishes
+
+                # [[projects/garden]]
+
+                - [ ] buy seeds
+                - [ ] water
+                    - [ ] morning
+                "#}
+                .to_string()
+            )]
+        );
+
+        fs::write(vault.path().join("Tasks.md"), &changes[0].1).unwrap();
+        assert_eq!(
+            collect(vault.path(), &WalkOptions::default(), &options),
+            changes
+        );
+    }
+
+    #[test]
+    fn nested_items_are_collected_down_to_the_child_depth() {
+        let vault = tempfile::tempdir().unwrap();
+        fs::write(
+            vault.path().join("project.md"),
+            indoc! {r#"
+            #todo
+            - [ ] parent
+                - [x] child
+                    - detail
+                - [ ] open child
+                    - [ ] open grandchild
+            "#},
+        )
+        .unwrap();
+        let collected = |child_depth| {
+            let options = CollectOptions {
+                tag: None,
+                out: PathBuf::from("Tasks.md"),
+                child_depth: Some(child_depth),
+            };
+            collect(vault.path(), &WalkOptions::default(), &options)
+                .remove(0)
+                .1
+        };
+
+        assert_eq!(
+            collected(1),
+            indoc! {r#"
+            # [[project]]
+
+            - [ ] parent
+                - [x] child
+                - [ ] open child
+            - [ ] open grandchild
+            "#}
+        );
+        assert_eq!(
+            collected(2),
+            indoc! {r#"
+            # [[project]]
+
+            - [ ] parent
+                - [x] child
+                    - detail
+                - [ ] open child
+                    - [ ] open grandchild
+            "#}
+        );
+    }
+}
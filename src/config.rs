@@ -0,0 +1,396 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use serde::Deserialize;
+use walkdir::WalkDir;
+
+use crate::{
+    archive::{ArchiveOptions, ArchivePlacement},
+    error::{Error, Result},
+    format_files::{FormatOptions, Lint, PassName},
+    util::is_visible,
+};
+
+/// The name of the config files, read from the root of the vault and any folder in it
+pub const CONFIG_FILE: &str = "marksage.toml";
+
+/// Settings from `marksage.toml`, which options given on the command line override
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub format: FormatConfig,
+    pub archive: ArchiveConfig,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct FormatConfig {
+    /// The passes to run, in order, like `--passes`
+    pub passes: Option<Vec<PassName>>,
+    /// Regex replacements to run over each formatted file, like
+    /// `{ name = "nbsp", pattern = "\u00a0", replacement = " " }`
+    pub lints: Option<Vec<Lint>>,
+    /// The lints to skip, like `--disable-lint`
+    pub disabled_lints: Option<Vec<String>>,
+    /// Languages of code blocks to leave exactly as written, like `--protect-lang`
+    pub protected_langs: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArchiveConfig {
+    /// Where newly archived todos go, like `--archive-append`
+    pub append: Option<ArchivePlacement>,
+    /// How many blank lines go around the Archived heading, like `--archive-blank-lines`
+    pub blank_lines: Option<usize>,
+}
+
+impl Config {
+    fn read(path: &Path) -> Result<Config> {
+        toml::from_str(&std::fs::read_to_string(path)?)
+            .map_err(|e| Error::Parse(format!("invalid {}: {e}", path.display())))
+    }
+
+    /// These settings, with any that `other` sets replacing them
+    fn merge(self, other: &Config) -> Config {
+        Config {
+            format: FormatConfig {
+                passes: other.format.passes.clone().or(self.format.passes),
+                lints: other.format.lints.clone().or(self.format.lints),
+                disabled_lints: other
+                    .format
+                    .disabled_lints
+                    .clone()
+                    .or(self.format.disabled_lints),
+                protected_langs: other
+                    .format
+                    .protected_langs
+                    .clone()
+                    .or(self.format.protected_langs),
+            },
+            archive: ArchiveConfig {
+                append: other.archive.append.or(self.archive.append),
+                blank_lines: other.archive.blank_lines.or(self.archive.blank_lines),
+            },
+        }
+    }
+}
+
+/// Every config in the vault by the folder it's in, where the settings for a file cascade from
+/// the config at the root of the vault down through the folders containing it
+#[derive(Debug, Default, Clone)]
+pub struct Configs {
+    vault_path: PathBuf,
+    by_folder: Arc<HashMap<PathBuf, Config>>,
+}
+
+impl Configs {
+    /// Read every config in the visible folders of the vault
+    pub fn load(vault_path: &Path) -> Result<Configs> {
+        let mut by_folder = HashMap::new();
+        // the vault root itself may be a hidden folder
+        let entries = WalkDir::new(vault_path)
+            .into_iter()
+            .filter_entry(|entry| entry.depth() == 0 || is_visible(entry));
+        for entry in entries {
+            let entry = entry.map_err(std::io::Error::from)?;
+            if entry.file_type().is_file() && entry.file_name() == CONFIG_FILE {
+                let folder = entry.path().parent().unwrap_or(vault_path).to_path_buf();
+                by_folder.insert(folder, Config::read(entry.path())?);
+            }
+        }
+        Ok(Configs {
+            vault_path: vault_path.to_path_buf(),
+            by_folder: Arc::new(by_folder),
+        })
+    }
+
+    /// The settings for the file at `path`, with those of deeper folders taking precedence
+    ///
+    /// A path outside the vault, like that of stdin, gets the settings at the root of the vault.
+    pub fn for_file(&self, path: &Path) -> Config {
+        let folder = path
+            .parent()
+            .filter(|folder| folder.starts_with(&self.vault_path))
+            .unwrap_or(&self.vault_path);
+        let mut folders = folder
+            .ancestors()
+            .take_while(|folder| folder.starts_with(&self.vault_path))
+            .collect::<Vec<_>>();
+        folders.reverse();
+        folders
+            .into_iter()
+            .filter_map(|folder| self.by_folder.get(folder))
+            .fold(Config::default(), Config::merge)
+    }
+
+    /// The format options for a file, with the settings of its configs filled in where they
+    /// weren't given on the command line
+    pub fn format_options<'a>(
+        &self,
+        path: &Path,
+        options: &'a FormatOptions,
+    ) -> Cow<'a, FormatOptions> {
+        let config = self.for_file(path).format;
+        if config == FormatConfig::default() {
+            return Cow::Borrowed(options);
+        }
+        let mut options = options.clone();
+        if options.passes.is_none() {
+            options.passes = config.passes;
+        }
+        if options.lints.is_empty() {
+            options.lints = config.lints.unwrap_or_default();
+        }
+        if options.disabled_lints.is_empty() {
+            options.disabled_lints = config.disabled_lints.unwrap_or_default();
+        }
+        if options.protected_langs.is_empty() {
+            options.protected_langs = config.protected_langs.unwrap_or_default();
+        }
+        Cow::Owned(options)
+    }
+
+    /// Like `format_options`, for archiving a file
+    pub fn archive_options<'a>(
+        &self,
+        path: &Path,
+        options: &'a ArchiveOptions,
+    ) -> Cow<'a, ArchiveOptions> {
+        let config = self.for_file(path).archive;
+        if (config.append.is_none() || options.archive_append.is_some())
+            && (config.blank_lines.is_none() || options.archive_blank_lines.is_some())
+        {
+            return Cow::Borrowed(options);
+        }
+        Cow::Owned(ArchiveOptions {
+            archive_append: options.archive_append.or(config.append),
+            archive_blank_lines: options.archive_blank_lines.or(config.blank_lines),
+            ..options.clone()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+    use rayon::prelude::*;
+
+    use crate::{
+        archive::archive,
+        format_files::format_files,
+        util::{vault_with, WalkOptions},
+    };
+
+    #[test]
+    fn missing_config_is_the_default() {
+        let vault = tempfile::tempdir().unwrap();
+        let configs = Configs::load(vault.path()).unwrap();
+        assert_eq!(
+            configs.for_file(&vault.path().join("note.md")),
+            Config::default()
+        );
+    }
+
+    #[test]
+    fn invalid_config_is_a_parse_error() {
+        for toml in [
+            "[format]\npasses = [\"reflow\"]",
+            "[format]\npass = []",
+            "[archive]\nappend = \"middle\"",
+            "format = 1",
+            "[[format.lints]]\nname = \"bad\"\npattern = \"(\"\nreplacement = \"\"",
+        ] {
+            let vault = vault_with(&[("folder/marksage.toml", toml)]);
+            assert!(
+                matches!(Configs::load(vault.path()), Err(Error::Parse(_))),
+                "{toml}"
+            );
+        }
+    }
+
+    #[test]
+    fn configs_cascade_down_folders() {
+        let vault = vault_with(&[
+            (
+                "marksage.toml",
+                "[format]\npasses = [\"em-dash\"]\n[archive]\nappend = \"bottom\"",
+            ),
+            ("projects/marksage.toml", "[format]\npasses = []"),
+            (".hidden/marksage.toml", "not toml"),
+        ]);
+        let configs = Configs::load(vault.path()).unwrap();
+        let config = |path: &str| configs.for_file(&vault.path().join(path));
+
+        assert_eq!(
+            config("note.md").format.passes,
+            Some(vec![PassName::EmDash])
+        );
+        assert_eq!(
+            config("projects/garden/note.md").format.passes,
+            Some(vec![])
+        );
+        assert_eq!(
+            config("projects/garden/note.md").archive.append,
+            Some(ArchivePlacement::Bottom)
+        );
+        assert_eq!(configs.for_file(Path::new("<stdin>")), config("note.md"));
+    }
+
+    #[test]
+    fn nested_config_overrides_root_for_its_subtree() {
+        let note = "a--b\n";
+        let vault = vault_with(&[
+            ("marksage.toml", "[format]\npasses = [\"em-dash\"]"),
+            ("raw/marksage.toml", "[format]\npasses = []"),
+            ("note.md", note),
+            ("raw/note.md", note),
+            ("raw/nested/note.md", note),
+        ]);
+        let format = |options: &FormatOptions| {
+            let options = FormatOptions {
+                configs: Configs::load(vault.path()).unwrap(),
+                ..options.clone()
+            };
+            let mut changes = format_files(
+                &vault.path().to_path_buf(),
+                &WalkOptions::default(),
+                &options,
+            )
+            .map(|(path, _)| path.strip_prefix(vault.path()).unwrap().to_path_buf())
+            .collect::<Vec<_>>();
+            changes.sort();
+            changes
+        };
+
+        assert_eq!(
+            format(&FormatOptions::default()),
+            [PathBuf::from("note.md")]
+        );
+        assert_eq!(
+            format(&FormatOptions {
+                passes: Some(vec![PassName::EmDash]),
+                ..Default::default()
+            }),
+            ["note.md", "raw/nested/note.md", "raw/note.md"].map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn config_lints_run_after_rendering() {
+        let vault = vault_with(&[
+            (
+                "marksage.toml",
+                indoc! {r##"
+                    [[format.lints]]
+                    name = "no-trailing-newline"
+                    pattern = "\n\\z"
+                    replacement = ""
+
+                    [[format.lints]]
+                    name = "todo-marker"
+                    pattern = "TODO:"
+                    replacement = "#todo"
+                "##},
+            ),
+            (
+                "raw/marksage.toml",
+                "[format]\ndisabled-lints = [\"trailing-newline\"]\nprotected-langs = [\"dataview\"]",
+            ),
+            ("note.md", "TODO: a--b\n"),
+            ("raw/note.md", "TODO: a--b\n"),
+            ("raw/query.md", "```dataview\nTODO: list\n```\n"),
+        ]);
+        let options = FormatOptions {
+            configs: Configs::load(vault.path()).unwrap(),
+            ..Default::default()
+        };
+        let mut changes = format_files(
+            &vault.path().to_path_buf(),
+            &WalkOptions::default(),
+            &options,
+        )
+        .collect::<Vec<_>>();
+        changes.sort();
+
+        // the built in trailing-newline lint runs last, putting back the newline
+        assert_eq!(changes[0].1, "#todo a—b\n");
+        assert_eq!(changes[1].1, "#todo a—b");
+        // protected code keeps its text, but not the newline after it
+        assert_eq!(changes[2].1, "```dataview\nTODO: list\n```");
+    }
+
+    #[test]
+    fn nested_config_sets_archive_placement() {
+        let note = indoc! {r#"
+            #todo
+            - [x] new
+
+            ## Archived
+
+            - [x] old
+        "#};
+        let vault = vault_with(&[
+            ("log/marksage.toml", "[archive]\nappend = \"bottom\""),
+            ("note.md", note),
+            ("log/note.md", note),
+        ]);
+        let options = ArchiveOptions {
+            configs: Configs::load(vault.path()).unwrap(),
+            ..Default::default()
+        };
+        let mut changes = archive(
+            &vault.path().to_path_buf(),
+            &WalkOptions::default(),
+            &options,
+        )
+        .collect::<Vec<_>>();
+        changes.sort();
+        let archived = |content: &str| {
+            content
+                .split("## Archived")
+                .nth(1)
+                .unwrap()
+                .trim()
+                .to_string()
+        };
+
+        assert_eq!(archived(&changes[0].1), "- [x] old\n- [x] new");
+        assert_eq!(archived(&changes[1].1), "- [x] new\n- [x] old");
+    }
+
+    #[test]
+    fn config_sets_archive_blank_lines_unless_given() {
+        let vault = vault_with(&[
+            ("marksage.toml", "[archive]\nblank_lines = 0"),
+            ("note.md", "#todo\n- [x] done\n"),
+        ]);
+        let archived = |archive_blank_lines| {
+            let options = ArchiveOptions {
+                configs: Configs::load(vault.path()).unwrap(),
+                archive_blank_lines,
+                ..Default::default()
+            };
+            archive(
+                &vault.path().to_path_buf(),
+                &WalkOptions::default(),
+                &options,
+            )
+            .map(|(_, content)| content)
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(archived(None), vec!["#todo\n## Archived\n- [x] done\n"]);
+        assert_eq!(
+            archived(Some(1)),
+            vec!["#todo\n\n## Archived\n\n- [x] done\n"]
+        );
+    }
+}
@@ -1,26 +1,102 @@
-use std::fmt;
+use std::{ffi::OsStr, fmt};
 
-use console::{style, Style};
-use similar::{ChangeTag, TextDiff};
+use clap::ValueEnum;
+use console::{pad_str, style, Alignment, Style};
+use similar::{ChangeTag, DiffOp, DiffTag, TextDiff};
 
-struct Line(Option<usize>);
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DiffStyle {
+    /// Interleave removed and added lines, highlighting the changed words
+    #[default]
+    Inline,
+    /// Show the old and new file in two columns
+    SideBySide,
+}
+
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMode {
+    /// Color output to a terminal, unless `NO_COLOR` is set
+    #[default]
+    Auto,
+    /// Always color output, even when piped
+    Always,
+    /// Never color output
+    Never,
+}
+
+impl ColorMode {
+    /// Whether to color output, given the value of `NO_COLOR` and whether the terminal was
+    /// detected to support color
+    fn is_enabled(self, no_color: Option<&OsStr>, detected: bool) -> bool {
+        match self {
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+            ColorMode::Auto => no_color.map_or(true, OsStr::is_empty) && detected,
+        }
+    }
+
+    /// Set whether all output is colored, for stdout and stderr
+    pub fn apply(self) {
+        let no_color = std::env::var_os("NO_COLOR");
+        console::set_colors_enabled(
+            self.is_enabled(no_color.as_deref(), console::colors_enabled()),
+        );
+        console::set_colors_enabled_stderr(
+            self.is_enabled(no_color.as_deref(), console::colors_enabled_stderr()),
+        );
+    }
+}
+
+/// A line number, padded to the width of the gutter
+struct Line(Option<usize>, usize);
 
 // lifted from https://github.com/mitsuhiko/similar/blob/de455873dab514082bf6e7bb5f0029837fe280d5/examples/terminal-inline.rs
 
 impl fmt::Display for Line {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self.0 {
-            None => write!(f, "    "),
-            Some(idx) => write!(f, "{:<4}", idx + 1),
+            None => write!(f, "{:1$}", "", self.1),
+            Some(idx) => write!(f, "{:<1$}", idx + 1, self.1),
         }
     }
 }
 
+/// The width of the line number gutter, enough for the largest line number in the diff and a
+/// space after it
+fn gutter_width(groups: &[Vec<DiffOp>]) -> usize {
+    let largest = groups
+        .iter()
+        .flatten()
+        .map(|op| op.old_range().end.max(op.new_range().end))
+        .max()
+        .unwrap_or(0);
+    (largest.to_string().len() + 1).max(4)
+}
+
+/// The line ending a diff that showed only `max_hunks` of its hunks
+fn more_changes(hunks: usize, max_hunks: Option<usize>) -> Option<String> {
+    let more = hunks.saturating_sub(max_hunks?);
+    (more > 0).then(|| format!("... ({more} more changes)\n"))
+}
+
+/// Interleave the removed and added lines of each hunk, stopping after `max_hunks` hunks
 #[must_use]
-pub fn diff(mut stdout_buffer: Vec<String>, old: &str, new: &str) -> Vec<String> {
+pub fn diff(
+    mut stdout_buffer: Vec<String>,
+    old: &str,
+    new: &str,
+    context: usize,
+    max_hunks: Option<usize>,
+) -> Vec<String> {
     let diff = TextDiff::from_lines(old, new);
+    let groups = diff.grouped_ops(context);
+    let gutter = gutter_width(&groups);
 
-    for (idx, group) in diff.grouped_ops(3).iter().enumerate() {
+    for (idx, group) in groups
+        .iter()
+        .take(max_hunks.unwrap_or(usize::MAX))
+        .enumerate()
+    {
         if idx > 0 {
             stdout_buffer.push(format!("{:-^1$}\n", "-", 80));
         }
@@ -33,8 +109,8 @@ pub fn diff(mut stdout_buffer: Vec<String>, old: &str, new: &str) -> Vec<String>
                 };
                 stdout_buffer.push(format!(
                     "{}{} |{}",
-                    s.apply_to(Line(change.old_index())).dim(),
-                    s.apply_to(Line(change.new_index())).dim(),
+                    s.apply_to(Line(change.old_index(), gutter)).dim(),
+                    s.apply_to(Line(change.new_index(), gutter)).dim(),
                     s.apply_to(sign).bold(),
                 ));
                 for (emphasized, value) in change.iter_strings_lossy() {
@@ -55,6 +131,188 @@ pub fn diff(mut stdout_buffer: Vec<String>, old: &str, new: &str) -> Vec<String>
             }
         }
     }
+    stdout_buffer.extend(more_changes(groups.len(), max_hunks));
+
+    stdout_buffer
+}
+
+/// One side of a side by side diff line, padded or truncated to `width`
+fn column(line: Option<(usize, &str)>, width: usize, gutter: usize, s: &Style) -> String {
+    let (number, text) = match line {
+        Some((idx, text)) => (Line(Some(idx), gutter), text.trim_end_matches(['\r', '\n'])),
+        None => (Line(None, gutter), ""),
+    };
+    format!(
+        "{}{}",
+        s.apply_to(number).dim(),
+        s.apply_to(pad_str(text, width, Alignment::Left, Some("…")))
+    )
+}
+
+/// Like `diff`, but with the old file on the left and the new file on the right, each column
+/// taking half of `width`
+#[must_use]
+pub fn side_by_side_diff(
+    mut stdout_buffer: Vec<String>,
+    old: &str,
+    new: &str,
+    context: usize,
+    max_hunks: Option<usize>,
+    width: usize,
+) -> Vec<String> {
+    let diff = TextDiff::from_lines(old, new);
+    let groups = diff.grouped_ops(context);
+    let gutter = gutter_width(&groups);
+    let old_lines = diff.old_slices();
+    let new_lines = diff.new_slices();
+    // each column has a line number gutter, and they're separated by ` │ `
+    let column_width = (width.saturating_sub(3) / 2).saturating_sub(gutter).max(8);
+
+    for (idx, group) in groups
+        .iter()
+        .take(max_hunks.unwrap_or(usize::MAX))
+        .enumerate()
+    {
+        if idx > 0 {
+            stdout_buffer.push(format!("{:-^1$}\n", "-", width));
+        }
+        for op in group {
+            let (tag, old_range, new_range) = op.as_tag_tuple();
+            let (old_style, new_style) = match tag {
+                DiffTag::Equal => (Style::new().dim(), Style::new().dim()),
+                _ => (Style::new().red(), Style::new().green()),
+            };
+            for i in 0..old_range.len().max(new_range.len()) {
+                let old_line = (i < old_range.len())
+                    .then(|| (old_range.start + i, old_lines[old_range.start + i]));
+                let new_line = (i < new_range.len())
+                    .then(|| (new_range.start + i, new_lines[new_range.start + i]));
+                stdout_buffer.push(format!(
+                    "{} │ {}\n",
+                    column(old_line, column_width, gutter, &old_style),
+                    column(new_line, column_width, gutter, &new_style)
+                ));
+            }
+        }
+    }
+    stdout_buffer.extend(more_changes(groups.len(), max_hunks));
 
     stdout_buffer
 }
+
+/// A unified diff of one file, with paths relative to the vault, that `git apply` and `patch -p1`
+/// understand
+///
+/// A file that doesn't exist yet has no `old` content, and is diffed against `/dev/null`.
+#[must_use]
+pub fn unified_patch(path: &str, old: Option<&str>, new: &str, context: usize) -> String {
+    let old_header = old.map_or_else(|| "/dev/null".to_string(), |_| format!("a/{path}"));
+    TextDiff::from_lines(old.unwrap_or_default(), new)
+        .unified_diff()
+        .context_radius(context)
+        .header(&old_header, &format!("b/{path}"))
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD: &str = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n";
+    const NEW: &str = "1\n2\n3\n4\n5\nsix\n7\n8\n9\n10\n11\n";
+
+    fn unchanged_lines_shown(context: usize) -> usize {
+        console::set_colors_enabled(false);
+        diff(vec![], OLD, NEW, context, None)
+            .iter()
+            .filter(|s| s.ends_with("| "))
+            .count()
+    }
+
+    #[test]
+    fn side_by_side_diff_shows_old_and_new_in_columns() {
+        console::set_colors_enabled(false);
+        let output = side_by_side_diff(
+            vec![],
+            "same\nold line\nsame\n",
+            "same\nnew line\nsame\n",
+            3,
+            None,
+            40,
+        );
+
+        assert_eq!(
+            output,
+            vec![
+                "1   same           │ 1   same          \n",
+                "2   old line       │ 2   new line      \n",
+                "3   same           │ 3   same          \n",
+            ]
+        );
+    }
+
+    #[test]
+    fn side_by_side_diff_pads_unmatched_lines() {
+        console::set_colors_enabled(false);
+        let output = side_by_side_diff(vec![], "a\n", "a\nadded\n", 3, None, 40);
+
+        assert_eq!(output[1], "                   │ 2   added         \n");
+    }
+
+    #[test]
+    fn diff_stops_after_max_hunks() {
+        console::set_colors_enabled(false);
+        let old = (1..=40).map(|i| format!("{i}\n")).collect::<String>();
+        let new = old
+            .replace("\n5\n", "\nfive\n")
+            .replace("\n15\n", "\nfifteen\n")
+            .replace("\n25\n", "\ntwenty five\n")
+            .replace("\n35\n", "\nthirty five\n");
+
+        let all = diff(vec![], &old, &new, 0, None);
+        assert!(all.iter().any(|s| s.contains("thirty five")));
+        assert!(!all.iter().any(|s| s.contains("more changes")));
+
+        let truncated = diff(vec![], &old, &new, 0, Some(2));
+        assert!(truncated.iter().any(|s| s.contains("fifteen")));
+        assert!(!truncated.iter().any(|s| s.contains("twenty five")));
+        assert_eq!(truncated.last().unwrap(), "... (2 more changes)\n");
+
+        let side_by_side = side_by_side_diff(vec![], &old, &new, 0, Some(3), 40);
+        assert_eq!(side_by_side.last().unwrap(), "... (1 more changes)\n");
+    }
+
+    #[test]
+    fn color_mode_precedence() {
+        let set = Some(OsStr::new("1"));
+        assert!(ColorMode::Always.is_enabled(set, false));
+        assert!(!ColorMode::Never.is_enabled(None, true));
+        assert!(!ColorMode::Auto.is_enabled(set, true));
+        assert!(ColorMode::Auto.is_enabled(Some(OsStr::new("")), true));
+        assert!(ColorMode::Auto.is_enabled(None, true));
+        assert!(!ColorMode::Auto.is_enabled(None, false));
+    }
+
+    #[test]
+    fn gutter_fits_large_line_numbers() {
+        console::set_colors_enabled(false);
+        let old = (1..=12400).map(|i| format!("{i}\n")).collect::<String>();
+        let new = old.replace("\n12345\n", "\nchanged\n");
+
+        let output = diff(vec![], &old, &new, 1, None);
+        assert_eq!(output[0], "12344 12344  | ");
+        assert_eq!(output[2], "12345        |-");
+        assert_eq!(output[5], "      12345  |+");
+
+        let output = side_by_side_diff(vec![], &old, &new, 0, None, 40);
+        assert_eq!(output, vec!["12345 12345        │ 12345 changed     \n"]);
+    }
+
+    #[test]
+    fn diff_context_controls_unchanged_lines() {
+        assert_eq!(unchanged_lines_shown(0), 0);
+        assert_eq!(unchanged_lines_shown(1), 2);
+        assert_eq!(unchanged_lines_shown(3), 6);
+        assert_eq!(unchanged_lines_shown(10), 10);
+    }
+}
@@ -0,0 +1,267 @@
+use std::{fmt, path::PathBuf};
+
+use markdown::mdast::Node;
+use rayon::prelude::*;
+
+use crate::{
+    markdown_file::{normalize_identifier, MdastDocument, RenderOptions},
+    util::{iterate_markdown_files, WalkOptions},
+};
+
+/// What a node means, without where it was or how it was written
+#[derive(Debug, PartialEq, Eq)]
+struct Shape {
+    kind: String,
+    value: String,
+    children: Vec<Shape>,
+}
+
+impl fmt::Display for Shape {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const MAX_CHARS: usize = 40;
+        if self.value.is_empty() {
+            return write!(f, "{}", self.kind);
+        }
+        let value = match self.value.char_indices().nth(MAX_CHARS) {
+            Some((end, _)) => format!("{}…", &self.value[..end]),
+            None => self.value.clone(),
+        };
+        write!(f, "{} {value:?}", self.kind)
+    }
+}
+
+/// The identifier a reference or definition is matched by
+fn identifier(identifier: &str, label: &Option<String>) -> String {
+    normalize_identifier(label.as_deref().unwrap_or(identifier))
+}
+
+/// The kind of a node, and whatever else about it changes what it means
+fn describe(node: &Node) -> (String, String) {
+    let (kind, value) = match node {
+        Node::Root(_) => ("document", String::new()),
+        Node::Heading(h) => return (format!("heading {}", h.depth), String::new()),
+        Node::List(l) if l.ordered => ("ordered list", l.start.unwrap_or(1).to_string()),
+        Node::List(_) => ("list", String::new()),
+        Node::ListItem(li) => match li.checked {
+            Some(true) => ("checked todo", String::new()),
+            Some(false) => ("todo", String::new()),
+            None => ("item", String::new()),
+        },
+        Node::Text(t) => ("text", t.value.clone()),
+        Node::InlineCode(c) => ("inline code", c.value.clone()),
+        Node::Code(c) => (
+            "code block",
+            format!(
+                "{} {}\n{}",
+                c.lang.as_deref().unwrap_or_default(),
+                c.meta.as_deref().unwrap_or_default(),
+                c.value
+            )
+            .trim()
+            .to_string(),
+        ),
+        Node::Html(h) => ("html", h.value.clone()),
+        Node::Yaml(y) => ("frontmatter", y.value.trim().to_string()),
+        Node::Link(l) => (
+            "link",
+            format!("{} {}", l.url, l.title.as_deref().unwrap_or_default()),
+        ),
+        Node::Image(i) => (
+            "image",
+            format!(
+                "{} {} {}",
+                i.alt,
+                i.url,
+                i.title.as_deref().unwrap_or_default()
+            ),
+        ),
+        Node::LinkReference(r) => ("reference", identifier(&r.identifier, &r.label)),
+        Node::ImageReference(r) => (
+            "image reference",
+            format!("{} {}", r.alt, identifier(&r.identifier, &r.label)),
+        ),
+        Node::Definition(d) => (
+            "definition",
+            format!(
+                "{} {} {}",
+                identifier(&d.identifier, &d.label),
+                d.url,
+                d.title.as_deref().unwrap_or_default()
+            ),
+        ),
+        Node::FootnoteReference(f) => ("footnote reference", f.identifier.clone()),
+        Node::FootnoteDefinition(f) => ("footnote", f.identifier.clone()),
+        node => {
+            // the rest, like paragraphs and emphasis, only mean what their children do
+            let debug = format!("{node:?}");
+            let kind = debug.split(['(', ' ']).next().unwrap_or_default();
+            return (kind.to_lowercase(), String::new());
+        }
+    };
+    (kind.to_string(), value.trim_end().to_string())
+}
+
+/// The shape of a node and its children, with adjacent text joined since the parser may split it
+/// differently after rendering
+fn shape(node: &Node) -> Shape {
+    let (kind, value) = describe(node);
+    let mut children: Vec<Shape> = vec![];
+    for child in node.children().into_iter().flatten() {
+        let child = shape(child);
+        match children.last_mut() {
+            Some(last) if last.kind == "text" && child.kind == "text" => {
+                last.value.push_str(&child.value);
+            }
+            _ => children.push(child),
+        }
+    }
+    Shape {
+        kind,
+        value,
+        children,
+    }
+}
+
+/// The first place `after` means something else than `before`, described by the nodes leading to it
+fn difference(before: &Shape, after: &Shape, path: &mut Vec<String>) -> Option<String> {
+    if (&before.kind, &before.value) != (&after.kind, &after.value) {
+        return Some(format!("{}: {before} became {after}", path.join(" > ")));
+    }
+    path.push(before.kind.clone());
+    for n in 0..before.children.len().max(after.children.len()) {
+        let found = match (before.children.get(n), after.children.get(n)) {
+            (Some(before), Some(after)) => difference(before, after, path),
+            (Some(before), None) => Some(format!("{}: {before} was lost", path.join(" > "))),
+            (None, Some(after)) => Some(format!("{}: {after} was added", path.join(" > "))),
+            (None, None) => None,
+        };
+        if found.is_some() {
+            return found;
+        }
+    }
+    path.pop();
+    None
+}
+
+/// How rendering the content and parsing it again changes what it means, or `None` if only its
+/// whitespace and the way it's written change
+pub fn round_trip_difference(content: &str) -> Option<String> {
+    let before = MdastDocument::parse(content);
+    let render = before.render_with(RenderOptions {
+        source: Some(content),
+        ..Default::default()
+    });
+    let after = MdastDocument::parse(&render);
+    difference(
+        &shape(&Node::Root(before.root)),
+        &shape(&Node::Root(after.root)),
+        &mut vec![],
+    )
+}
+
+/// Print every file that formatting would change the meaning of, and why, before formatting for
+/// real
+pub fn print_doctor(vault_path: &PathBuf, walk: &WalkOptions) -> Option<i32> {
+    let mut flagged = iterate_markdown_files(vault_path, walk)
+        .filter_map(|file| {
+            round_trip_difference(&file.content).map(|difference| (file.path, difference))
+        })
+        .collect::<Vec<_>>();
+    flagged.sort();
+
+    for (path, difference) in &flagged {
+        let path = path.strip_prefix(vault_path).unwrap_or(path);
+        println!("{}: {difference}", path.display());
+    }
+    Some(i32::from(!flagged.is_empty()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn clean_file_passes() {
+        assert_eq!(
+            round_trip_difference(indoc! {r#"
+                ---
+                title: note
+                ---
+                # Heading
+
+                Some *text*   with [a link](https://example.com) and [a reference].
+                * [x] done
+                * [ ] open
+                    1. nested
+
+                > quote
+
+                | a | b |
+                |---|---|
+                | 1 | 2 |
+
+                [A  Reference]: https://example.com
+            "#}),
+            None
+        );
+    }
+
+    #[test]
+    fn lossy_construct_is_flagged() {
+        // the renderer doesn't write the titles of links and definitions
+        assert_eq!(
+            round_trip_difference(indoc! {r#"
+                See [the docs].
+
+                [the docs]: https://example.com "The docs"
+            "#}),
+            Some(
+                r#"document: definition "the docs https://example.com The docs" became definition "the docs https://example.com""#
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            round_trip_difference("# Title\n\n- [x] [docs](https://example.com \"Docs\")\n"),
+            Some(
+                r#"document > list > checked todo > paragraph: link "https://example.com Docs" became link "https://example.com""#
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn difference_is_described_by_its_path() {
+        let text = |value: &str| Shape {
+            kind: "text".to_string(),
+            value: value.to_string(),
+            children: vec![],
+        };
+        let paragraph = |children| Shape {
+            kind: "paragraph".to_string(),
+            value: String::new(),
+            children,
+        };
+        assert_eq!(
+            difference(
+                &paragraph(vec![text("a"), text("b")]),
+                &paragraph(vec![text("a")]),
+                &mut vec![]
+            ),
+            Some(r#"paragraph: text "b" was lost"#.to_string())
+        );
+        assert_eq!(
+            difference(
+                &paragraph(vec![text(&"x".repeat(50))]),
+                &paragraph(vec![text("y")]),
+                &mut vec![]
+            ),
+            Some(format!(
+                r#"paragraph: text "{}…" became text "y""#,
+                "x".repeat(40)
+            ))
+        );
+    }
+}
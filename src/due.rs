@@ -0,0 +1,206 @@
+use std::path::PathBuf;
+
+use chrono::NaiveDate;
+use clap::Args;
+use lazy_static::lazy_static;
+use markdown::mdast::{self, Node};
+use rayon::prelude::*;
+use regex::Regex;
+
+use crate::{
+    archive::move_list_items,
+    markdown_file::{list_item_text, MdastDocument},
+    util::{iterate_tagged_markdown_files, DateOptions, WalkOptions},
+};
+
+lazy_static! {
+    // the due date format used by the obsidian tasks plugin
+    static ref DUE_DATE: Regex = Regex::new(r"📅\s*(\d{4}-\d{2}-\d{2})").unwrap();
+}
+
+#[derive(Args, Debug, Default, Clone)]
+pub struct DueOptions {
+    /// Move open todos that are past their due date into an Overdue section
+    #[arg(long, default_value = "false")]
+    pub overdue: bool,
+}
+
+fn due_date(list_item: &mdast::ListItem) -> Option<NaiveDate> {
+    DUE_DATE
+        .captures(&list_item_text(list_item))
+        .and_then(|c| NaiveDate::parse_from_str(&c[1], "%Y-%m-%d").ok())
+}
+
+fn is_overdue(node: &Node, today: NaiveDate) -> bool {
+    match node {
+        Node::ListItem(list_item) => {
+            list_item.checked == Some(false) && due_date(list_item).map_or(false, |d| d < today)
+        }
+        _ => false,
+    }
+}
+
+fn move_overdue_mdast(mdast: &mdast::Root, today: NaiveDate) -> Option<mdast::Root> {
+    move_list_items(mdast, "Overdue", |node| is_overdue(node, today))
+}
+
+/// Every open todo with a due date, at any depth
+fn open_due_tasks(nodes: &[Node], tasks: &mut Vec<(NaiveDate, String)>) {
+    for node in nodes {
+        if let Node::ListItem(list_item) = node {
+            if list_item.checked == Some(false) {
+                if let Some(date) = due_date(list_item) {
+                    tasks.push((date, list_item_text(list_item)));
+                }
+            }
+        }
+        if let Some(children) = node.children() {
+            open_due_tasks(children, tasks);
+        }
+    }
+}
+
+#[must_use]
+pub fn move_overdue(
+    vault_path: &PathBuf,
+    walk: &WalkOptions,
+    today: NaiveDate,
+) -> impl ParallelIterator<Item = (PathBuf, String)> {
+    iterate_tagged_markdown_files(vault_path, walk, "todo")
+        .map(|file| (file.path, MdastDocument::parse(file.content.as_str())))
+        .filter_map(move |(path, document)| {
+            move_overdue_mdast(&document.root, today)
+                .map(|mdast| (path, MdastDocument { root: mdast }.render()))
+        })
+}
+
+pub fn print_due(
+    vault_path: &PathBuf,
+    walk: &WalkOptions,
+    dates: &DateOptions,
+    today: NaiveDate,
+) -> Option<i32> {
+    let mut tasks = iterate_tagged_markdown_files(vault_path, walk, "todo")
+        .flat_map_iter(|file| {
+            let mut tasks = vec![];
+            open_due_tasks(
+                &MdastDocument::parse(&file.content).root.children,
+                &mut tasks,
+            );
+            let path = file
+                .path
+                .strip_prefix(vault_path)
+                .unwrap_or(&file.path)
+                .display()
+                .to_string();
+            tasks
+                .into_iter()
+                .map(move |(date, text)| (date, path.clone(), text))
+        })
+        .collect::<Vec<_>>();
+    tasks.sort();
+
+    for (date, path, text) in tasks {
+        let overdue = if date < today { " (overdue)" } else { "" };
+        println!("{}{overdue} {path}: {text}", dates.format_date(date));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    fn today() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 1, 10).unwrap()
+    }
+
+    fn first_item(input: &str) -> Node {
+        let document = MdastDocument::parse(input);
+        match document.root.children.first() {
+            Some(Node::List(list)) => list.children[0].clone(),
+            _ => panic!("expected a list"),
+        }
+    }
+
+    #[test]
+    fn overdue_detection() {
+        assert!(is_overdue(
+            &first_item("- [ ] pay rent 📅 2024-01-05"),
+            today()
+        ));
+        assert!(!is_overdue(
+            &first_item("- [ ] due today 📅 2024-01-10"),
+            today()
+        ));
+        assert!(!is_overdue(
+            &first_item("- [ ] future 📅 2024-02-01"),
+            today()
+        ));
+        assert!(!is_overdue(
+            &first_item("- [x] done 📅 2024-01-05"),
+            today()
+        ));
+        assert!(!is_overdue(&first_item("- [ ] no date"), today()));
+        assert!(!is_overdue(
+            &first_item("- [ ] bad date 📅 2024-13-40"),
+            today()
+        ));
+    }
+
+    #[test]
+    fn overdue_tasks_are_moved() {
+        let input = MdastDocument::parse(indoc! {r#"
+            #todo
+
+            - [ ] late 📅 2024-01-05
+            - [ ] future 📅 2024-02-01
+            - [x] done 📅 2024-01-01
+                - [ ] nested late 📅 2024-01-02
+            - [ ] no date
+        "#});
+
+        let moved = MdastDocument::of(move_overdue_mdast(&input.root, today()).unwrap());
+
+        assert_eq!(
+            moved.render(),
+            indoc! {r#"
+            #todo
+
+            - [ ] future 📅 2024-02-01
+            - [x] done 📅 2024-01-01
+                - [ ] nested late 📅 2024-01-02
+            - [ ] no date
+
+            ## Overdue
+
+            - [ ] late 📅 2024-01-05
+            "#}
+        );
+        assert!(move_overdue_mdast(&MdastDocument::parse(&moved.render()).root, today()).is_none());
+    }
+
+    #[test]
+    fn overdue_tasks_are_added_to_existing_section() {
+        let input = MdastDocument::parse(indoc! {r#"
+            - [ ] late 📅 2024-01-05
+
+            ## Overdue
+
+            - [ ] older 📅 2024-01-01
+        "#});
+
+        assert_eq!(
+            MdastDocument::of(move_overdue_mdast(&input.root, today()).unwrap()).render(),
+            indoc! {r#"
+            ## Overdue
+
+            - [ ] late 📅 2024-01-05
+            - [ ] older 📅 2024-01-01
+            "#}
+        );
+    }
+}
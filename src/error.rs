@@ -0,0 +1,97 @@
+use std::io;
+
+use thiserror::Error;
+
+/// Everything that can go wrong in marksage, so embedders can tell failures apart
+#[derive(Error, Debug)]
+pub enum Error {
+    /// Reading or writing a file failed
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    /// A file couldn't be decoded or encoded with the chosen encoding
+    #[error("{0}")]
+    Encoding(String),
+
+    /// The value of an option couldn't be parsed
+    #[error("{0}")]
+    Parse(String),
+
+    /// A tag, heading or pattern isn't a valid regex
+    #[error(transparent)]
+    Regex(#[from] regex::Error),
+
+    /// An ntfy or click url isn't valid
+    #[cfg(feature = "notify")]
+    #[error(transparent)]
+    Url(#[from] url::ParseError),
+
+    /// A notification couldn't be sent
+    #[cfg(feature = "notify")]
+    #[error("failed to send notification: {0}")]
+    Notify(#[from] NotifyError),
+
+    /// The vault couldn't be watched for changes
+    #[cfg(feature = "watch")]
+    #[error(transparent)]
+    Watch(#[from] notify::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Why a notification couldn't be sent
+#[cfg(feature = "notify")]
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum NotifyError {
+    /// The server answered with an http error status
+    #[error("status code {0}")]
+    Status(u16),
+    /// The server never answered, like when the connection failed
+    #[error("{0}")]
+    Unanswered(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use crate::{
+        markdown_file::{Encoding, File},
+        util::markdown_contains_heading,
+    };
+
+    #[test]
+    fn errors_are_told_apart() {
+        let vault = tempfile::tempdir().unwrap();
+        let path = vault.path().join("latin1.md");
+        fs::write(&path, b"caf\xe9").unwrap();
+
+        assert!(matches!(
+            File::at_path(vault.path().join("missing.md"), Encoding::Utf8),
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::NotFound
+        ));
+        assert!(matches!(
+            File::at_path(path.clone(), Encoding::Utf8),
+            Err(Error::Encoding(_))
+        ));
+        assert!(matches!(
+            File::atomic_overwrite(&path, "日本".to_string(), Encoding::Latin1),
+            Err(Error::Encoding(_))
+        ));
+        assert!(matches!(
+            markdown_contains_heading("(unclosed"),
+            Err(Error::Regex(_))
+        ));
+    }
+
+    #[cfg(feature = "watch")]
+    #[test]
+    fn watch_errors_convert() {
+        assert!(matches!(
+            Error::from(notify::Error::generic("no watcher")),
+            Error::Watch(_)
+        ));
+    }
+}
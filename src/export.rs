@@ -0,0 +1,234 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use clap::{Args, ValueEnum};
+use markdown::mdast::Node;
+use rayon::prelude::*;
+
+use crate::{
+    error::{Error, Result},
+    format_files::map_urls,
+    markdown_file::{File, MdastDocument, RenderFlavor, RenderOptions},
+    util::{is_relative_url, iterate_markdown_files, WalkOptions},
+};
+
+#[derive(Debug, Clone)]
+pub struct CheckboxSymbols {
+    pub unchecked: String,
+    pub checked: String,
+}
+
+fn parse_checkbox_symbols(arg: &str) -> Result<CheckboxSymbols> {
+    match arg.split_once(',') {
+        Some((unchecked, checked)) if !unchecked.is_empty() && !checked.is_empty() => {
+            Ok(CheckboxSymbols {
+                unchecked: unchecked.to_string(),
+                checked: checked.to_string(),
+            })
+        }
+        _ => Err(Error::Parse(
+            "expected an unchecked and checked symbol like `☐,☑`".to_string(),
+        )),
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ExportOptions {
+    /// The folder to write rendered files to, mirroring the vault's layout
+    #[arg(short, long)]
+    pub output_dir: PathBuf,
+
+    /// Render todos with an unchecked and checked symbol instead of `[ ]` and `[x]`, like `☐,☑`
+    #[arg(long, value_name = "UNCHECKED,CHECKED", value_parser = parse_checkbox_symbols)]
+    pub checkbox_symbols: Option<CheckboxSymbols>,
+
+    /// The markdown flavor to export, commonmark turns tables, todos and strikethrough into
+    /// html or plain list items
+    #[arg(long, value_enum, default_value_t = RenderFlavor::Gfm)]
+    pub flavor: RenderFlavor,
+
+    /// How to write the `.md` extension of markdown links to other notes
+    #[arg(long, value_enum, default_value_t = LinkExtension::Keep)]
+    pub internal_link_extension: LinkExtension,
+}
+
+/// How markdown links to other notes in the vault are written
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LinkExtension {
+    /// As they are in the vault
+    #[default]
+    Keep,
+    /// Without `.md`, like `[text](note)`, as some static site generators want
+    Strip,
+    /// With `.md`, like `[text](note.md)`, for links to notes that leave it out
+    Add,
+}
+
+impl LinkExtension {
+    /// The url of a link from the note at `path` with its extension changed, or `None` to keep it
+    fn apply(self, path: &Path, url: &str) -> Option<String> {
+        if self == LinkExtension::Keep || !is_relative_url(url) {
+            return None;
+        }
+        let (file, fragment) = match url.find('#') {
+            Some(index) => url.split_at(index),
+            None => (url, ""),
+        };
+        let file = match self {
+            LinkExtension::Strip => file.strip_suffix(".md")?.to_string(),
+            // only for notes that exist, so links to folders and pages elsewhere are left alone
+            LinkExtension::Add => {
+                let note = path
+                    .parent()?
+                    .join(format!("{}.md", file.replace("%20", " ")));
+                (!file.is_empty() && Path::new(file).extension().is_none() && note.is_file())
+                    .then(|| format!("{file}.md"))?
+            }
+            LinkExtension::Keep => return None,
+        };
+        Some(format!("{file}{fragment}"))
+    }
+}
+
+impl ExportOptions {
+    fn render_options(&self) -> RenderOptions<'_> {
+        RenderOptions {
+            checkbox_symbols: self
+                .checkbox_symbols
+                .as_ref()
+                .map(|s| (s.unchecked.as_str(), s.checked.as_str())),
+            flavor: self.flavor,
+            ..Default::default()
+        }
+    }
+}
+
+/// Render every file in the vault into `output_dir`
+///
+/// The vault itself is never changed, so export options don't need to round trip as gfm.
+pub fn export(vault_path: &PathBuf, walk: &WalkOptions, options: &ExportOptions) -> Option<i32> {
+    iterate_markdown_files(vault_path, walk)
+        .filter(|file| !file.path.starts_with(&options.output_dir))
+        .map(|file| {
+            let relative_path = file.path.strip_prefix(vault_path).unwrap_or(&file.path);
+            let output_path = options.output_dir.join(relative_path);
+            let mut document = MdastDocument::parse(&file.content);
+            let mut root = Node::Root(document.root);
+            map_urls(&mut root, &|url| {
+                options.internal_link_extension.apply(&file.path, url)
+            });
+            let Node::Root(root) = root else {
+                unreachable!()
+            };
+            document.root = root;
+            let render = document.render_with(options.render_options());
+
+            let result = output_path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .map_err(Error::from)
+                .and_then(|_| File::atomic_overwrite(&output_path, render, walk.encoding));
+            match result {
+                Ok(()) => {
+                    println!("Exported {}", output_path.display());
+                    0
+                }
+                Err(e) => {
+                    eprintln!("Failed to export {}: {e}", output_path.display());
+                    1
+                }
+            }
+        })
+        .max()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn export_renders_checkbox_symbols_without_changing_vault() {
+        let vault = tempfile::tempdir().unwrap();
+        let output_dir = tempfile::tempdir().unwrap();
+        fs::create_dir(vault.path().join("folder")).unwrap();
+        fs::write(
+            vault.path().join("folder/todo.md"),
+            "- [ ] open\n- [x] done\n",
+        )
+        .unwrap();
+
+        let exit_code = export(
+            &vault.path().to_path_buf(),
+            &WalkOptions::default(),
+            &ExportOptions {
+                output_dir: output_dir.path().to_path_buf(),
+                checkbox_symbols: Some(parse_checkbox_symbols("☐,☑").unwrap()),
+                flavor: RenderFlavor::Gfm,
+                internal_link_extension: LinkExtension::Keep,
+            },
+        );
+
+        assert_eq!(exit_code, Some(0));
+        assert_eq!(
+            fs::read_to_string(output_dir.path().join("folder/todo.md")).unwrap(),
+            "- ☐ open\n- ☑ done\n"
+        );
+        assert_eq!(
+            fs::read_to_string(vault.path().join("folder/todo.md")).unwrap(),
+            "- [ ] open\n- [x] done\n"
+        );
+    }
+
+    fn exported_links(extension: LinkExtension) -> String {
+        let vault = tempfile::tempdir().unwrap();
+        let output_dir = tempfile::tempdir().unwrap();
+        fs::create_dir(vault.path().join("folder")).unwrap();
+        fs::write(vault.path().join("folder/other note.md"), "").unwrap();
+        fs::write(
+            vault.path().join("note.md"),
+            "[a](folder/other%20note.md#heading) [b](folder/other%20note) [c](folder) \
+             [d](https://example.com/page.md) [e](image.png) [f](#heading)\n",
+        )
+        .unwrap();
+
+        export(
+            &vault.path().to_path_buf(),
+            &WalkOptions::default(),
+            &ExportOptions {
+                output_dir: output_dir.path().to_path_buf(),
+                checkbox_symbols: None,
+                flavor: RenderFlavor::Gfm,
+                internal_link_extension: extension,
+            },
+        );
+        fs::read_to_string(output_dir.path().join("note.md")).unwrap()
+    }
+
+    #[test]
+    fn internal_link_extensions_are_stripped() {
+        assert_eq!(
+            exported_links(LinkExtension::Strip),
+            "[a](folder/other%20note#heading) [b](folder/other%20note) [c](folder) \
+             [d](https://example.com/page.md) [e](image.png) [f](#heading)\n"
+        );
+    }
+
+    #[test]
+    fn internal_link_extensions_are_added() {
+        assert_eq!(
+            exported_links(LinkExtension::Add),
+            "[a](folder/other%20note.md#heading) [b](folder/other%20note.md) [c](folder) \
+             [d](https://example.com/page.md) [e](image.png) [f](#heading)\n"
+        );
+    }
+
+    #[test]
+    fn checkbox_symbols_need_both_symbols() {
+        assert!(parse_checkbox_symbols("☐").is_err());
+        assert!(parse_checkbox_symbols(",☑").is_err());
+    }
+}
@@ -1,55 +1,1404 @@
-use std::{borrow::Cow, path::PathBuf};
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    ops::Range,
+    path::{Path, PathBuf},
+};
 
+use clap::{Args, ValueEnum};
 use lazy_static::lazy_static;
 use markdown::mdast::{self, Node};
 use rayon::prelude::ParallelIterator;
 use regex::Regex;
 use replace_with::replace_with_or_abort;
+use serde::{Deserialize, Deserializer};
 
-use crate::{markdown_file::MdastDocument, util::iterate_markdown_files};
+use crate::{
+    config::Configs,
+    frontmatter,
+    markdown_file::{
+        self, is_protected_code, split_block_id, MdastDocument, OrderedDelimiter, RenderOptions,
+    },
+    util::{iterate_markdown_files, TagMatcher, WalkOptions},
+};
+
+#[derive(Args, Debug, Default, Clone)]
+pub struct FormatOptions {
+    /// Merge consecutive top level lists of the same type into a single list
+    #[arg(long, default_value = "false")]
+    pub merge_adjacent_lists: bool,
+
+    /// The delimiter to use after the number of ordered list items
+    #[arg(long, value_enum, default_value_t = OrderedDelimiter::Dot)]
+    pub ordered_delimiter: OrderedDelimiter,
+
+    /// Normalize the created, modified and date frontmatter fields to YYYY-MM-DD
+    #[arg(long, default_value = "false")]
+    pub format_frontmatter_dates: bool,
+
+    /// An additional frontmatter field to normalize with --format-frontmatter-dates
+    #[arg(long = "frontmatter-date-field", value_name = "FIELD")]
+    pub frontmatter_date_fields: Vec<String>,
+
+    /// Rewrite frontmatter booleans like `yes` and `off` as `true` and `false`, and `~` as `null`
+    #[arg(long, default_value = "false")]
+    pub canonicalize_frontmatter_scalars: bool,
+
+    /// Sort and dedupe the tags in frontmatter, so nested tags like `a/b` follow their parent
+    #[arg(long, default_value = "false")]
+    pub sort_frontmatter_tags: bool,
+
+    /// Keep code blocks indented by four spaces indented, instead of converting them to fences
+    #[arg(long, default_value = "false")]
+    pub preserve_indented_code: bool,
+
+    /// Leave code blocks in this language exactly as written, fences and info string included, even
+    /// by lints, can be given more than once
+    #[arg(long = "protect-lang", value_name = "LANG")]
+    pub protected_langs: Vec<String>,
+
+    /// Write files that formatting would leave empty, instead of skipping them
+    #[arg(long, default_value = "false")]
+    pub allow_empty: bool,
+
+    /// Only format the frontmatter, sorting its top level keys, and leave the rest of the file untouched
+    #[arg(long, default_value = "false", conflicts_with = "body_only")]
+    pub frontmatter_only: bool,
+
+    /// Only format the content after the frontmatter, keeping the frontmatter exactly as written
+    #[arg(long, default_value = "false")]
+    pub body_only: bool,
+
+    /// Warn about files that formatting would grow by more than this percentage, like `50`
+    #[arg(long, value_name = "PCT")]
+    pub growth_warning: Option<f64>,
+
+    /// Skip files that formatting would grow by more than this percentage, in case of a bug that
+    /// duplicates content
+    #[arg(long, value_name = "PCT")]
+    pub max_growth: Option<f64>,
+
+    /// Leave files with this tag unformatted, can be given more than once
+    #[arg(long = "exclude-tag", value_name = "TAG")]
+    pub exclude_tags: Vec<String>,
+
+    /// The passes to run, in order, instead of the default of em-dash followed by the passes
+    /// enabled by other options, or those from `format.passes` in `marksage.toml`
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "PASS")]
+    pub passes: Option<Vec<PassName>>,
+
+    /// Only normalize structure and spacing, skipping every pass that changes what the text or
+    /// frontmatter says, like em-dash
+    #[arg(long, default_value = "false")]
+    pub structural_only: bool,
+
+    /// Skip a pass that would otherwise run, can be given more than once
+    #[arg(long = "disable-pass", value_enum, value_name = "PASS")]
+    pub disabled_passes: Vec<PassName>,
+
+    /// Skip a lint, built in like trailing-newline or from `format.lints` in `marksage.toml`, can
+    /// be given more than once
+    #[arg(long = "disable-lint", value_name = "NAME")]
+    pub disabled_lints: Vec<String>,
+
+    /// Regex replacements run over each formatted file before the built in lints, from
+    /// `format.lints` in `marksage.toml`
+    #[arg(skip)]
+    pub lints: Vec<Lint>,
+
+    /// Leave files alone when formatting would only change trailing whitespace or the final
+    /// newline, so they aren't rewritten for nothing
+    #[arg(long, default_value = "false")]
+    pub skip_whitespace_only: bool,
+
+    /// Format markdown read from stdin and print it, instead of the files in the vault
+    #[arg(long, default_value = "false")]
+    pub stdin: bool,
+
+    /// Print the passes that would change each file, instead of formatting them
+    #[arg(long, default_value = "false", conflicts_with = "stdin")]
+    pub explain: bool,
+
+    /// Write the formatted stdin to this file, relative to the vault, creating it and its folders
+    /// if they don't exist, instead of printing it
+    #[arg(long, value_name = "FILE", requires = "stdin")]
+    pub out: Option<PathBuf>,
+
+    /// The configs of the vault, applied to each file formatted
+    #[arg(skip)]
+    pub configs: Configs,
+
+    /// Keep running, and reformat each file as it is saved instead of formatting the whole vault
+    #[arg(long, default_value = "false")]
+    #[cfg(feature = "watch")]
+    pub watch: bool,
+
+    /// How long a file must go without changes before --watch reformats it
+    #[arg(long, value_name = "MS", default_value = "300", requires = "watch")]
+    #[cfg(feature = "watch")]
+    pub watch_debounce: u64,
+
+    /// How long --watch ignores changes to a file after writing it, so its own writes don't
+    /// trigger another format
+    #[arg(long, value_name = "MS", default_value = "1000", requires = "watch")]
+    #[cfg(feature = "watch")]
+    pub watch_suppress_window: u64,
+}
+
+/// A regex replacement over the whole of a formatted file, after it's rendered
+///
+/// Lints don't apply with `--frontmatter-only`, and only apply to the body with `--body-only`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Lint {
+    /// What to call the lint, to disable it
+    pub name: String,
+    #[serde(deserialize_with = "deserialize_regex")]
+    pub pattern: Regex,
+    /// What to replace each match with, which can refer to capture groups like `$1`
+    pub replacement: String,
+}
+
+impl PartialEq for Lint {
+    fn eq(&self, other: &Self) -> bool {
+        (&self.name, self.pattern.as_str(), &self.replacement)
+            == (&other.name, other.pattern.as_str(), &other.replacement)
+    }
+}
+
+impl Eq for Lint {}
+
+impl Lint {
+    /// Replace every match that doesn't overlap the protected ranges of the content, returning
+    /// where those ranges moved to
+    fn replace_outside(
+        &self,
+        content: &str,
+        protected: &[Range<usize>],
+    ) -> (String, Vec<Range<usize>>) {
+        let mut replaced = String::with_capacity(content.len());
+        let mut moved = vec![];
+        let mut copy = |replaced: &mut String, from: usize, to: usize| {
+            moved.extend(
+                protected
+                    .iter()
+                    .filter(|range| from <= range.start && range.start < to)
+                    .map(|range| {
+                        let start = replaced.len() + range.start - from;
+                        start..start + range.len()
+                    }),
+            );
+            replaced.push_str(&content[from..to]);
+        };
+        let mut last = 0;
+        for captures in self.pattern.captures_iter(content) {
+            let found = captures.get(0).expect("the whole match is always captured");
+            if protected
+                .iter()
+                .any(|range| found.start() < range.end && range.start < found.end())
+            {
+                continue;
+            }
+            copy(&mut replaced, last, found.start());
+            captures.expand(&self.replacement, &mut replaced);
+            last = found.end();
+        }
+        copy(&mut replaced, last, content.len());
+        (replaced, moved)
+    }
+}
+
+fn deserialize_regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Regex, D::Error> {
+    Regex::new(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+lazy_static! {
+    /// End every file that isn't empty with a single newline, and no other trailing whitespace
+    static ref BUILT_IN_LINTS: Vec<Lint> = vec![Lint {
+        name: "trailing-newline".to_string(),
+        pattern: Regex::new(r"(\S)\s*\z").unwrap(),
+        replacement: "${1}\n".to_string(),
+    }];
+}
+
+/// A transformation of a whole document, run in order by `format_document`
+pub trait Pass {
+    fn apply(&self, document: &mut MdastDocument);
+}
+
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PassName {
+    /// Replace `--` between words with an em-dash
+    EmDash,
+    /// Merge consecutive top level lists of the same type
+    MergeAdjacentLists,
+    /// Normalize frontmatter dates to YYYY-MM-DD
+    FrontmatterDates,
+    /// Canonicalize frontmatter booleans and nulls
+    FrontmatterScalars,
+    /// Sort and dedupe frontmatter tags
+    FrontmatterTags,
+}
+
+impl PassName {
+    /// Whether the pass only rearranges the document, without changing any of its text
+    pub fn is_structural(self) -> bool {
+        match self {
+            PassName::MergeAdjacentLists => true,
+            PassName::EmDash
+            | PassName::FrontmatterDates
+            | PassName::FrontmatterScalars
+            | PassName::FrontmatterTags => false,
+        }
+    }
+}
+
+struct EmDash;
+
+impl Pass for EmDash {
+    fn apply(&self, document: &mut MdastDocument) {
+        replace_with_or_abort(&mut document.root, |root| {
+            let Node::Root(root) = format_node(Node::Root(root)) else {
+                unreachable!()
+            };
+            root
+        });
+    }
+}
+
+struct MergeAdjacentLists;
+
+impl Pass for MergeAdjacentLists {
+    fn apply(&self, document: &mut MdastDocument) {
+        merge_adjacent_lists(&mut document.root);
+    }
+}
+
+struct FrontmatterDates<'a>(&'a FormatOptions);
+
+impl Pass for FrontmatterDates<'_> {
+    fn apply(&self, document: &mut MdastDocument) {
+        if let Some(Node::Yaml(yaml)) = document.root.children.first_mut() {
+            yaml.value = format_frontmatter_dates(&yaml.value, self.0);
+        }
+    }
+}
+
+struct FrontmatterScalars;
+
+impl Pass for FrontmatterScalars {
+    fn apply(&self, document: &mut MdastDocument) {
+        if let Some(Node::Yaml(yaml)) = document.root.children.first_mut() {
+            yaml.value = frontmatter::canonicalize_scalars(&yaml.value);
+        }
+    }
+}
+
+struct FrontmatterTags;
+
+impl Pass for FrontmatterTags {
+    fn apply(&self, document: &mut MdastDocument) {
+        if let Some(Node::Yaml(yaml)) = document.root.children.first_mut() {
+            yaml.value = frontmatter::sort_tags(&yaml.value);
+        }
+    }
+}
+
+impl FormatOptions {
+    /// The passes to run, in order
+    ///
+    /// `--passes`, or `format.passes` in `marksage.toml`, replaces the default order of em-dash,
+    /// merge-adjacent-lists, frontmatter-dates, frontmatter-scalars and then frontmatter-tags.
+    /// Passes rewriting text come before those changing the structure of the document, so text is
+    /// fixed where it was written, and frontmatter comes last since no other pass touches it.
+    pub fn enabled_passes(&self) -> Vec<PassName> {
+        let passes = self.passes.clone().unwrap_or_else(|| {
+            [
+                Some(PassName::EmDash),
+                self.merge_adjacent_lists
+                    .then_some(PassName::MergeAdjacentLists),
+                self.format_frontmatter_dates
+                    .then_some(PassName::FrontmatterDates),
+                self.canonicalize_frontmatter_scalars
+                    .then_some(PassName::FrontmatterScalars),
+                self.sort_frontmatter_tags
+                    .then_some(PassName::FrontmatterTags),
+            ]
+            .into_iter()
+            .flatten()
+            .collect()
+        });
+        passes
+            .into_iter()
+            .filter(|pass| !self.disabled_passes.contains(pass))
+            .filter(|pass| !self.structural_only || pass.is_structural())
+            .collect()
+    }
+
+    fn pass(&self, name: PassName) -> Box<dyn Pass + '_> {
+        match name {
+            PassName::EmDash => Box::new(EmDash),
+            PassName::MergeAdjacentLists => Box::new(MergeAdjacentLists),
+            PassName::FrontmatterDates => Box::new(FrontmatterDates(self)),
+            PassName::FrontmatterScalars => Box::new(FrontmatterScalars),
+            PassName::FrontmatterTags => Box::new(FrontmatterTags),
+        }
+    }
+
+    /// Run the lints from the config and then the built in ones, except those disabled, around
+    /// the protected code blocks
+    fn lint(&self, mut content: String) -> String {
+        let mut protected = self.protected_code(&content);
+        for lint in self.lints.iter().chain(BUILT_IN_LINTS.iter()) {
+            if !self.disabled_lints.contains(&lint.name) {
+                (content, protected) = lint.replace_outside(&content, &protected);
+            }
+        }
+        content
+    }
+
+    /// Where the code blocks in protected languages are in rendered content
+    fn protected_code(&self, content: &str) -> Vec<Range<usize>> {
+        fn find(nodes: &[Node], protected_langs: &[String], found: &mut Vec<Range<usize>>) {
+            for node in nodes {
+                match node {
+                    Node::Code(code) if is_protected_code(code, protected_langs) => {
+                        found.extend(
+                            code.position
+                                .as_ref()
+                                .map(|position| position.start.offset..position.end.offset),
+                        );
+                    }
+                    _ => find(node.children().map_or(&[], |c| c), protected_langs, found),
+                }
+            }
+        }
+
+        let mut found = vec![];
+        if !self.protected_langs.is_empty() {
+            let document = MdastDocument::parse(content);
+            find(&document.root.children, &self.protected_langs, &mut found);
+        }
+        found
+    }
+
+    /// Matchers for the tags of files to leave unformatted
+    pub fn excluded_tags(&self, walk: &WalkOptions) -> Vec<TagMatcher> {
+        self.exclude_tags
+            .iter()
+            .map(|tag| TagMatcher::new(tag.trim_start_matches('#'), walk).unwrap())
+            .collect()
+    }
+
+    fn render_options<'a>(&'a self, source: &'a str) -> RenderOptions<'a> {
+        RenderOptions {
+            ordered_delimiter: self.ordered_delimiter,
+            source: Some(source),
+            preserve_indented_code: self.preserve_indented_code,
+            protected_langs: &self.protected_langs,
+            ..Default::default()
+        }
+    }
+}
 
 lazy_static! {
-    static ref EM_DASH_REPLACE: Regex = Regex::new("([[:alnum:]])(--)([[:alnum:]])").unwrap();
+    // posix classes like [[:alnum:]] are ascii only, so match unicode letters and numbers instead
+    static ref EM_DASH_REPLACE: Regex = Regex::new(r"([\p{L}\p{N}])(--)([\p{L}\p{N}])").unwrap();
 }
 
 fn text_replace(text: String) -> String {
-    match EM_DASH_REPLACE.replace_all(&text, "${1}—${3}") {
+    let (content, block_id) = split_block_id(&text);
+    match EM_DASH_REPLACE.replace_all(content, "${1}—${3}") {
         Cow::Borrowed(_) => text,
-        Cow::Owned(text) => text,
+        Cow::Owned(content) => content + block_id,
     }
 }
 
-fn format_node(mut node: Node) -> Node {
-    if let Node::Text(text) = node {
-        Node::Text(mdast::Text {
-            value: text_replace(text.value),
+fn format_node(node: Node) -> Node {
+    map_text(node, &text_replace)
+}
+
+/// Replace the value of every text node with `f` of it, leaving code, urls and the text of
+/// autolinks alone
+pub fn map_text(mut node: Node, f: &impl Fn(String) -> String) -> Node {
+    match node {
+        Node::Text(text) => Node::Text(mdast::Text {
+            value: f(text.value),
             position: None, // position may be changed by text replacement
-        })
+        }),
+        // the text of an autolink is its url, changing it would turn it into a regular link
+        Node::Link(ref link) if is_autolink(link) => node,
+        _ => {
+            if let Some(children) = node.children_mut() {
+                for child in children.iter_mut() {
+                    replace_with_or_abort(child, |child| map_text(child, f));
+                }
+            }
+            node
+        }
+    }
+}
+
+/// Rewrite the url of every link and definition with `f`, which returns `None` to keep it
+pub fn map_urls(node: &mut Node, f: &impl Fn(&str) -> Option<String>) {
+    match node {
+        Node::Link(link) => {
+            if let Some(url) = f(&link.url) {
+                link.url = url;
+            }
+        }
+        Node::Definition(definition) => {
+            if let Some(url) = f(&definition.url) {
+                definition.url = url;
+            }
+        }
+        _ => {}
+    }
+    if let Some(children) = node.children_mut() {
+        for child in children {
+            map_urls(child, f);
+        }
+    }
+}
+
+/// Matches `<https://example.com>` and gfm literal links like `www.example.com`, whose text is
+/// their url without the scheme markdown adds
+pub(crate) fn is_autolink(link: &mdast::Link) -> bool {
+    let [Node::Text(text)] = link.children.as_slice() else {
+        return false;
+    };
+    ["", "http://", "mailto:"]
+        .iter()
+        .any(|scheme| link.url.strip_prefix(scheme) == Some(text.value.as_str()))
+}
+
+/// Merge top level lists that directly follow a list of the same type (ordered or unordered)
+fn merge_adjacent_lists(root: &mut mdast::Root) {
+    let mut children: Vec<Node> = Vec::with_capacity(root.children.len());
+    for node in root.children.drain(..) {
+        match (children.last_mut(), node) {
+            (Some(Node::List(previous)), Node::List(list)) if previous.ordered == list.ordered => {
+                previous.spread = previous.spread || list.spread;
+                previous.position = None;
+                previous.children.extend(list.children);
+            }
+            (_, node) => children.push(node),
+        }
+    }
+    root.children = children;
+}
+
+pub fn format_document(document: MdastDocument, options: &FormatOptions) -> MdastDocument {
+    let passes = options
+        .enabled_passes()
+        .into_iter()
+        .map(|name| options.pass(name))
+        .collect::<Vec<_>>();
+    run_passes(document, &passes)
+}
+
+/// Run each pass over the document in order
+pub fn run_passes(mut document: MdastDocument, passes: &[Box<dyn Pass + '_>]) -> MdastDocument {
+    for pass in passes {
+        pass.apply(&mut document);
+    }
+    document
+}
+
+fn format_frontmatter_dates(yaml: &str, options: &FormatOptions) -> String {
+    frontmatter::normalize_dates(yaml, &options.frontmatter_date_fields).0
+}
+
+/// A warning for each frontmatter date the frontmatter-dates pass leaves alone because it can't
+/// parse it
+fn frontmatter_date_warnings(content: &str, options: &FormatOptions) -> Vec<String> {
+    if options.body_only
+        || !options
+            .enabled_passes()
+            .contains(&PassName::FrontmatterDates)
+    {
+        return vec![];
+    }
+    match MdastDocument::parse(content).root.children.first() {
+        Some(Node::Yaml(yaml)) => {
+            frontmatter::normalize_dates(&yaml.value, &options.frontmatter_date_fields).1
+        }
+        _ => vec![],
+    }
+}
+
+/// Format only the frontmatter of a file, splicing it back in so the body is byte for byte the same
+fn format_frontmatter_only(content: &str, options: &FormatOptions) -> String {
+    let document = MdastDocument::parse(content);
+    let Some(Node::Yaml(mdast::Yaml {
+        value,
+        position: Some(position),
+    })) = document.root.children.first()
+    else {
+        return content.to_string();
+    };
+
+    let mut value = frontmatter::sort_keys(value);
+    let passes = options.enabled_passes();
+    if passes.contains(&PassName::FrontmatterDates) {
+        value = format_frontmatter_dates(&value, options);
+    }
+    if passes.contains(&PassName::FrontmatterScalars) {
+        value = frontmatter::canonicalize_scalars(&value);
+    }
+    if passes.contains(&PassName::FrontmatterTags) {
+        value = frontmatter::sort_tags(&value);
+    }
+    let frontmatter = if value.is_empty() {
+        "---\n---".to_string()
     } else {
-        if let Some(children) = node.children_mut() {
-            for child in children.iter_mut() {
-                replace_with_or_abort(child, format_node);
+        format!("---\n{value}\n---")
+    };
+
+    format!(
+        "{}{frontmatter}{}",
+        &content[..position.start.offset],
+        &content[position.end.offset..]
+    )
+}
+
+/// Format everything after the frontmatter, keeping the frontmatter byte for byte the same
+fn format_body_only(content: &str, options: &FormatOptions) -> String {
+    let mut document = MdastDocument::parse(content);
+    let frontmatter = match document.root.children.first() {
+        Some(Node::Yaml(mdast::Yaml {
+            position: Some(position),
+            ..
+        })) => Some(&content[position.start.offset..position.end.offset]),
+        _ => None,
+    };
+    if frontmatter.is_some() {
+        document.root.children.remove(0);
+    }
+
+    let body = options
+        .lint(format_document(document, options).render_with(options.render_options(content)));
+    match frontmatter {
+        None => body,
+        Some(frontmatter) if body.is_empty() => format!("{frontmatter}\n"),
+        Some(frontmatter) => format!("{frontmatter}\n\n{body}"),
+    }
+}
+
+/// Whether a change alters what a file says, or only its whitespace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// Only trailing whitespace on lines, or blank lines at the end of the file, changed
+    WhitespaceOnly,
+    Substantive,
+}
+
+/// Classify the change from `old` to `new`
+///
+/// Blank lines and indentation within a file can change how markdown is parsed, so only
+/// whitespace at the end of lines and of the file counts as whitespace only. Even that is
+/// substantive on a line ending in a hard break or inside a code block.
+pub fn classify_change(old: &str, new: &str) -> ChangeKind {
+    fn lines(content: &str) -> Vec<&str> {
+        let mut lines = content.lines().collect::<Vec<_>>();
+        while lines
+            .last()
+            .map_or(false, |line| line.trim_end().is_empty())
+        {
+            lines.pop();
+        }
+        lines
+    }
+    /// The lines, counting from 1, where trailing whitespace is part of the content
+    fn significant_lines(nodes: &[Node], lines: &mut HashSet<usize>) {
+        for node in nodes {
+            match (node, node.position()) {
+                (Node::Code(_), Some(position)) => {
+                    lines.extend(position.start.line..=position.end.line)
+                }
+                (Node::Break(_), Some(position)) => {
+                    lines.insert(position.start.line);
+                }
+                _ => {}
+            }
+            if let Some(children) = node.children() {
+                significant_lines(children, lines);
             }
         }
-        node
+    }
+
+    let (old_lines, new_lines) = (lines(old), lines(new));
+    if old_lines.len() != new_lines.len() {
+        return ChangeKind::Substantive;
+    }
+    let mut significant = HashSet::new();
+    for content in [old, new] {
+        significant_lines(
+            &MdastDocument::parse(content).root.children,
+            &mut significant,
+        );
+    }
+    let is_whitespace_only = old_lines
+        .iter()
+        .zip(&new_lines)
+        .enumerate()
+        .all(|(i, (a, b))| {
+            a == b || (a.trim_end() == b.trim_end() && !significant.contains(&(i + 1)))
+        });
+    if is_whitespace_only {
+        ChangeKind::WhitespaceOnly
+    } else {
+        ChangeKind::Substantive
     }
 }
 
-fn format_document(document: MdastDocument) -> MdastDocument {
-    let Node::Root(root) = format_node(Node::Root(document.root)) else { unreachable!() };
+/// Format the content, or only its frontmatter or body if the options say so
+fn render(content: &str, options: &FormatOptions) -> String {
+    if options.frontmatter_only {
+        format_frontmatter_only(content, options)
+    } else if options.body_only {
+        format_body_only(content, options)
+    } else {
+        options.lint(
+            format_document(MdastDocument::parse(content), options)
+                .render_with(options.render_options(content)),
+        )
+    }
+}
 
-    MdastDocument { root }
+/// The passes that change a file, in the order they run, or `None` if formatting would leave it
+/// alone
+///
+/// Each pass is run after the ones before it, so a pass only counts if it changes the file further.
+/// A file can be changed by rendering alone, with none of its passes changing it.
+#[must_use]
+pub fn explain_file(
+    file: markdown_file::File,
+    options: &FormatOptions,
+    excluded: &[TagMatcher],
+) -> Option<(PathBuf, Vec<PassName>)> {
+    let passes = {
+        let options = &*options.configs.format_options(&file.path, options);
+        let passes = options.enabled_passes();
+        let renders = (0..=passes.len())
+            .map(|n| {
+                render(
+                    &file.content,
+                    &FormatOptions {
+                        passes: Some(passes[..n].to_vec()),
+                        disabled_passes: vec![],
+                        ..options.clone()
+                    },
+                )
+            })
+            .collect::<Vec<_>>();
+        passes
+            .into_iter()
+            .zip(renders.windows(2))
+            .filter(|(_, renders)| renders[0] != renders[1])
+            .map(|(pass, _)| pass)
+            .collect()
+    };
+    format_file(file, options, excluded).map(|(path, _)| (path, passes))
 }
 
+/// Print each file formatting would change, with the passes that change it
 #[must_use]
-pub fn format_files(vault_path: &PathBuf) -> impl ParallelIterator<Item = (PathBuf, String)> {
-    iterate_markdown_files(vault_path).filter_map(|file| {
-        let document = MdastDocument::parse(file.content.as_str());
-        let render = format_document(document).render();
-        if file.content == render {
-            None
+pub fn explain_format(vault_path: &PathBuf, walk: &WalkOptions, options: &FormatOptions) -> i32 {
+    let excluded = options.excluded_tags(walk);
+    let mut explained = iterate_markdown_files(vault_path, walk)
+        .filter_map(|file| explain_file(file, options, &excluded))
+        .collect::<Vec<_>>();
+    explained.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (path, passes) in explained {
+        let passes = passes
+            .iter()
+            .filter_map(|pass| pass.to_possible_value())
+            .map(|pass| pass.get_name().to_string())
+            .collect::<Vec<_>>();
+        if passes.is_empty() {
+            println!("{}: rendering only", path.display());
         } else {
-            Some((file.path, render))
+            println!("{}: {}", path.display(), passes.join(", "));
+        }
+    }
+    0
+}
+
+/// Format a single file, or `None` if it is excluded, already formatted, or would be left empty
+#[must_use]
+pub fn format_file(
+    file: markdown_file::File,
+    options: &FormatOptions,
+    excluded: &[TagMatcher],
+) -> Option<(PathBuf, String)> {
+    let options = &*options.configs.format_options(&file.path, options);
+    if excluded
+        .iter()
+        .any(|matcher| matcher.is_match(&file.content))
+    {
+        return None;
+    }
+    for warning in frontmatter_date_warnings(&file.content, options) {
+        eprintln!("warning: {}: {warning}", file.path.display());
+    }
+    let render = render(&file.content, options);
+    if file.content == render
+        || (options.skip_whitespace_only
+            && classify_change(&file.content, &render) == ChangeKind::WhitespaceOnly)
+    {
+        None
+    } else if render.trim().is_empty() && !file.content.is_empty() && !options.allow_empty {
+        eprintln!(
+            "skipping {}, formatting would leave it empty (use --allow-empty to write it anyway)",
+            file.path.display()
+        );
+        None
+    } else if !growth_is_allowed(&file.path, &file.content, &render, options) {
+        None
+    } else {
+        Some((file.path, render))
+    }
+}
+
+/// How much larger `render` is than `content`, as a percentage
+fn growth_percent(content: &str, render: &str) -> f64 {
+    if content.is_empty() {
+        return 0.0;
+    }
+    (render.len() as f64 - content.len() as f64) / content.len() as f64 * 100.0
+}
+
+/// Warn about a file that would grow past the growth warning, and reject it past the max growth
+fn growth_is_allowed(path: &Path, content: &str, render: &str, options: &FormatOptions) -> bool {
+    let growth = growth_percent(content, render);
+    if options.max_growth.map_or(false, |max| growth > max) {
+        eprintln!(
+            "skipping {}, formatting would grow it by {growth:.0}%, more than --max-growth",
+            path.display()
+        );
+        false
+    } else {
+        if options
+            .growth_warning
+            .map_or(false, |warning| growth > warning)
+        {
+            eprintln!(
+                "warning: formatting grows {} by {growth:.0}%",
+                path.display()
+            );
+        }
+        true
+    }
+}
+
+#[must_use]
+pub fn format_files<'a>(
+    vault_path: &PathBuf,
+    walk: &WalkOptions,
+    options: &'a FormatOptions,
+) -> impl ParallelIterator<Item = (PathBuf, String)> + 'a {
+    let excluded = options.excluded_tags(walk);
+    iterate_markdown_files(vault_path, walk)
+        .filter_map(move |file| format_file(file, options, &excluded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    use crate::util::vault_with;
+
+    macro_rules! test_format {
+        ($($name:ident $options:expr; $input:expr => $expected:expr)*) => {
+            $(
+                #[test]
+                fn $name() {
+                    let input = indoc!($input);
+                    let expected = indoc!($expected);
+                    let options = $options;
+                    let actual = format_document(MdastDocument::parse(input), &options)
+                        .render_with(options.render_options(input));
+                    assert_eq!(expected, actual);
+                }
+            )*
+        }
+    }
+
+    test_format! {
+        merge_adjacent_unordered_lists FormatOptions { merge_adjacent_lists: true, ..Default::default() }; r#"
+        - item 1
+        - item 2
+
+        * item 3
+        * item 4
+        "# => r#"
+        - item 1
+        - item 2
+        - item 3
+        - item 4
+        "#
+
+        do_not_merge_adjacent_lists_without_option FormatOptions::default(); r#"
+        - item 1
+
+        * item 2
+        "# => r#"
+        - item 1
+
+        - item 2
+        "#
+
+        format_frontmatter_dates FormatOptions { format_frontmatter_dates: true, ..Default::default() }; r#"
+        ---
+        created: 2024/1/5
+        title: Jan 5 2024
+        ---
+
+        body 2024/1/5
+        "# => r#"
+        ---
+        created: 2024-01-05
+        title: Jan 5 2024
+        ---
+
+        body 2024/1/5
+        "#
+
+        ordered_delimiter_dot FormatOptions::default(); r#"
+        1) First
+        2) Second
+        "# => r#"
+        1. First
+        2. Second
+        "#
+
+        canonicalize_frontmatter_scalars FormatOptions { canonicalize_frontmatter_scalars: true, ..Default::default() }; r#"
+        ---
+        published: yes
+        quoted: "yes"
+        ---
+        yes
+        "# => r#"
+        ---
+        published: true
+        quoted: "yes"
+        ---
+
+        yes
+        "#
+
+        sort_frontmatter_tags FormatOptions { sort_frontmatter_tags: true, ..Default::default() }; r#"
+        ---
+        tags: [work/b, home, work, home]
+        ---
+        text
+        "# => r#"
+        ---
+        tags: [home, work, work/b]
+        ---
+
+        text
+        "#
+
+        preserve_indented_code FormatOptions { preserve_indented_code: true, ..Default::default() }; r#"
+        a--b
+
+            a--b
+        "# => r#"
+        a—b
+
+            a--b
+        "#
+
+        ordered_delimiter_paren FormatOptions { ordered_delimiter: OrderedDelimiter::Paren, ..Default::default() }; r#"
+        1. First
+        2. Second
+            1. Nested
+        "# => r#"
+        1) First
+        2) Second
+            1) Nested
+        "#
+
+        ordered_delimiter_preserve FormatOptions { ordered_delimiter: OrderedDelimiter::Preserve, ..Default::default() }; r#"
+        1) First
+        2) Second
+            1. Nested
+            2. Nested
+        3) Third
+
+        - unordered
+        "# => r#"
+        1) First
+        2) Second
+            1. Nested
+            2. Nested
+        3) Third
+
+        - unordered
+        "#
+
+        em_dash_between_ascii_words FormatOptions::default(); r#"
+        foo--bar
+        "# => r#"
+        foo—bar
+        "#
+
+        em_dash_between_accented_words FormatOptions::default(); r#"
+        café--bar
+        "# => r#"
+        café—bar
+        "#
+
+        em_dash_between_cjk_words FormatOptions::default(); r#"
+        漢字--字
+        "# => r#"
+        漢字—字
+        "#
+
+        no_em_dash_for_spaced_dashes FormatOptions::default(); r#"
+        foo -- bar
+        "# => r#"
+        foo -- bar
+        "#
+
+        do_not_merge_ordered_and_unordered_lists FormatOptions { merge_adjacent_lists: true, ..Default::default() }; r#"
+        1. item 1
+        2. item 2
+
+        - item 3
+        "# => r#"
+        1. item 1
+        2. item 2
+
+        - item 3
+        "#
+
+        frontmatter_only_file_is_unchanged FormatOptions { format_frontmatter_dates: true, ..Default::default() }; r#"
+        ---
+        created: 2023-08-01
+        ---
+        "# => r#"
+        ---
+        created: 2023-08-01
+        ---
+        "#
+
+        block_ids_are_not_changed FormatOptions::default(); r#"
+        some--text ^some--id
+
+        - [ ] a--b ^task--id
+        "# => r#"
+        some—text ^some--id
+
+        - [ ] a—b ^task--id
+        "#
+
+        autolinks_are_not_changed FormatOptions::default(); r#"
+        <https://a--b.com> and https://c--d.com but [e--f](https://g--h.com)
+        "# => r#"
+        <https://a--b.com> and <https://c--d.com> but [e—f](https://g--h.com)
+        "#
+
+        highlights_survive_text_replacement FormatOptions::default(); r#"
+        ==foo== **==bold highlight==** ==a--b==
+        "# => r#"
+        ==foo== **==bold highlight==** ==a—b==
+        "#
+
+        highlight_delimiters_are_not_em_dashed FormatOptions::default(); r#"
+        foo--==bar== ==baz==--qux ==a--b==c--d
+        "# => r#"
+        foo--==bar== ==baz==--qux ==a—b==c—d
+        "#
+    }
+
+    #[test]
+    fn whitespace_only_file_is_not_emptied_without_allow_empty() {
+        let vault = tempfile::tempdir().unwrap();
+        std::fs::write(vault.path().join("blank.md"), "  \n\n\n").unwrap();
+        let vault_path = vault.path().to_path_buf();
+
+        let changes: Vec<_> = format_files(
+            &vault_path,
+            &WalkOptions::default(),
+            &FormatOptions::default(),
+        )
+        .collect();
+        assert_eq!(changes, vec![]);
+
+        let changes: Vec<_> = format_files(
+            &vault_path,
+            &WalkOptions::default(),
+            &FormatOptions {
+                allow_empty: true,
+                ..Default::default()
+            },
+        )
+        .collect();
+        assert_eq!(changes.len(), 1);
+        assert!(changes[0].1.trim().is_empty());
+    }
+
+    #[test]
+    fn changes_are_classified_by_whether_only_whitespace_changed() {
+        for (old, new) in [
+            ("a\n", "a"),
+            ("a", "a\n"),
+            ("a\n\n\n", "a\n"),
+            ("# a  \nb\t\n", "# a\nb\n"),
+            ("", "\n"),
+        ] {
+            assert_eq!(
+                classify_change(old, new),
+                ChangeKind::WhitespaceOnly,
+                "{old:?} -> {new:?}"
+            );
+        }
+        for (old, new) in [
+            ("a--b\n", "a—b\n"),
+            ("a\nb\n", "a\n\nb\n"),
+            ("- a\n- b\n", "- a\n    - b\n"),
+            ("\na\n", "a\n"),
+            ("a  \nb\n", "a\nb\n"),
+            ("a   \nb\n", "a  \nb\n"),
+            ("a\nb\n", "a  \nb\n"),
+            ("```\ncode \n```\n", "```\ncode\n```\n"),
+            ("    code\t\n    more\n", "    code\n    more\n"),
+        ] {
+            assert_eq!(
+                classify_change(old, new),
+                ChangeKind::Substantive,
+                "{old:?} -> {new:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn whitespace_only_changes_are_skipped_when_asked() {
+        let file = |content: &str| markdown_file::File {
+            path: PathBuf::from("note.md"),
+            content: content.to_string(),
+        };
+        let options = FormatOptions {
+            skip_whitespace_only: true,
+            ..Default::default()
+        };
+        assert!(format_file(file("# a\n\nb"), &FormatOptions::default(), &[]).is_some());
+        assert_eq!(format_file(file("# a\n\nb"), &options, &[]), None);
+        assert_eq!(
+            format_file(file("# a\n\nb--c"), &options, &[]),
+            Some((PathBuf::from("note.md"), "# a\n\nb—c\n".to_string()))
+        );
+    }
+
+    #[test]
+    fn frontmatter_date_warnings_need_the_pass() {
+        let content = "---\ncreated: someday\n---\n\nbody\n";
+        let options = |format_frontmatter_dates| FormatOptions {
+            format_frontmatter_dates,
+            ..Default::default()
+        };
+        assert_eq!(
+            frontmatter_date_warnings(content, &options(true)),
+            vec!["leaving unrecognized date `someday` in frontmatter field `created` unchanged"]
+        );
+        assert!(frontmatter_date_warnings(content, &options(false)).is_empty());
+        assert!(frontmatter_date_warnings(
+            content,
+            &FormatOptions {
+                body_only: true,
+                ..options(true)
+            }
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn files_growing_past_max_growth_are_skipped() {
+        let content = "| a | b |\n| - | - |\n| a long cell that widens the table | c |\n";
+        let file = || markdown_file::File {
+            path: PathBuf::from("table.md"),
+            content: content.to_string(),
+        };
+        let (_, render) = format_file(file(), &FormatOptions::default(), &[]).unwrap();
+        assert!(growth_percent(content, &render) > 50.0);
+
+        let options = |max_growth| FormatOptions {
+            max_growth: Some(max_growth),
+            ..Default::default()
+        };
+        assert_eq!(format_file(file(), &options(50.0), &[]), None);
+        assert!(format_file(file(), &options(200.0), &[]).is_some());
+    }
+
+    #[test]
+    fn growth_warning_is_opt_in() {
+        #[derive(clap::Parser)]
+        struct Cli {
+            #[command(flatten)]
+            options: FormatOptions,
         }
-    })
+        let parse = |args: &[&str]| {
+            <Cli as clap::Parser>::parse_from([&["format"], args].concat())
+                .options
+                .growth_warning
+        };
+
+        assert_eq!(parse(&[]), FormatOptions::default().growth_warning);
+        assert_eq!(parse(&[]), None);
+        assert_eq!(parse(&["--growth-warning", "50"]), Some(50.0));
+    }
+
+    #[test]
+    fn frontmatter_only_leaves_body_untouched() {
+        let body = "\n\n#  messy   heading\n\n\n- [X] a--b\n* c\n|a|b|\n|-|-|\n|1|2|";
+        let input = format!("---\ntitle: note\ncreated: 2024/1/5\naliases: [x]\n---{body}");
+        let options = FormatOptions {
+            frontmatter_only: true,
+            format_frontmatter_dates: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            format_frontmatter_only(&input, &options),
+            format!("---\naliases: [x]\ncreated: 2024-01-05\ntitle: note\n---{body}")
+        );
+    }
+
+    #[test]
+    fn body_only_keeps_frontmatter_bytes() {
+        let frontmatter =
+            "---\n# hand tuned\ncreated:   Jan 5 2024   # keep this\ntags: [ a,b ]  \n---";
+        let input = format!("{frontmatter}\n\n\n#  messy heading\n\nfoo--bar\n\n\n");
+        let options = FormatOptions {
+            body_only: true,
+            format_frontmatter_dates: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            format_body_only(&input, &options),
+            format!("{frontmatter}\n\n# messy heading\n\nfoo—bar\n")
+        );
+        assert_eq!(
+            format_body_only(&format!("{frontmatter}\n\n"), &options),
+            format!("{frontmatter}\n")
+        );
+    }
+
+    #[test]
+    fn frontmatter_only_without_frontmatter_is_unchanged() {
+        let input = "#  messy   heading\n\n\n- [X] a--b\n";
+        let options = FormatOptions {
+            frontmatter_only: true,
+            ..Default::default()
+        };
+
+        assert_eq!(format_frontmatter_only(input, &options), input);
+    }
+
+    #[test]
+    fn excluded_tags_are_not_formatted() {
+        let vault = vault_with(&[
+            ("noformat.md", "#noformat\n\nfoo--bar\n\n\n"),
+            ("draft.md", "#draft\n\nfoo--bar\n\n\n"),
+            ("untagged.md", "foo--bar\n\n\n"),
+        ]);
+        let vault_path = vault.path().to_path_buf();
+        let formatted = |exclude_tags: &[&str]| {
+            let options = FormatOptions {
+                exclude_tags: exclude_tags.iter().map(|t| t.to_string()).collect(),
+                ..Default::default()
+            };
+            let mut paths = format_files(&vault_path, &WalkOptions::default(), &options)
+                .map(|(path, _)| path.strip_prefix(&vault_path).unwrap().to_path_buf())
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        };
+
+        assert_eq!(
+            formatted(&["noformat"]),
+            vec![PathBuf::from("draft.md"), PathBuf::from("untagged.md")]
+        );
+        assert_eq!(
+            formatted(&["#noformat", "draft"]),
+            vec![PathBuf::from("untagged.md")]
+        );
+    }
+
+    #[test]
+    fn explain_reports_the_passes_that_change_a_file() {
+        let options = FormatOptions {
+            merge_adjacent_lists: true,
+            format_frontmatter_dates: true,
+            canonicalize_frontmatter_scalars: true,
+            ..Default::default()
+        };
+        let explain = |content: &str| {
+            let file = markdown_file::File {
+                path: PathBuf::from("note.md"),
+                content: content.to_string(),
+            };
+            explain_file(file, &options, &[]).map(|(_, passes)| passes)
+        };
+
+        assert_eq!(
+            explain(indoc! {r#"
+                ---
+                done: yes
+                ---
+
+                - a--b
+                - c
+            "#}),
+            Some(vec![PassName::EmDash, PassName::FrontmatterScalars])
+        );
+        assert_eq!(explain("a--b\n"), Some(vec![PassName::EmDash]));
+        assert_eq!(explain("* a\n"), Some(vec![]));
+        assert_eq!(explain("- a\n"), None);
+    }
+
+    #[test]
+    fn structural_only_keeps_text_but_realigns_tables() {
+        let options = FormatOptions {
+            structural_only: true,
+            merge_adjacent_lists: true,
+            canonicalize_frontmatter_scalars: true,
+            ..Default::default()
+        };
+        let file = markdown_file::File {
+            path: PathBuf::from("note.md"),
+            content: indoc! {r#"
+                ---
+                done: yes
+                ---
+                a--b
+
+                |a|long header|
+                |-|-|
+                |a long cell|b|
+
+                - x
+
+                * y"#}
+            .to_string(),
+        };
+
+        assert_eq!(
+            format_file(file, &options, &[]).unwrap().1,
+            indoc! {r#"
+                ---
+                done: yes
+                ---
+
+                a--b
+
+                | a           | long header |
+                | ----------- | ----------- |
+                | a long cell | b           |
+
+                - x
+                - y
+            "#}
+        );
+    }
+
+    #[test]
+    fn protected_code_is_untouched_by_every_pass() {
+        let mermaid = "~~~ mermaid  {init: {'theme': 'dark'}}\ngraph TD\n  A--text-->B\t\n~~~\n";
+        let input = format!("a--b\n{mermaid}\n~~~python\nx--y\t\n~~~");
+        let options = FormatOptions {
+            passes: Some(vec![PassName::EmDash, PassName::MergeAdjacentLists]),
+            lints: vec![Lint {
+                name: "arrows".to_string(),
+                pattern: Regex::new(r"-->|\t").unwrap(),
+                replacement: "→".to_string(),
+            }],
+            protected_langs: vec!["mermaid".to_string()],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            render(&input, &options),
+            format!("a—b\n\n{mermaid}\n```python\nx--y→\n```\n")
+        );
+    }
+
+    #[test]
+    fn lints_skip_protected_ranges() {
+        let lint = Lint {
+            name: "vowels".to_string(),
+            pattern: Regex::new("[ae]").unwrap(),
+            replacement: "$0$0".to_string(),
+        };
+        let (replaced, moved) = lint.replace_outside("a [ae] e [a] e", &[3..5, 10..11]);
+
+        assert_eq!(replaced, "aa [ae] ee [a] ee");
+        assert_eq!(moved, vec![4..6, 12..13]);
+        assert_eq!(&replaced[4..6], "ae");
+    }
+
+    #[test]
+    fn disabled_pass_is_skipped() {
+        let options = FormatOptions {
+            disabled_passes: vec![PassName::EmDash],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            format_document(MdastDocument::parse("foo--bar\n"), &options).render(),
+            "foo--bar\n"
+        );
+    }
+
+    #[test]
+    fn passes_replace_the_default_passes() {
+        let options = FormatOptions {
+            merge_adjacent_lists: true,
+            passes: Some(vec![PassName::MergeAdjacentLists]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            format_document(MdastDocument::parse("- a--b\n\n* c\n"), &options).render(),
+            "- a--b\n- c\n"
+        );
+    }
+
+    /// Appends to the text at the end of a document's last paragraph
+    struct Append(&'static str);
+
+    impl Pass for Append {
+        fn apply(&self, document: &mut MdastDocument) {
+            if let Some(Node::Paragraph(paragraph)) = document.root.children.last_mut() {
+                if let Some(Node::Text(text)) = paragraph.children.last_mut() {
+                    text.value.push_str(self.0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pass_order_changes_interacting_passes() {
+        let run = |passes: Vec<Box<dyn Pass>>| {
+            run_passes(MdastDocument::parse("foo-\n"), &passes).render()
+        };
+
+        assert_eq!(
+            run(vec![Box::new(Append("-bar")), Box::new(EmDash)]),
+            "foo—bar\n"
+        );
+        assert_eq!(
+            run(vec![Box::new(EmDash), Box::new(Append("-bar"))]),
+            "foo--bar\n"
+        );
+    }
 }
@@ -0,0 +1,587 @@
+use chrono::NaiveDate;
+use lazy_static::lazy_static;
+use regex::Regex;
+use saphyr_parser::{Event, Parser, ScalarStyle};
+
+lazy_static! {
+    static ref TOP_LEVEL_FIELD: Regex =
+        Regex::new(r"^(?P<key>[A-Za-z_][\w-]*):[ \t]*(?P<value>.*?)[ \t]*$").unwrap();
+    /// A field or list item at any depth, like `  - key: value`
+    static ref SCALAR_LINE: Regex = Regex::new(
+        r"^(?P<prefix>(?P<indent>[ \t]*)(?:- )?(?:[A-Za-z_][\w-]*:[ \t]*)?)(?P<value>.*?)[ \t]*$"
+    )
+    .unwrap();
+}
+
+/// Fields that are always treated as dates by `normalize_dates`
+const DATE_FIELDS: [&str; 3] = ["created", "modified", "date"];
+
+const DATE_FORMATS: [&str; 8] = [
+    "%Y-%m-%d",
+    "%Y/%m/%d",
+    "%b %d %Y",
+    "%B %d %Y",
+    "%b %d, %Y",
+    "%B %d, %Y",
+    "%d %b %Y",
+    "%d %B %Y",
+];
+
+fn parse_date(value: &str) -> Option<NaiveDate> {
+    DATE_FORMATS
+        .iter()
+        .find_map(|format| NaiveDate::parse_from_str(value, format).ok())
+}
+
+/// Split a quoted yaml scalar into its quote and content
+fn unquote(value: &str) -> (&str, &str) {
+    for quote in ["\"", "'"] {
+        if value.len() >= 2 && value.starts_with(quote) && value.ends_with(quote) {
+            return (quote, &value[1..value.len() - 1]);
+        }
+    }
+    ("", value)
+}
+
+/// Where a yaml parser is in the collections around the next node
+enum Frame {
+    Sequence,
+    /// A mapping whose next node is a key
+    Key,
+    /// A mapping whose next node is the value of this key, if it was a scalar
+    Value(Option<String>),
+}
+
+/// The byte offset of a yaml parser's position, which counts chars
+fn byte_offset(yaml: &str, chars: usize) -> usize {
+    yaml.char_indices()
+        .nth(chars)
+        .map_or(yaml.len(), |(offset, _)| offset)
+}
+
+/// Rewrite date fields in frontmatter as `YYYY-MM-DD`, at any depth and in flow mappings too
+///
+/// Only the changed values are rewritten, keeping their quotes, so the rest of the frontmatter
+/// stays as written. Returns the new frontmatter and a warning for each date field that couldn't
+/// be parsed, which is left untouched, or for frontmatter that isn't valid yaml.
+pub fn normalize_dates(yaml: &str, extra_fields: &[String]) -> (String, Vec<String>) {
+    let is_date_field =
+        |key: &str| DATE_FIELDS.contains(&key) || extra_fields.iter().any(|f| f == key);
+    let mut warnings = vec![];
+    let mut edits = vec![];
+    let mut stack = vec![];
+    for event in Parser::new_from_str(yaml) {
+        let (event, span) = match event {
+            Ok(event) => event,
+            Err(e) => {
+                let warning =
+                    format!("leaving dates in frontmatter unchanged, it isn't valid yaml: {e}");
+                return (yaml.to_string(), vec![warning]);
+            }
+        };
+        // the key this node is the value of, if it's the value of a field
+        let mut field = None;
+        if matches!(
+            event,
+            Event::Scalar(..)
+                | Event::Alias(_)
+                | Event::MappingStart(..)
+                | Event::SequenceStart(..)
+        ) {
+            match stack.last_mut() {
+                Some(frame @ Frame::Key) => {
+                    *frame = Frame::Value(match &event {
+                        Event::Scalar(key, ..) => Some(key.to_string()),
+                        _ => None,
+                    });
+                }
+                Some(frame @ Frame::Value(_)) => {
+                    if let Frame::Value(key) = std::mem::replace(frame, Frame::Key) {
+                        field = key;
+                    }
+                }
+                _ => {}
+            }
+        }
+        match event {
+            Event::MappingStart(..) => stack.push(Frame::Key),
+            Event::SequenceStart(..) => stack.push(Frame::Sequence),
+            Event::MappingEnd | Event::SequenceEnd => {
+                stack.pop();
+            }
+            Event::Scalar(value, style, ..) => {
+                let Some(key) = field.filter(|key| is_date_field(key)) else {
+                    continue;
+                };
+                // block scalars are text, and an empty span is a field without a value
+                let quote = match style {
+                    ScalarStyle::Plain => "",
+                    ScalarStyle::SingleQuoted => "'",
+                    ScalarStyle::DoubleQuoted => "\"",
+                    _ => continue,
+                };
+                if span.is_empty() || value.is_empty() {
+                    continue;
+                }
+                match parse_date(&value) {
+                    Some(date) => {
+                        let range = byte_offset(yaml, span.start.index())
+                            ..byte_offset(yaml, span.end.index());
+                        let date = format!("{quote}{}{quote}", date.format("%Y-%m-%d"));
+                        if yaml[range.clone()] != date {
+                            edits.push((range, date));
+                        }
+                    }
+                    None => warnings.push(format!(
+                        "leaving unrecognized date `{value}` in frontmatter field `{key}` unchanged"
+                    )),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut yaml = yaml.to_string();
+    for (range, date) in edits.into_iter().rev() {
+        yaml.replace_range(range, &date);
+    }
+    (yaml, warnings)
+}
+
+/// The canonical form of a plain yaml boolean or null, leaving quoted strings alone
+fn canonical_scalar(value: &str) -> Option<&'static str> {
+    match value {
+        "yes" | "Yes" | "YES" | "true" | "True" | "TRUE" | "on" | "On" | "ON" => Some("true"),
+        "no" | "No" | "NO" | "false" | "False" | "FALSE" | "off" | "Off" | "OFF" => Some("false"),
+        "~" | "null" | "Null" | "NULL" => Some("null"),
+        _ => None,
+    }
+}
+
+/// Rewrite booleans like `yes` and `off` as `true` and `false`, and `~` as `null`, in fields and
+/// list items at any depth
+///
+/// Quoted values are strings and the lines of `|` and `>` block scalars are text, so both are left
+/// as written.
+pub fn canonicalize_scalars(yaml: &str) -> String {
+    let mut block_scalar_indent = None;
+    yaml.split('\n')
+        .map(|line| {
+            let Some(scalar) = SCALAR_LINE.captures(line) else {
+                return line.to_string();
+            };
+            let indent = scalar["indent"].len();
+            match block_scalar_indent {
+                Some(block) if indent > block || line.trim().is_empty() => return line.to_string(),
+                _ => block_scalar_indent = None,
+            }
+
+            let value = &scalar["value"];
+            if value.starts_with(['|', '>']) {
+                block_scalar_indent = Some(indent);
+            }
+            match canonical_scalar(value) {
+                Some(canonical) if scalar["prefix"].trim() != "" => {
+                    format!("{}{canonical}", &scalar["prefix"])
+                }
+                _ => line.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The tags listed in the `tags` or `tag` field of frontmatter, either inline like `[a, b]` and
+/// `a, b` or as a block list
+pub fn tags(yaml: &str) -> Vec<String> {
+    let mut tags = vec![];
+    let mut in_tags = false;
+    for line in yaml.split('\n') {
+        if let Some(field) = TOP_LEVEL_FIELD.captures(line) {
+            in_tags = matches!(&field["key"], "tags" | "tag");
+            if in_tags {
+                let value = field["value"].trim_start_matches('[').trim_end_matches(']');
+                tags.extend(value.split([',', ' ']).map(str::to_string));
+            }
+        } else if in_tags {
+            if let Some(item) = line.trim_start().strip_prefix("- ") {
+                tags.push(item.to_string());
+            }
+        }
+    }
+    tags.iter()
+        .map(|tag| unquote(tag.trim()).1.trim_start_matches('#').to_string())
+        .filter(|tag| !tag.is_empty())
+        .collect()
+}
+
+/// The key a tag sorts and dedupes by, its folders lowercased without quotes or `#`, so nested tags
+/// sort right after their parent
+fn tag_key(tag: &str) -> Vec<String> {
+    unquote(tag.trim())
+        .1
+        .trim_start_matches('#')
+        .to_lowercase()
+        .split('/')
+        .map(str::to_string)
+        .collect()
+}
+
+/// Sort tags by `tag_key`, keeping the first way each was written
+fn sorted_tags<'a>(tags: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+    let mut tags = tags
+        .filter(|tag| !tag_key(tag).concat().is_empty())
+        .collect::<Vec<_>>();
+    tags.sort_by_cached_key(|tag| tag_key(tag));
+    tags.dedup_by_key(|tag| tag_key(tag));
+    tags
+}
+
+/// Whether tags can be split on the commas and spaces of this value, without a comment or quoted
+/// commas that splitting would change the meaning of
+fn is_plain_value(value: &str) -> bool {
+    let mut quote = None;
+    let mut previous = ' ';
+    for c in value.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) if c == ',' => return false,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '#' && previous.is_whitespace() => return false,
+            None => {}
+        }
+        previous = c;
+    }
+    true
+}
+
+/// Sort and dedupe the `tags` or `tag` field of frontmatter, keeping it inline like `[a, b]` and
+/// `a, b`, or a block list, the way it was written
+///
+/// A value with a comment or quoted commas, or a block list with anything but items in it, is
+/// left alone.
+pub fn sort_tags(yaml: &str) -> String {
+    let lines = yaml.split('\n').collect::<Vec<_>>();
+    let mut sorted = Vec::with_capacity(lines.len());
+    let mut index = 0;
+    while index < lines.len() {
+        let line = lines[index];
+        index += 1;
+        let Some(field) = TOP_LEVEL_FIELD
+            .captures(line)
+            .filter(|field| matches!(&field["key"], "tags" | "tag"))
+        else {
+            sorted.push(line.to_string());
+            continue;
+        };
+        let (key, value) = (&field["key"], &field["value"]);
+
+        if !is_plain_value(value) {
+            sorted.push(line.to_string());
+        } else if let Some(inner) = value.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+            let tags = sorted_tags(inner.split(',').map(str::trim));
+            sorted.push(format!("{key}: [{}]", tags.join(", ")));
+        } else if !value.is_empty() {
+            let separator = if value.contains(',') { ", " } else { " " };
+            let tags = sorted_tags(value.split([',', ' ']).map(str::trim));
+            sorted.push(format!("{key}: {}", tags.join(separator)));
+        } else {
+            sorted.push(line.to_string());
+            let end = lines[index..]
+                .iter()
+                .position(|line| TOP_LEVEL_FIELD.is_match(line))
+                .map_or(lines.len(), |end| index + end);
+            let block = &lines[index..end];
+            let items = block
+                .iter()
+                .map(|line| {
+                    let item = line.trim_start().strip_prefix("- ")?;
+                    is_plain_value(item)
+                        .then_some((&line[..line.len() - line.trim_start().len()], item))
+                })
+                .collect::<Option<Vec<_>>>();
+            match items {
+                Some(items) if !items.is_empty() => {
+                    let indent = items[0].0;
+                    sorted.extend(
+                        sorted_tags(items.iter().map(|(_, item)| *item))
+                            .into_iter()
+                            .map(|tag| format!("{indent}- {tag}")),
+                    );
+                }
+                _ => sorted.extend(block.iter().map(|line| line.to_string())),
+            }
+            index = end;
+        }
+    }
+    sorted.join("\n")
+}
+
+/// The value of a top level field in frontmatter, without quotes
+pub fn field(yaml: &str, key: &str) -> Option<String> {
+    yaml.split('\n')
+        .filter_map(|line| TOP_LEVEL_FIELD.captures(line))
+        .find(|field| &field["key"] == key)
+        .map(|field| unquote(&field["value"]).1.to_string())
+}
+
+/// Sort the top level fields of frontmatter by key, keeping nested lines with their field
+///
+/// Lines before the first field, like comments, stay at the top.
+pub fn sort_keys(yaml: &str) -> String {
+    let mut header = vec![];
+    let mut fields: Vec<(&str, Vec<&str>)> = vec![];
+    for line in yaml.split('\n') {
+        match TOP_LEVEL_FIELD.captures(line) {
+            Some(field) => fields.push((field.name("key").unwrap().as_str(), vec![line])),
+            None => match fields.last_mut() {
+                Some((_, lines)) => lines.push(line),
+                None => header.push(line),
+            },
+        }
+    }
+    fields.sort_by_key(|(key, _)| *key);
+
+    header
+        .into_iter()
+        .chain(fields.into_iter().flat_map(|(_, lines)| lines))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    macro_rules! test_normalize_dates {
+        ($($name:ident $input:expr => $expected:expr)*) => {
+            $(
+                #[test]
+                fn $name() {
+                    let (actual, _) = normalize_dates(indoc!($input), &["due".to_string()]);
+                    assert_eq!(indoc!($expected), actual);
+                }
+            )*
+        }
+    }
+
+    #[test]
+    fn frontmatter_tags() {
+        assert_eq!(
+            tags("title: a\ntags: [one, \"two\", '#three']"),
+            ["one", "two", "three"]
+        );
+        assert_eq!(tags("tags: one two, three"), ["one", "two", "three"]);
+        assert_eq!(
+            tags("tag:\n  - one\n  - project/two\nother:\n  - not a tag"),
+            ["one", "project/two"]
+        );
+        assert!(tags("title: tags\ntags:").is_empty());
+    }
+
+    test_normalize_dates! {
+        normalize_unpadded_date r#"
+        created: 2024-1-5
+        "# => r#"
+        created: 2024-01-05
+        "#
+
+        normalize_slashed_date r#"
+        modified: 2024/01/05
+        "# => r#"
+        modified: 2024-01-05
+        "#
+
+        normalize_month_name_date r#"
+        date: Jan 5 2024
+        "# => r#"
+        date: 2024-01-05
+        "#
+
+        normalize_quoted_date r#"
+        date: "Jan 5, 2024"
+        due: '2024/1/5'
+        "# => r#"
+        date: "2024-01-05"
+        due: '2024-01-05'
+        "#
+
+        leave_other_fields_alone r#"
+        title: 2024/01/05
+        tags:
+          - 2024/01/05
+        notes: |
+          created: 2024/01/05
+        "# => r#"
+        title: 2024/01/05
+        tags:
+          - 2024/01/05
+        notes: |
+          created: 2024/01/05
+        "#
+
+        normalize_nested_and_flow_dates r#"
+        project:
+          created:   2024/1/5   # kickoff
+          tasks:
+            - date: Jan 5 2024
+        review: {due: "2024/2/1", title: date}
+        "# => r#"
+        project:
+          created:   2024-01-05   # kickoff
+          tasks:
+            - date: 2024-01-05
+        review: {due: "2024-02-01", title: date}
+        "#
+
+        normalize_multi_line_date r#"
+        created: Jan 5
+          2024
+        title: note
+        "# => r#"
+        created: 2024-01-05
+        title: note
+        "#
+    }
+
+    #[test]
+    fn unparseable_date_is_left_alone_with_warning() {
+        let (actual, warnings) = normalize_dates("created: someday\ntitle: x", &[]);
+        assert_eq!(actual, "created: someday\ntitle: x");
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("someday"));
+    }
+
+    #[test]
+    fn invalid_yaml_is_left_alone_with_warning() {
+        let yaml = "created: 2024/1/5\ntags: [a\ntitle: x";
+        let (actual, warnings) = normalize_dates(yaml, &[]);
+        assert_eq!(actual, yaml);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("isn't valid yaml"));
+    }
+
+    #[test]
+    fn booleans_and_nulls_are_canonicalized() {
+        let yaml = indoc! {r#"
+        published: yes
+        draft: Off
+        answer: "yes"
+        other: 'no'
+        parent: ~
+        note: yes please
+        flags:
+          - on
+          - "off"
+          - nested: NO
+        description: |
+          yes
+          no
+        after: null"#};
+
+        assert_eq!(
+            canonicalize_scalars(yaml),
+            indoc! {r#"
+            published: true
+            draft: false
+            answer: "yes"
+            other: 'no'
+            parent: null
+            note: yes please
+            flags:
+              - true
+              - "off"
+              - nested: false
+            description: |
+              yes
+              no
+            after: null"#}
+        );
+    }
+
+    #[test]
+    fn tags_are_sorted_and_deduped() {
+        assert_eq!(
+            sort_tags("title: b\ntags: [project/b, b, \"a\", project, '#b', A]"),
+            "title: b\ntags: [\"a\", b, project, project/b]"
+        );
+        assert_eq!(sort_tags("tags: c b, b"), "tags: b, c");
+        assert_eq!(sort_tags("tag: c b a"), "tag: a b c");
+        assert_eq!(
+            sort_tags(indoc! {r#"
+            tags:
+              - b
+              - a/b
+              - a
+              - b
+            title: note"#}),
+            indoc! {r#"
+            tags:
+              - a
+              - a/b
+              - b
+            title: note"#}
+        );
+    }
+
+    #[test]
+    fn tag_blocks_with_comments_are_left_alone() {
+        let yaml = "tags:\n  # keep in order\n  - b\n  - a";
+        assert_eq!(sort_tags(yaml), yaml);
+        assert_eq!(sort_tags("tags:\ntitle: note"), "tags:\ntitle: note");
+        let yaml = "tags:\n  - b # later\n  - a";
+        assert_eq!(sort_tags(yaml), yaml);
+    }
+
+    #[test]
+    fn tags_with_comments_are_left_alone() {
+        for yaml in [
+            "tags: [b, a] # note",
+            "tags: b, a # note",
+            "tags: # none yet",
+        ] {
+            assert_eq!(sort_tags(yaml), yaml);
+        }
+        assert_eq!(sort_tags("tags: [b, a#1]"), "tags: [a#1, b]");
+    }
+
+    #[test]
+    fn tags_with_quoted_commas_are_left_alone() {
+        for yaml in [r#"tags: [d, "b, c"]"#, "tags: d, 'b, c'"] {
+            assert_eq!(sort_tags(yaml), yaml);
+        }
+        assert_eq!(sort_tags(r#"tags: [b, "a"]"#), r#"tags: ["a", b]"#);
+    }
+
+    #[test]
+    fn sort_keys_keeps_nested_lines_with_their_field() {
+        let yaml = indoc! {r#"
+        # a comment
+        title: note
+        tags:
+          - b
+          - a
+        aliases: [x]
+        description: |
+          multi
+          line"#};
+
+        assert_eq!(
+            sort_keys(yaml),
+            indoc! {r#"
+            # a comment
+            aliases: [x]
+            description: |
+              multi
+              line
+            tags:
+              - b
+              - a
+            title: note"#}
+        );
+    }
+}
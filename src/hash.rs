@@ -0,0 +1,51 @@
+use std::path::PathBuf;
+
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    format_files::{format_document, FormatOptions},
+    markdown_file::MdastDocument,
+    util::{iterate_markdown_files, WalkOptions},
+};
+
+/// The sha256 of a note as `format` would write it, so notes that only differ in ways `format`
+/// fixes have the same hash
+pub fn normalized_hash(content: &str) -> String {
+    let render = format_document(MdastDocument::parse(content), &FormatOptions::default()).render();
+    format!("{:x}", Sha256::digest(render.as_bytes()))
+}
+
+/// Print the normalized hash of every file in the vault, in the format of `sha256sum`
+pub fn print_hashes(vault_path: &PathBuf, walk: &WalkOptions) -> Option<i32> {
+    let mut hashes = iterate_markdown_files(vault_path, walk)
+        .map(|file| {
+            let relative_path = file
+                .path
+                .strip_prefix(vault_path)
+                .unwrap_or(&file.path)
+                .to_path_buf();
+            (relative_path, normalized_hash(&file.content))
+        })
+        .collect::<Vec<_>>();
+    hashes.sort();
+
+    for (path, hash) in hashes {
+        println!("{hash}  {}", path.display());
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trivial_differences_hash_the_same() {
+        assert_eq!(
+            normalized_hash("# Note \n\nfoo--bar \n- item\n\n\n"),
+            normalized_hash("# Note\n\nfoo—bar\n\n- item\n")
+        );
+        assert_ne!(normalized_hash("foo bar\n"), normalized_hash("foo baz\n"));
+    }
+}
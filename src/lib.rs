@@ -0,0 +1,24 @@
+pub mod archive;
+pub mod ast;
+pub mod collect;
+pub mod config;
+#[cfg(feature = "dry_run")]
+pub mod diff;
+pub mod doctor;
+pub mod due;
+pub mod error;
+pub mod export;
+pub mod format_files;
+pub mod frontmatter;
+pub mod hash;
+pub mod markdown_file;
+pub mod normalize_tasks;
+#[cfg(feature = "notify")]
+pub mod notify_conflicts;
+pub mod query;
+pub mod rename;
+pub mod replace;
+pub mod tags;
+pub mod util;
+#[cfg(feature = "watch")]
+pub mod watch;
@@ -1,26 +1,39 @@
-mod archive;
-#[cfg(feature = "dry_run")]
-mod diff;
-mod format_files;
-mod markdown_file;
-#[cfg(feature = "notify")]
-mod notify_conflicts;
-mod util;
-
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-#[cfg(feature = "dry_run")]
-use crate::diff::diff;
-use crate::markdown_file::File;
-#[cfg(feature = "notify")]
-use crate::notify_conflicts::notify_conflicts;
-use archive::archive;
-use clap::{Parser, Subcommand};
-use format_files::format_files;
-use rayon::prelude::ParallelIterator;
-use std::io;
+use clap::{ArgAction, Parser, Subcommand};
+use marksage::markdown_file::{Encoding, File};
 #[cfg(feature = "notify")]
-use url::Url;
+use marksage::notify_conflicts::{notify_conflicts, NotifyOptions};
+#[cfg(feature = "watch")]
+use marksage::watch::watch;
+use marksage::{
+    archive::{archive, archive_to_file, check_archive, ArchiveOptions},
+    ast::print_ast,
+    collect::{collect, CollectOptions},
+    config::Configs,
+    doctor::print_doctor,
+    due::{move_overdue, print_due, DueOptions},
+    error,
+    export::{export, ExportOptions},
+    format_files::{explain_format, format_file, format_files, FormatOptions},
+    hash::print_hashes,
+    normalize_tasks::{normalize_tasks, NormalizeTasksOptions},
+    rename::{rename_to_title, RenamePlan},
+    replace::{replace, ReplaceOptions},
+    tags::{print_tags, TagsOptions},
+    util::{DateOptions, WalkOptions},
+};
+#[cfg(feature = "dry_run")]
+use marksage::{
+    diff::{diff, side_by_side_diff, unified_patch, ColorMode, DiffStyle},
+    util::{tagged_file_matcher, walk_vault, WalkStatus},
+};
+use rayon::prelude::*;
+#[cfg(feature = "dry_run")]
+use std::fs;
+use std::io::{self, Read};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 fn parse_path(arg: &str) -> Result<PathBuf, std::io::Error> {
     let path = PathBuf::from(arg);
@@ -34,12 +47,6 @@ fn parse_path(arg: &str) -> Result<PathBuf, std::io::Error> {
     }
 }
 
-#[cfg(feature = "notify")]
-fn parse_url(arg: &str) -> Result<Url, url::ParseError> {
-    let url = arg.to_string();
-    Url::parse(&url)
-}
-
 #[derive(Parser, Debug)]
 #[command(author, about, version)]
 struct Cli {
@@ -53,43 +60,217 @@ struct Cli {
     #[cfg(feature = "dry_run")]
     dry_run: bool,
 
+    /// Write the dry run changes to this file as a single patch, instead of printing diffs
+    #[arg(long, value_name = "FILE", requires = "dry_run")]
+    #[cfg(feature = "dry_run")]
+    patch_out: Option<PathBuf>,
+
+    /// The number of unchanged lines to show around each dry run change
+    #[arg(long, value_name = "N", default_value = "3")]
+    #[cfg(feature = "dry_run")]
+    diff_context: usize,
+
+    /// Show at most this many changed parts of each file in a dry run, summarizing the rest
+    #[arg(long, value_name = "N")]
+    #[cfg(feature = "dry_run")]
+    diff_max_hunks: Option<usize>,
+
+    /// How to show the changes of a dry run
+    #[arg(long, value_enum, default_value_t = DiffStyle::Inline)]
+    #[cfg(feature = "dry_run")]
+    diff_style: DiffStyle,
+
+    /// When to color output, `auto` colors output to a terminal unless `NO_COLOR` is set
+    #[arg(long, value_enum, default_value_t = ColorMode::Auto)]
+    #[cfg(feature = "dry_run")]
+    color: ColorMode,
+
+    /// List every markdown file the dry run saw, and whether it was changed, skipped or unreadable
+    #[arg(long, default_value = "false", requires = "dry_run")]
+    #[cfg(feature = "dry_run")]
+    verbose: bool,
+
+    /// Append a line of json to this file describing the run and what it did to each file, so a
+    /// history of runs builds up
+    #[arg(long, value_name = "FILE")]
+    report: Option<PathBuf>,
+
+    /// Don't print a line for each file changed, or with `-qq` for failures either, leaving only
+    /// the exit code and any output asked for like a dry run
+    #[arg(short, long, action = ArgAction::Count)]
+    quiet: u8,
+
+    /// Restore every file changed by this run if any write fails
+    #[arg(long, default_value = "false")]
+    atomic_run: bool,
+
+    /// Stop at the first write that fails, leaving the files after it unchanged, by writing one
+    /// file at a time in order instead of in parallel
+    #[arg(long, default_value = "false")]
+    fail_fast: bool,
+
+    /// Apply changes even when more files would change than the confirm threshold
+    #[arg(short, long, visible_alias = "force", default_value = "false")]
+    yes: bool,
+
+    /// Abort with exit code 2, changing nothing, if more files would change than this without --yes
+    #[arg(long, visible_alias = "max-changes", default_value = "100")]
+    confirm_threshold: usize,
+
+    /// Print how many notes and bytes the run read, and how many of each it got through per second
+    #[arg(long, default_value = "false", hide = true)]
+    bench_report: bool,
+
+    #[command(flatten)]
+    walk: WalkOptions,
+
+    #[command(flatten)]
+    dates: DateOptions,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+impl Cli {
+    #[cfg(feature = "dry_run")]
+    fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    #[cfg(not(feature = "dry_run"))]
+    fn is_dry_run(&self) -> bool {
+        false
+    }
+
+    /// Replace only shows what it would change unless asked to apply it
+    #[cfg(feature = "dry_run")]
+    fn with_command_defaults(mut self) -> Self {
+        if let Commands::Replace { options } = &self.command {
+            self.dry_run |= !options.apply;
+        }
+        self
+    }
+
+    #[cfg(not(feature = "dry_run"))]
+    fn with_command_defaults(self) -> Self {
+        self
+    }
+
+    /// Read the configs of the vault, filling in the options of each file that weren't given on
+    /// the command line, and pass the global date options on to archive
+    fn with_config(mut self) -> error::Result<Self> {
+        match &mut self.command {
+            Commands::Format { options } => options.configs = Configs::load(&self.vault_path)?,
+            Commands::Archive { options } => {
+                options.configs = Configs::load(&self.vault_path)?;
+                options.dates = self.dates.clone();
+            }
+            _ => {}
+        }
+        Ok(self)
+    }
+}
+
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Archive todos that have been entirely completed
-    Archive {},
+    Archive {
+        #[command(flatten)]
+        options: ArchiveOptions,
+    },
     /// Apply basic formatting to all markdown files in the vault
-    Format {},
+    Format {
+        #[command(flatten)]
+        options: FormatOptions,
+    },
+    /// List open todos with a due date, or move the overdue ones into their own section
+    Due {
+        #[command(flatten)]
+        options: DueOptions,
+    },
+    /// Render every markdown file in the vault into another folder, for use outside obsidian
+    Export {
+        #[command(flatten)]
+        options: ExportOptions,
+    },
+    /// Rewrite every list of todos with the same bullet and checkbox style
+    NormalizeTasks {
+        #[command(flatten)]
+        options: NormalizeTasksOptions,
+    },
+    /// Gather every open todo from tagged files into a single note, grouped by file
+    Collect {
+        #[command(flatten)]
+        options: CollectOptions,
+    },
+    /// Find and replace in the prose of every file, leaving code, urls and frontmatter alone
+    Replace {
+        #[command(flatten)]
+        options: ReplaceOptions,
+    },
+    /// List every tag in the vault with how often it's used, most used first
+    Tags {
+        #[command(flatten)]
+        options: TagsOptions,
+    },
+    /// Rename every note to a slug of its frontmatter title or first heading, updating the links
+    /// to it, and skipping notes whose new name is taken
+    RenameToTitle,
+    /// Print a hash of every file as it would be formatted, so files that only differ in ways
+    /// format would fix hash the same
+    Hash,
+    /// List the files whose meaning formatting would change, not just their whitespace, and how,
+    /// so they can be fixed or excluded before formatting for real
+    Doctor,
+    /// Print the parsed markdown ast of a single file, for debugging
+    #[command(hide = true)]
+    Ast {
+        /// The file to parse, relative to the vault path
+        #[arg(short, long)]
+        file: PathBuf,
+    },
     /// Use ntfy.sh to send a push notification about sync conflicts
     #[cfg(feature = "notify")]
     NotifyConflicts {
-        /// The ntfy.sh url to send the notification to
-        #[arg(short, long)]
-        #[clap(value_parser = parse_url, default_value = "https://ntfy.sh")]
-        ntfy_url: Url,
-        /// The topic to send the notification to
-        #[arg(short, long)]
-        topic: String,
+        #[command(flatten)]
+        options: NotifyOptions,
     },
 }
 
+/// Show the changes from `old` to `new` a dry run would make, in the chosen diff style
+#[cfg(feature = "dry_run")]
+fn dry_run_diff(mut stdout_buffer: Vec<String>, arg: &Cli, old: &str, new: &str) -> Vec<String> {
+    stdout_buffer.push("  dry run, would make the following changes:\n".to_string());
+    match arg.diff_style {
+        DiffStyle::Inline => diff(
+            stdout_buffer,
+            old,
+            new,
+            arg.diff_context,
+            arg.diff_max_hunks,
+        ),
+        DiffStyle::SideBySide => side_by_side_diff(
+            stdout_buffer,
+            old,
+            new,
+            arg.diff_context,
+            arg.diff_max_hunks,
+            console::Term::stdout().size().1 as usize,
+        ),
+    }
+}
+
 #[cfg(feature = "dry_run")]
 fn write_file(
     mut stdout_buffer: Vec<String>,
     arg: &Cli,
     path: PathBuf,
     content: String,
-) -> (Vec<String>, io::Result<()>) {
-    use std::fs;
-
+) -> (Vec<String>, error::Result<()>) {
     if arg.dry_run {
         (
-            if let Ok(old_content) = fs::read_to_string(path) {
-                stdout_buffer.push("  dry run, would make the following changes:\n".to_string());
-                diff(stdout_buffer, &old_content, &content)
+            if let Ok(old_content) = arg.walk.encoding.read(&path) {
+                dry_run_diff(stdout_buffer, arg, &old_content, &content)
             } else {
                 stdout_buffer.push(format!(
                     "  dry run, couldn't read old file! new file would be:\n{content}\n"
@@ -99,18 +280,297 @@ fn write_file(
             Ok(()),
         )
     } else {
-        (stdout_buffer, File::atomic_overwrite(&path, content))
+        (
+            stdout_buffer,
+            File::atomic_overwrite(&path, content, arg.walk.encoding),
+        )
     }
 }
 
+/// Why the command leaves a file it read alone without looking at it: being outside `--query`,
+/// missing the tag the command works on, or having a tag `format` excludes
+#[cfg(feature = "dry_run")]
+fn opt_out_reason(args: &Cli) -> impl Fn(&Path) -> Option<String> + '_ {
+    let tag = match &args.command {
+        Commands::Archive { .. } | Commands::Due { .. } => Some("todo"),
+        Commands::Collect { options } => Some(options.tag()),
+        _ => None,
+    };
+    let is_tagged = tag.map(|tag| tagged_file_matcher(&args.walk, tag));
+    let excluded = match &args.command {
+        Commands::Format { options } => options.excluded_tags(&args.walk),
+        _ => vec![],
+    };
+    let query = args
+        .walk
+        .query
+        .as_ref()
+        .map(|query| query.predicate(&args.walk));
+
+    move |path| {
+        let content = args.walk.encoding.read(path).ok()?;
+        let relative = path.strip_prefix(&args.vault_path).unwrap_or(path);
+        if query
+            .as_ref()
+            .map_or(false, |query| !query(relative, &content))
+        {
+            Some("skipped, outside the query".to_string())
+        } else if is_tagged
+            .as_ref()
+            .map_or(false, |is_tagged| !is_tagged(&content))
+        {
+            Some(format!(
+                "skipped, not tagged #{}",
+                tag.unwrap_or_default().trim_start_matches('#')
+            ))
+        } else if excluded.iter().any(|matcher| matcher.is_match(&content)) {
+            Some("skipped, excluded tag".to_string())
+        } else {
+            None
+        }
+    }
+}
+
+/// What a dry run would do with every markdown file in the vault, by path relative to the vault
+#[cfg(feature = "dry_run")]
+fn walk_report(args: &Cli, changes: &[(PathBuf, String)]) -> Vec<(PathBuf, String)> {
+    use std::collections::HashSet;
+
+    let changed = changes.iter().map(|(path, _)| path).collect::<HashSet<_>>();
+    let walked = walk_vault(&args.vault_path, &args.walk);
+    let walked_paths = walked.iter().map(|(path, _)| path).collect::<HashSet<_>>();
+    let opt_out_reason = opt_out_reason(args);
+
+    let mut report = walked
+        .iter()
+        .map(|(path, status)| {
+            let category = match status {
+                WalkStatus::Read if changed.contains(path) => "would change".to_string(),
+                WalkStatus::Read => opt_out_reason(path).unwrap_or_else(|| "unchanged".to_string()),
+                WalkStatus::Skipped(reason) => format!("skipped, {reason}"),
+                WalkStatus::Failed(e) => format!("error, {e}"),
+            };
+            (path.clone(), category)
+        })
+        .chain(
+            changes
+                .iter()
+                .filter(|(path, _)| !walked_paths.contains(path))
+                .map(|(path, _)| (path.clone(), "would change".to_string())),
+        )
+        .map(|(path, category)| {
+            (
+                path.strip_prefix(&args.vault_path)
+                    .unwrap_or(&path)
+                    .to_path_buf(),
+                category,
+            )
+        })
+        .collect::<Vec<_>>();
+    report.sort();
+    report
+}
+
+/// Write all changes as one patch, with file paths relative to the vault
+#[cfg(feature = "dry_run")]
+fn write_patch(
+    args: &Cli,
+    changes: &[(PathBuf, String)],
+    patch_path: &PathBuf,
+) -> error::Result<()> {
+    let mut patches = changes
+        .par_iter()
+        .map(|(path, content)| {
+            let relative_path = path
+                .strip_prefix(&args.vault_path)
+                .unwrap_or(path)
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            let old_content = args.walk.encoding.read(path).ok();
+            (
+                relative_path.clone(),
+                unified_patch(
+                    &relative_path,
+                    old_content.as_deref(),
+                    content,
+                    args.diff_context,
+                ),
+            )
+        })
+        .collect::<Vec<_>>();
+    patches.sort();
+
+    fs::write(
+        patch_path,
+        patches
+            .into_iter()
+            .map(|(_, patch)| patch)
+            .collect::<String>(),
+    )?;
+    Ok(())
+}
+
 #[cfg(not(feature = "dry_run"))]
 fn write_file(
     stdout_buffer: Vec<String>,
-    _arg: &Cli,
+    arg: &Cli,
     path: PathBuf,
     content: String,
-) -> (Vec<String>, io::Result<()>) {
-    (stdout_buffer, File::atomic_overwrite(&path, content))
+) -> (Vec<String>, error::Result<()>) {
+    (
+        stdout_buffer,
+        File::atomic_overwrite(&path, content, arg.walk.encoding),
+    )
+}
+
+/// Write a change, returning its exit code and what to print about it unless `--quiet` hides it,
+/// which goes to stderr when the write failed
+fn write_change(args: &Cli, path: PathBuf, content: String, verb: &str) -> (i32, Option<String>) {
+    let mut stdout_buffer: Vec<String> = Vec::with_capacity(3);
+    stdout_buffer.push(format!("{verb} {}\n", path.display()));
+    let (mut stdout_buffer, result) = write_file(stdout_buffer, args, path, content);
+    if let Err(e) = result {
+        stdout_buffer.push(format!("Failed to apply changes: {e}\n"));
+        (1, (args.quiet < 2).then(|| stdout_buffer.join("")))
+    } else {
+        // a dry run's diffs are the output asked for, so only -qq hides them
+        let shown = args.quiet == 0 || (args.is_dry_run() && args.quiet < 2);
+        (0, shown.then(|| stdout_buffer.join("")))
+    }
+}
+
+fn apply_change(args: &Cli, path: PathBuf, content: String, verb: &str) -> i32 {
+    let (exit_code, output) = write_change(args, path, content, verb);
+    match output {
+        Some(output) if exit_code == 0 => println!("{output}"),
+        Some(output) => eprintln!("{output}"),
+        None => {}
+    }
+    exit_code
+}
+
+/// Apply every change in parallel, or one at a time in order until one fails with `fail_fast`
+fn apply_all(
+    changes: Vec<(PathBuf, String)>,
+    fail_fast: bool,
+    apply: impl Fn(PathBuf, String) -> i32 + Sync + Send,
+) -> Option<i32> {
+    if !fail_fast {
+        return changes
+            .into_par_iter()
+            .map(|(path, content)| apply(path, content))
+            .max();
+    }
+    let mut exit_code = None;
+    for (path, content) in changes {
+        let code = apply(path, content);
+        exit_code = exit_code.max(Some(code));
+        if code != 0 {
+            break;
+        }
+    }
+    exit_code
+}
+
+/// What a run did to the files of its plan, beyond which writes failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RunOutcome {
+    /// Each change was written, or failed, on its own
+    Written,
+    /// A write failed and every file was restored
+    Restored,
+    /// Nothing was written
+    Aborted,
+    /// Nothing was written, the changes went to a patch instead
+    #[cfg(feature = "dry_run")]
+    Patched,
+}
+
+impl RunOutcome {
+    #[cfg(feature = "dry_run")]
+    fn is_patched(self) -> bool {
+        self == RunOutcome::Patched
+    }
+
+    #[cfg(not(feature = "dry_run"))]
+    fn is_patched(self) -> bool {
+        false
+    }
+}
+
+/// Apply every change, restoring the original content of all files if any write fails, and
+/// removing the files the changes created
+fn apply_changes_atomically(
+    changes: Vec<(PathBuf, String)>,
+    encoding: Encoding,
+    fail_fast: bool,
+    apply: impl Fn(PathBuf, String) -> i32 + Sync + Send,
+) -> (Option<i32>, RunOutcome) {
+    let originals = match changes
+        .par_iter()
+        .map(|(path, _)| {
+            let original = path.exists().then(|| encoding.read(path)).transpose()?;
+            Ok((path.clone(), original))
+        })
+        .collect::<error::Result<Vec<(PathBuf, Option<String>)>>>()
+    {
+        Ok(originals) => originals,
+        Err(e) => {
+            eprintln!("Failed to read files before applying changes, nothing was changed: {e}");
+            return (Some(1), RunOutcome::Aborted);
+        }
+    };
+
+    let exit_code = apply_all(changes, fail_fast, apply);
+
+    if exit_code.unwrap_or(0) == 0 {
+        return (exit_code, RunOutcome::Written);
+    }
+
+    eprintln!("A write failed, restoring the original content of all files");
+    let exit_code = originals
+        .into_par_iter()
+        .map(|(path, original)| {
+            let restored = match original {
+                Some(original) if encoding.read(&path).map_or(true, |c| c != original) => {
+                    File::atomic_overwrite(&path, original, encoding)
+                }
+                None if path.exists() => std::fs::remove_file(&path).map_err(error::Error::from),
+                _ => Ok(()),
+            };
+            match restored {
+                Ok(()) => 0,
+                Err(e) => {
+                    eprintln!("Failed to restore {}: {e}", path.display());
+                    3
+                }
+            }
+        })
+        .chain(exit_code)
+        .max();
+    (exit_code, RunOutcome::Restored)
+}
+
+/// Every change that would actually alter a file, computed before anything is written
+///
+/// Changes are sorted by path, and a change to a file that can't be read is kept, since it
+/// creates or replaces that file.
+fn plan_changes(
+    args: &Cli,
+    iter: impl ParallelIterator<Item = (PathBuf, String)>,
+) -> Vec<(PathBuf, String)> {
+    let mut changes = iter
+        .filter(|(path, content)| {
+            args.walk
+                .encoding
+                .read(path)
+                .map_or(true, |current| &current != content)
+        })
+        .collect::<Vec<_>>();
+    changes.sort_by(|a, b| a.0.cmp(&b.0));
+    changes
 }
 
 fn apply_changes(
@@ -118,36 +578,1123 @@ fn apply_changes(
     iter: impl ParallelIterator<Item = (PathBuf, String)>,
     verb: &str,
 ) -> Option<i32> {
-    iter.map(|(path, content)| {
-        let mut stdout_buffer: Vec<String> = Vec::with_capacity(3);
-        stdout_buffer.push(format!("{verb} {}\n", path.display()));
-        write_file(stdout_buffer, args, path, content)
+    apply_plan(args, plan_changes(args, iter), verb)
+}
+
+/// Write a plan from `plan_changes`, or only show it for a dry run or patch
+fn apply_plan(args: &Cli, changes: Vec<(PathBuf, String)>, verb: &str) -> Option<i32> {
+    let Some(report_path) = &args.report else {
+        return write_plan(args, changes, verb, &Mutex::new(vec![])).0;
+    };
+    let summaries = changes
+        .iter()
+        .map(|(path, content)| {
+            let before = args.walk.encoding.read(path).ok();
+            (path.clone(), change_summary(before.as_deref(), content))
+        })
+        .collect::<Vec<_>>();
+    let failed = Mutex::new(vec![]);
+    let (exit_code, outcome) = write_plan(args, changes, verb, &failed);
+    if exit_code == Some(2) && !args.is_dry_run() {
+        // nothing was changed past the confirm threshold, so there's nothing to report
+        return exit_code;
+    }
+
+    let entry = report_entry(
+        args,
+        verb,
+        summaries,
+        &failed.into_inner().unwrap(),
+        outcome,
+    );
+    match append_report(report_path, &entry) {
+        Ok(()) => exit_code,
+        Err(e) => {
+            eprintln!("Failed to write report to {}: {e}", report_path.display());
+            exit_code.max(Some(1))
+        }
+    }
+}
+
+/// The size of a file before and after a change, and the lines it adds and removes in how many
+/// hunks of a diff with three lines of context
+fn change_summary(before: Option<&str>, after: &str) -> serde_json::Map<String, serde_json::Value> {
+    let diff = similar::TextDiff::from_lines(before.unwrap_or_default(), after);
+    let count = |tag| diff.iter_all_changes().filter(|c| c.tag() == tag).count();
+    let serde_json::Value::Object(summary) = serde_json::json!({
+        "bytes_before": before.map(str::len),
+        "bytes_after": after.len(),
+        "lines_added": count(similar::ChangeTag::Insert),
+        "lines_removed": count(similar::ChangeTag::Delete),
+        "hunks": diff.grouped_ops(3).len(),
+    }) else {
+        unreachable!()
+    };
+    summary
+}
+
+/// One line of the `--report` log, describing what happened to each file of a run
+fn report_entry(
+    args: &Cli,
+    verb: &str,
+    summaries: Vec<(PathBuf, serde_json::Map<String, serde_json::Value>)>,
+    failed: &[PathBuf],
+    outcome: RunOutcome,
+) -> serde_json::Value {
+    let files = summaries
+        .into_iter()
+        .map(|(path, mut summary)| {
+            let status = if failed.contains(&path) {
+                "failed"
+            } else if outcome.is_patched() {
+                "patched"
+            } else if args.is_dry_run() {
+                "dry run"
+            } else if outcome == RunOutcome::Aborted {
+                "aborted"
+            } else if outcome == RunOutcome::Restored {
+                "restored"
+            } else if args.fail_fast && failed.first().map_or(false, |first| &path > first) {
+                // the plan is written in order, so nothing after the failure was
+                "skipped"
+            } else {
+                "changed"
+            };
+            let path = path.strip_prefix(&args.vault_path).unwrap_or(&path);
+            summary.insert("path".to_string(), serde_json::json!(path));
+            summary.insert("action".to_string(), serde_json::json!(verb));
+            summary.insert("status".to_string(), serde_json::json!(status));
+            serde_json::Value::Object(summary)
+        })
+        .collect::<Vec<_>>();
+    serde_json::json!({
+        "timestamp": chrono::Local::now().to_rfc3339(),
+        "args": std::env::args().collect::<Vec<_>>(),
+        "vault": args.vault_path,
+        "dry_run": args.is_dry_run(),
+        "aborted": outcome == RunOutcome::Aborted,
+        "patched": outcome.is_patched(),
+        "files": files,
     })
-    .map(|(mut stdout_buffer, result)| {
-        if let Err(e) = result {
-            stdout_buffer.push(format!("Failed to apply changes: {e}\n"));
-            eprintln!("{}", stdout_buffer.join(""));
-            1
+}
+
+/// Add an entry to the end of the report, as a line of json, so a history of runs builds up
+fn append_report(path: &Path, entry: &serde_json::Value) -> error::Result<()> {
+    use std::io::Write;
+
+    let mut report = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(report, "{entry}")?;
+    Ok(())
+}
+
+/// Write the changes of a plan, recording the paths of those that failed
+fn write_plan(
+    args: &Cli,
+    changes: Vec<(PathBuf, String)>,
+    verb: &str,
+    failed: &Mutex<Vec<PathBuf>>,
+) -> (Option<i32>, RunOutcome) {
+    let apply = |path: PathBuf, content: String| {
+        let exit_code = apply_change(args, path.clone(), content, verb);
+        if exit_code != 0 {
+            failed.lock().unwrap().push(path);
+        }
+        exit_code
+    };
+
+    if !args.yes && !args.is_dry_run() && changes.len() > args.confirm_threshold {
+        eprintln!(
+            "{} files would be changed, more than the confirm threshold of {}. Nothing was changed, re-run with a higher --confirm-threshold or with --yes to apply the changes",
+            changes.len(),
+            args.confirm_threshold
+        );
+        return (Some(2), RunOutcome::Aborted);
+    }
+
+    #[cfg(feature = "dry_run")]
+    if let Some(patch_path) = &args.patch_out {
+        return match write_patch(args, &changes, patch_path) {
+            Ok(()) => {
+                println!(
+                    "Wrote a patch of {} changed files to {}",
+                    changes.len(),
+                    patch_path.display()
+                );
+                (Some(0), RunOutcome::Patched)
+            }
+            Err(e) => {
+                eprintln!("Failed to write patch to {}: {e}", patch_path.display());
+                (Some(1), RunOutcome::Aborted)
+            }
+        };
+    }
+
+    #[cfg(feature = "dry_run")]
+    if args.verbose {
+        for (path, category) in walk_report(args, &changes) {
+            println!("{category}: {}", path.display());
+        }
+    }
+
+    if args.atomic_run {
+        return apply_changes_atomically(changes, args.walk.encoding, args.fail_fast, apply);
+    }
+
+    (
+        apply_all(changes, args.fail_fast, apply),
+        RunOutcome::Written,
+    )
+}
+
+/// The input formatted as markdown, or the input itself if formatting leaves it alone
+fn formatted_input(args: &Cli, options: &FormatOptions, input: &str) -> String {
+    let file = File {
+        path: PathBuf::from("<stdin>"),
+        content: input.to_string(),
+    };
+    format_file(file, options, &options.excluded_tags(&args.walk))
+        .map_or_else(|| input.to_string(), |(_, content)| content)
+}
+
+/// Update the links to renamed notes, then rename them, or only show what would happen for a dry
+/// run
+fn rename_notes(args: &Cli) -> Option<i32> {
+    let RenamePlan { renames, changes } = rename_to_title(&args.vault_path, &args.walk);
+    let exit_code = apply_plan(
+        args,
+        plan_changes(args, changes.into_par_iter()),
+        "Updated links in",
+    );
+    if exit_code.unwrap_or(0) != 0 {
+        return exit_code;
+    }
+
+    let renamed = renames
+        .into_iter()
+        .map(|(from, to)| {
+            let result = if args.is_dry_run() {
+                Ok(())
+            } else {
+                std::fs::rename(&from, &to)
+            };
+            match result {
+                Ok(()) => {
+                    if args.quiet == 0 || args.is_dry_run() {
+                        let verb = if args.is_dry_run() {
+                            "Would rename"
+                        } else {
+                            "Renamed"
+                        };
+                        println!("{verb} {} to {}", from.display(), to.display());
+                    }
+                    0
+                }
+                Err(e) => {
+                    if args.quiet < 2 {
+                        eprintln!("Failed to rename {}: {e}", from.display());
+                    }
+                    1
+                }
+            }
+        })
+        .max();
+    exit_code.max(renamed)
+}
+
+/// What `format --stdin` prints for the input, the formatted markdown, or for a dry run the
+/// changes formatting would make to it
+fn format_input(args: &Cli, options: &FormatOptions, input: &str) -> String {
+    let formatted = formatted_input(args, options, input);
+
+    #[cfg(feature = "dry_run")]
+    if args.is_dry_run() {
+        return if formatted == input {
+            "  dry run, no changes\n".to_string()
         } else {
-            println!("{}", stdout_buffer.join(""));
-            0
+            dry_run_diff(vec![], args, input, &formatted).join("")
+        };
+    }
+    formatted
+}
+
+/// Write the formatted input to `out` in the vault, which doesn't need to exist yet
+fn format_input_to_file(args: &Cli, options: &FormatOptions, input: &str, out: &Path) -> i32 {
+    let path = args.vault_path.join(out);
+    if let Some(parent) = path.parent().filter(|_| !args.is_dry_run()) {
+        if let Err(e) = std::fs::create_dir_all(parent) {
+            eprintln!("Failed to create {}: {e}", parent.display());
+            return 1;
         }
-    })
-    .max()
+    }
+    apply_change(
+        args,
+        path,
+        formatted_input(args, options, input),
+        "Formatted",
+    )
+}
+
+/// Format markdown from stdin to stdout, or to the `--out` file
+fn format_stdin(args: &Cli, options: &FormatOptions) -> i32 {
+    let mut input = String::new();
+    match io::stdin().read_to_string(&mut input) {
+        Ok(_) => match &options.out {
+            Some(out) => format_input_to_file(args, options, &input, out),
+            None => {
+                print!("{}", format_input(args, options, &input));
+                0
+            }
+        },
+        Err(e) => {
+            eprintln!("Failed to read stdin: {e}");
+            1
+        }
+    }
+}
+
+/// Reformat each file in the vault as it is saved, until interrupted
+#[cfg(feature = "watch")]
+fn watch_format(args: &Cli, options: &FormatOptions) -> i32 {
+    let excluded = options.excluded_tags(&args.walk);
+    let delay = Duration::from_millis(options.watch_debounce);
+    let suppress_window = Duration::from_millis(options.watch_suppress_window);
+    println!("Watching {} for changes", args.vault_path.display());
+    let result = watch(
+        &args.vault_path,
+        &args.walk,
+        delay,
+        suppress_window,
+        |file| {
+            format_file(file, options, &excluded).map_or(false, |(path, content)| {
+                apply_change(args, path, content, "Formatted") == 0 && !args.is_dry_run()
+            })
+        },
+    );
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Failed to watch {}: {e}", args.vault_path.display());
+            1
+        }
+    }
+}
+
+/// The throughput of a run over `files` files of `bytes` bytes in total
+fn bench_report(files: usize, bytes: usize, elapsed: Duration) -> String {
+    let megabytes = bytes as f64 / 1_000_000.0;
+    let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+    format!(
+        "{files} files ({megabytes:.2} MB) in {seconds:.3}s: {:.0} files/sec, {:.2} MB/sec",
+        files as f64 / seconds,
+        megabytes / seconds
+    )
 }
 
 fn main() {
-    let args = Cli::parse();
+    let args = match Cli::parse().with_command_defaults().with_config() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+    #[cfg(feature = "dry_run")]
+    args.color.apply();
 
-    let exit_code = match args.command {
-        Commands::Archive {} => apply_changes(&args, archive(&args.vault_path), "Archived"),
-        Commands::Format {} => apply_changes(&args, format_files(&args.vault_path), "Formatted"),
-        #[cfg(feature = "notify")]
-        Commands::NotifyConflicts { ntfy_url, topic } => {
-            notify_conflicts(&args.vault_path, ntfy_url, topic)
+    let start = Instant::now();
+    let exit_code = match &args.command {
+        Commands::Archive { options } if options.check => {
+            Some(check_archive(&args.vault_path, &args.walk, options))
         }
+        Commands::Archive { options } => match &options.to_file {
+            Some(target) => apply_changes(
+                &args,
+                archive_to_file(&args.vault_path, &args.walk, options, target).into_par_iter(),
+                "Archived",
+            ),
+            None => apply_changes(
+                &args,
+                archive(&args.vault_path, &args.walk, options),
+                "Archived",
+            ),
+        },
+        Commands::Format { options } if options.stdin => Some(format_stdin(&args, options)),
+        Commands::Format { options } if options.explain => {
+            Some(explain_format(&args.vault_path, &args.walk, options))
+        }
+        #[cfg(feature = "watch")]
+        Commands::Format { options } if options.watch => Some(watch_format(&args, options)),
+        Commands::Format { options } => apply_changes(
+            &args,
+            format_files(&args.vault_path, &args.walk, options),
+            "Formatted",
+        ),
+        Commands::Due { options } => {
+            let today = chrono::Local::now().date_naive();
+            if options.overdue {
+                apply_changes(
+                    &args,
+                    move_overdue(&args.vault_path, &args.walk, today),
+                    "Moved overdue todos in",
+                )
+            } else {
+                print_due(&args.vault_path, &args.walk, &args.dates, today)
+            }
+        }
+        Commands::Export { options } => export(&args.vault_path, &args.walk, options),
+        Commands::NormalizeTasks { options } => apply_changes(
+            &args,
+            normalize_tasks(&args.vault_path, &args.walk, options),
+            "Normalized todos in",
+        ),
+        Commands::Collect { options }
+            if options.tag.is_some() && args.walk.select_heading.is_some() =>
+        {
+            eprintln!(
+                "--select-heading selects notes instead of --tag, so they can't be used together"
+            );
+            Some(2)
+        }
+        Commands::Collect { options } => apply_changes(
+            &args,
+            collect(&args.vault_path, &args.walk, options).into_par_iter(),
+            "Collected todos into",
+        ),
+        Commands::Replace { options } if !options.apply && !args.is_dry_run() => {
+            eprintln!("This build can't show a dry run of replace, re-run with --apply to replace");
+            Some(2)
+        }
+        Commands::Replace { options } => match options.pattern() {
+            Ok(pattern) => apply_changes(
+                &args,
+                replace(&args.vault_path, &args.walk, options, pattern),
+                "Replaced text in",
+            ),
+            Err(e) => {
+                eprintln!("Invalid --from regex: {e}");
+                Some(2)
+            }
+        },
+        Commands::Tags { options } => print_tags(&args.vault_path, &args.walk, options),
+        Commands::RenameToTitle => rename_notes(&args),
+        Commands::Hash => print_hashes(&args.vault_path, &args.walk),
+        Commands::Doctor => print_doctor(&args.vault_path, &args.walk),
+        Commands::Ast { file } => print_ast(&args.vault_path.join(file), args.walk.encoding),
+        #[cfg(feature = "notify")]
+        Commands::NotifyConflicts { options } => notify_conflicts(&args.vault_path, options),
     }
     .unwrap_or(0);
 
+    if args.bench_report {
+        let elapsed = start.elapsed();
+        let (files, bytes) = args.walk.read.totals();
+        eprintln!("{}", bench_report(files, bytes, elapsed));
+    }
+
     std::process::exit(exit_code);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use pretty_assertions::assert_eq;
+
+    #[cfg(feature = "dry_run")]
+    fn vault_with(files: &[(&str, &str)]) -> tempfile::TempDir {
+        let vault = tempfile::tempdir().unwrap();
+        for (path, content) in files {
+            let path = vault.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        vault
+    }
+
+    fn vault_with_files(names: &[&str], content: &str) -> (tempfile::TempDir, Vec<PathBuf>) {
+        let vault = tempfile::tempdir().unwrap();
+        let files = names
+            .iter()
+            .map(|name| vault.path().join(name))
+            .collect::<Vec<_>>();
+        for path in &files {
+            fs::write(path, content).unwrap();
+        }
+        (vault, files)
+    }
+
+    fn changed(files: &[PathBuf]) -> Vec<(PathBuf, String)> {
+        files
+            .iter()
+            .map(|path| (path.clone(), "changed\n".to_string()))
+            .collect()
+    }
+
+    #[test]
+    #[cfg(feature = "dry_run")]
+    fn verbose_dry_run_reports_every_file() {
+        let vault = vault_with(&[
+            ("messy.md", "# a\n\n\nfoo--bar\n"),
+            ("clean.md", "already formatted\n"),
+            (".templates/template.md", "# a\n\n\ntemplate\n"),
+            ("note.sync-conflict-20230101-120000-ABCDEF.md", "conflict\n"),
+            ("drawing.excalidraw.md", "drawing\n"),
+            ("image.png", "not markdown"),
+        ]);
+        fs::write(vault.path().join("latin1.md"), b"caf\xe9\n").unwrap();
+        let args = Cli::parse_from([
+            "marksage",
+            "--vault-path",
+            vault.path().to_str().unwrap(),
+            "--dry-run",
+            "--verbose",
+            "format",
+        ]);
+        let Commands::Format { options } = &args.command else {
+            unreachable!()
+        };
+
+        let plan = plan_changes(&args, format_files(&args.vault_path, &args.walk, options));
+        let report = walk_report(&args, &plan);
+
+        assert_eq!(
+            report
+                .iter()
+                .map(|(path, category)| (path.to_str().unwrap(), category.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                (".templates", "skipped, hidden"),
+                ("clean.md", "unchanged"),
+                ("drawing.excalidraw.md", "skipped, excalidraw drawing"),
+                ("latin1.md", "error, stream did not contain valid UTF-8"),
+                ("messy.md", "would change"),
+                (
+                    "note.sync-conflict-20230101-120000-ABCDEF.md",
+                    "skipped, sync conflict"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "dry_run")]
+    fn verbose_dry_run_reports_files_left_out_of_the_command() {
+        let vault = vault_with(&[
+            ("notes/messy.md", "#todo\n\n- [x] done\n"),
+            ("notes/untagged.md", "- [x] done\n"),
+            ("notes/private.md", "#todo #private\n\n- [x] done\n"),
+            ("other.md", "#todo\n\n- [x] done\n"),
+        ]);
+        let report = |command: &[&str]| {
+            let args = Cli::parse_from(
+                [
+                    "marksage",
+                    "--vault-path",
+                    vault.path().to_str().unwrap(),
+                    "--dry-run",
+                    "--query",
+                    "path:notes",
+                ]
+                .iter()
+                .chain(command),
+            );
+            walk_report(&args, &[])
+                .into_iter()
+                .map(|(path, category)| (path.to_str().unwrap().to_string(), category))
+                .collect::<Vec<_>>()
+        };
+        let expected = |entries: &[(&str, &str)]| {
+            entries
+                .iter()
+                .map(|(path, category)| (path.to_string(), category.to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            report(&["archive"]),
+            expected(&[
+                ("notes/messy.md", "unchanged"),
+                ("notes/private.md", "unchanged"),
+                ("notes/untagged.md", "skipped, not tagged #todo"),
+                ("other.md", "skipped, outside the query"),
+            ])
+        );
+        assert_eq!(
+            report(&["format", "--exclude-tag", "private"]),
+            expected(&[
+                ("notes/messy.md", "unchanged"),
+                ("notes/private.md", "skipped, excluded tag"),
+                ("notes/untagged.md", "unchanged"),
+                ("other.md", "skipped, outside the query"),
+            ])
+        );
+    }
+
+    #[test]
+    fn plan_matches_applied_changes() {
+        let (vault, files) = vault_with_files(&["a.md", "b.md"], "original\n");
+        let new_file = vault.path().join("new.md");
+        let args = Cli::parse_from([
+            "marksage",
+            "--vault-path",
+            vault.path().to_str().unwrap(),
+            "format",
+        ]);
+        let proposed = vec![
+            (files[0].clone(), "changed\n".to_string()),
+            (files[1].clone(), "original\n".to_string()),
+            (new_file.clone(), "new\n".to_string()),
+        ];
+
+        let plan = plan_changes(&args, proposed.into_par_iter());
+        assert_eq!(
+            plan,
+            vec![
+                (files[0].clone(), "changed\n".to_string()),
+                (new_file, "new\n".to_string()),
+            ]
+        );
+
+        assert_eq!(apply_plan(&args, plan.clone(), "Formatted"), Some(0));
+        for (path, content) in plan {
+            assert_eq!(fs::read_to_string(path).unwrap(), content);
+        }
+        assert_eq!(fs::read_to_string(&files[1]).unwrap(), "original\n");
+    }
+
+    #[test]
+    fn exceeding_confirm_threshold_without_yes_changes_nothing() {
+        let (vault, files) = vault_with_files(&["a.md", "b.md", "c.md"], "original\n");
+        let args = Cli::parse_from([
+            "marksage",
+            "--vault-path",
+            vault.path().to_str().unwrap(),
+            "--confirm-threshold",
+            "2",
+            "format",
+        ]);
+
+        assert_eq!(
+            apply_changes(&args, changed(&files).into_par_iter(), "Formatted"),
+            Some(2)
+        );
+        for path in &files {
+            assert_eq!(fs::read_to_string(path).unwrap(), "original\n");
+        }
+    }
+
+    #[test]
+    fn max_changes_caps_the_run() {
+        let (vault, files) = vault_with_files(&["a.md", "b.md"], "original\n");
+        let args = |max_changes: &str| {
+            Cli::parse_from([
+                "marksage",
+                "--vault-path",
+                vault.path().to_str().unwrap(),
+                "--max-changes",
+                max_changes,
+                "format",
+            ])
+        };
+
+        assert_eq!(
+            apply_changes(&args("1"), changed(&files).into_par_iter(), "Formatted"),
+            Some(2)
+        );
+        for path in &files {
+            assert_eq!(fs::read_to_string(path).unwrap(), "original\n");
+        }
+
+        assert_eq!(
+            apply_changes(&args("2"), changed(&files).into_par_iter(), "Formatted"),
+            Some(0)
+        );
+        for path in &files {
+            assert_eq!(fs::read_to_string(path).unwrap(), "changed\n");
+        }
+    }
+
+    #[test]
+    fn exceeding_confirm_threshold_with_yes_applies_changes() {
+        let (vault, files) = vault_with_files(&["a.md", "b.md", "c.md"], "original\n");
+        let args = Cli::parse_from([
+            "marksage",
+            "--vault-path",
+            vault.path().to_str().unwrap(),
+            "--confirm-threshold",
+            "2",
+            "--yes",
+            "format",
+        ]);
+
+        assert_eq!(
+            apply_changes(&args, changed(&files).into_par_iter(), "Formatted"),
+            Some(0)
+        );
+        for path in &files {
+            assert_eq!(fs::read_to_string(path).unwrap(), "changed\n");
+        }
+    }
+
+    /// Held by tests that set whether output is colored, since the setting is global
+    #[cfg(feature = "dry_run")]
+    static COLOR: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    /// What `format --stdin` prints for the input with these global args, colored as they say
+    #[cfg(feature = "dry_run")]
+    fn format_stdin_with(vault: &tempfile::TempDir, args: &[&str], input: &str) -> String {
+        let args = Cli::parse_from(
+            [
+                &["marksage", "--vault-path", vault.path().to_str().unwrap()],
+                args,
+                &["format", "--stdin"],
+            ]
+            .concat(),
+        );
+        let Commands::Format { options } = &args.command else {
+            unreachable!()
+        };
+        args.color.apply();
+        format_input(&args, options, input)
+    }
+
+    #[test]
+    fn stdin_is_formatted_into_a_new_file() {
+        let vault = tempfile::tempdir().unwrap();
+        let args = Cli::parse_from([
+            "marksage",
+            "--vault-path",
+            vault.path().to_str().unwrap(),
+            "format",
+            "--stdin",
+            "--out",
+            "new/folder/note.md",
+        ]);
+        let Commands::Format { options } = &args.command else {
+            unreachable!()
+        };
+
+        let out = options.out.as_ref().unwrap();
+        assert_eq!(format_input_to_file(&args, options, "* a--b", out), 0);
+        assert_eq!(
+            fs::read_to_string(vault.path().join("new/folder/note.md")).unwrap(),
+            "- a—b\n"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "dry_run")]
+    fn color_flag_overrides_detection() {
+        let _color = COLOR.lock().unwrap();
+        let vault = tempfile::tempdir().unwrap();
+
+        let output = format_stdin_with(&vault, &["--dry-run", "--color", "always"], "a--b\n");
+        assert!(output.contains('\x1b'), "{output:?}");
+        let output = format_stdin_with(&vault, &["--dry-run", "--color", "never"], "a--b\n");
+        assert!(!output.contains('\x1b'), "{output:?}");
+    }
+
+    #[test]
+    #[cfg(feature = "dry_run")]
+    fn dry_run_of_stdin_diffs_the_input() {
+        let _color = COLOR.lock().unwrap();
+        let (vault, files) = vault_with_files(&["note.md"], "foo--bar\n");
+        let format_args = |args: &[&str]| format_stdin_with(&vault, args, "a--b\n\n\nc\n");
+
+        assert_eq!(format_args(&["--color", "never"]), "a—b\n\nc\n");
+        let output = format_args(&["--dry-run", "--color", "never"]);
+        assert!(output.contains("|-a--b\n"), "{output}");
+        assert!(output.contains("|+a—b\n"), "{output}");
+        assert_eq!(fs::read_to_string(&files[0]).unwrap(), "foo--bar\n");
+    }
+
+    #[test]
+    #[cfg(feature = "dry_run")]
+    fn dry_run_archive_to_file_previews_every_file_without_writing() {
+        let _color = COLOR.lock().unwrap();
+        ColorMode::Never.apply();
+        let (vault, files) =
+            vault_with_files(&["a.md", "b.md"], "#todo\n\n- [x] done\n- [ ] open\n");
+        let args = Cli::parse_from([
+            "marksage",
+            "--vault-path",
+            vault.path().to_str().unwrap(),
+            "--dry-run",
+            "archive",
+            "--to-file",
+            "Archive.md",
+        ]);
+        let Commands::Archive { options } = &args.command else {
+            unreachable!()
+        };
+
+        let output = archive_to_file(
+            vault.path(),
+            &args.walk,
+            options,
+            options.to_file.as_ref().unwrap(),
+        )
+        .into_iter()
+        .map(|(path, content)| {
+            let (stdout_buffer, result) = write_file(vec![], &args, path, content);
+            result.unwrap();
+            stdout_buffer.join("")
+        })
+        .collect::<Vec<_>>();
+
+        assert_eq!(output.len(), 3);
+        for removal in &output[..2] {
+            assert!(removal.contains("- [x] done"), "{removal}");
+        }
+        assert!(
+            output[2].contains("new file would be:\n- [x] done\n- [x] done\n"),
+            "{}",
+            output[2]
+        );
+        for path in &files {
+            assert_eq!(
+                fs::read_to_string(path).unwrap(),
+                "#todo\n\n- [x] done\n- [ ] open\n"
+            );
+        }
+        assert!(!vault.path().join("Archive.md").exists());
+    }
+
+    #[test]
+    #[cfg(feature = "dry_run")]
+    fn patch_out_applies_cleanly_to_the_vault() {
+        let vault = tempfile::tempdir().unwrap();
+        fs::create_dir(vault.path().join("folder")).unwrap();
+        let unformatted = [
+            ("a.md", "# a\n\n\nfoo--bar\n"),
+            ("folder/b.md", "| x | y |\n| - | - |\n| long | z |\n"),
+            ("c.md", "already formatted\n"),
+        ];
+        for (path, content) in unformatted {
+            fs::write(vault.path().join(path), content).unwrap();
+        }
+        let patch_path = vault.path().join("changes.patch");
+        let args = Cli::parse_from([
+            "marksage",
+            "--vault-path",
+            vault.path().to_str().unwrap(),
+            "--dry-run",
+            "--patch-out",
+            patch_path.to_str().unwrap(),
+            "format",
+        ]);
+        let Commands::Format { options } = &args.command else {
+            unreachable!()
+        };
+        let expected = format_files(&args.vault_path, &args.walk, options).collect::<Vec<_>>();
+
+        assert_eq!(
+            apply_changes(
+                &args,
+                format_files(&args.vault_path, &args.walk, options),
+                "Formatted"
+            ),
+            Some(0)
+        );
+        for (path, content) in unformatted {
+            assert_eq!(
+                fs::read_to_string(vault.path().join(path)).unwrap(),
+                content
+            );
+        }
+
+        let status = std::process::Command::new("git")
+            .args(["apply", patch_path.to_str().unwrap()])
+            .current_dir(vault.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(expected.len(), 2);
+        for (path, content) in expected {
+            assert_eq!(fs::read_to_string(path).unwrap(), content);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "dry_run")]
+    fn replace_is_a_dry_run_unless_applied() {
+        let vault = tempfile::tempdir().unwrap();
+        let args = |apply: &[&str]| {
+            let vault_path = vault.path().to_str().unwrap();
+            let base = ["marksage", "--vault-path", vault_path, "replace"];
+            let replace = ["--from", "old", "--to", "new"];
+            Cli::parse_from(base.iter().chain(&replace).chain(apply)).with_command_defaults()
+        };
+
+        assert!(args(&[]).dry_run);
+        assert!(!args(&["--apply"]).dry_run);
+    }
+
+    #[test]
+    fn atomic_run_restores_all_files_on_failure() {
+        let (_vault, files) = vault_with_files(&["a.md", "b.md", "c.md", "d.md"], "original\n");
+        let failing = files[1].clone();
+
+        let run =
+            apply_changes_atomically(changed(&files), Encoding::Utf8, false, |path, content| {
+                if path == failing {
+                    1
+                } else {
+                    File::atomic_overwrite(&path, content, Encoding::Utf8).map_or(1, |_| 0)
+                }
+            });
+
+        assert_eq!(run, (Some(1), RunOutcome::Restored));
+        for path in &files {
+            assert_eq!(fs::read_to_string(path).unwrap(), "original\n");
+        }
+    }
+
+    #[test]
+    fn atomic_run_removes_created_files_on_failure() {
+        let (vault, files) = vault_with_files(&["a.md", "b.md"], "original\n");
+        let created = vault.path().join("new.md");
+        let mut changes = changed(&files);
+        changes.push((created.clone(), "created\n".to_string()));
+        let run = |changes: Vec<(PathBuf, String)>, failing: Option<&PathBuf>| {
+            apply_changes_atomically(changes, Encoding::Utf8, false, |path, content| {
+                if Some(&path) == failing {
+                    1
+                } else {
+                    File::atomic_overwrite(&path, content, Encoding::Utf8).map_or(1, |_| 0)
+                }
+            })
+            .0
+        };
+
+        assert_eq!(run(changes.clone(), Some(&files[1])), Some(1));
+        assert!(!created.exists());
+        for path in &files {
+            assert_eq!(fs::read_to_string(path).unwrap(), "original\n");
+        }
+
+        assert_eq!(run(changes, None), Some(0));
+        assert_eq!(fs::read_to_string(&created).unwrap(), "created\n");
+    }
+
+    #[test]
+    fn atomic_run_applies_all_files_on_success() {
+        let (vault, files) = vault_with_files(&["a.md", "b.md"], "original\n");
+        let args = Cli::parse_from([
+            "marksage",
+            "--vault-path",
+            vault.path().to_str().unwrap(),
+            "--atomic-run",
+            "format",
+        ]);
+
+        assert_eq!(
+            apply_changes(&args, changed(&files).into_par_iter(), "Formatted"),
+            Some(0)
+        );
+        for path in &files {
+            assert_eq!(fs::read_to_string(path).unwrap(), "changed\n");
+        }
+    }
+
+    #[test]
+    fn fail_fast_leaves_later_files_untouched() {
+        let (vault, files) = vault_with_files(&["a.md", "c.md", "d.md"], "original\n");
+        // a folder that doesn't exist can't be written to
+        let unwritable = vault.path().join("b/missing.md");
+        let run = |fail_fast: &[&str]| {
+            let args = Cli::parse_from(
+                [
+                    &["marksage", "--vault-path", vault.path().to_str().unwrap()],
+                    fail_fast,
+                    &["-qq", "format"],
+                ]
+                .concat(),
+            );
+            let mut changes = changed(&files);
+            changes.push((unwritable.clone(), "changed\n".to_string()));
+            apply_changes(&args, changes.into_par_iter(), "Formatted")
+        };
+        let contents = || {
+            files
+                .iter()
+                .map(|path| fs::read_to_string(path).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(run(&["--fail-fast"]), Some(1));
+        assert_eq!(contents(), ["changed\n", "original\n", "original\n"]);
+        assert_eq!(run(&[]), Some(1));
+        assert_eq!(contents(), ["changed\n", "changed\n", "changed\n"]);
+    }
+
+    #[test]
+    fn bench_report_shows_throughput() {
+        assert_eq!(
+            bench_report(50, 2_500_000, Duration::from_millis(500)),
+            "50 files (2.50 MB) in 0.500s: 100 files/sec, 5.00 MB/sec"
+        );
+    }
+
+    #[test]
+    fn quiet_hides_successes_and_then_failures() {
+        let vault = tempfile::tempdir().unwrap();
+        let written = vault.path().join("a.md");
+        let unwritable = vault.path().join("missing/b.md");
+        let args = |quiet: &[&str]| {
+            Cli::parse_from(
+                [
+                    &["marksage", "--vault-path", vault.path().to_str().unwrap()],
+                    quiet,
+                    &["format"],
+                ]
+                .concat(),
+            )
+        };
+        let write = |args: &Cli, path: &PathBuf| {
+            write_change(args, path.clone(), "changed\n".to_string(), "Formatted")
+        };
+
+        let (exit_code, output) = write(&args(&[]), &written);
+        assert_eq!(exit_code, 0);
+        assert!(output.unwrap().starts_with("Formatted "));
+
+        assert_eq!(write(&args(&["-q"]), &written), (0, None));
+        let (exit_code, output) = write(&args(&["--quiet"]), &unwritable);
+        assert_eq!(exit_code, 1);
+        assert!(output.unwrap().contains("Failed to apply changes"));
+
+        assert_eq!(write(&args(&["-qq"]), &unwritable), (1, None));
+        assert_eq!(fs::read_to_string(&written).unwrap(), "changed\n");
+    }
+
+    #[test]
+    fn report_records_each_file_of_a_run() {
+        let (vault, files) = vault_with_files(&["a.md", "b.md"], "original\n");
+        let report = vault.path().join("report.jsonl");
+        let args = Cli::parse_from([
+            "marksage",
+            "--vault-path",
+            vault.path().to_str().unwrap(),
+            "--report",
+            report.to_str().unwrap(),
+            "format",
+        ]);
+
+        let changes = vec![
+            (files[0].clone(), "changed\n".to_string()),
+            (vault.path().join("missing/c.md"), "new\n".to_string()),
+        ];
+        assert_eq!(apply_plan(&args, changes, "Formatted"), Some(1));
+        assert_eq!(
+            apply_plan(
+                &args,
+                vec![(files[1].clone(), "b\n".to_string())],
+                "Formatted"
+            ),
+            Some(0)
+        );
+
+        let report = fs::read_to_string(report).unwrap();
+        let entries = report
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["dry_run"], false);
+        assert!(entries[0]["timestamp"].is_string());
+        assert_eq!(
+            entries[0]["files"],
+            serde_json::json!([
+                {
+                    "path": "a.md",
+                    "action": "Formatted",
+                    "status": "changed",
+                    "bytes_before": 9,
+                    "bytes_after": 8,
+                    "lines_added": 1,
+                    "lines_removed": 1,
+                    "hunks": 1,
+                },
+                {
+                    "path": "missing/c.md",
+                    "action": "Formatted",
+                    "status": "failed",
+                    "bytes_before": null,
+                    "bytes_after": 4,
+                    "lines_added": 1,
+                    "lines_removed": 0,
+                    "hunks": 1,
+                },
+            ])
+        );
+        assert_eq!(entries[1]["files"][0]["path"], "b.md");
+    }
+
+    #[test]
+    #[cfg(feature = "dry_run")]
+    fn report_records_a_run_written_to_a_patch() {
+        let (vault, files) = vault_with_files(&["a.md"], "original\n");
+        let report = vault.path().join("report.jsonl");
+        let patch_path = vault.path().join("changes.patch");
+        let args = Cli::parse_from([
+            "marksage",
+            "--vault-path",
+            vault.path().to_str().unwrap(),
+            "--dry-run",
+            "--report",
+            report.to_str().unwrap(),
+            "--patch-out",
+            patch_path.to_str().unwrap(),
+            "format",
+        ]);
+
+        let changes = vec![(files[0].clone(), "changed\n".to_string())];
+        assert_eq!(apply_plan(&args, changes, "Formatted"), Some(0));
+        assert_eq!(fs::read_to_string(&files[0]).unwrap(), "original\n");
+        assert!(patch_path.exists());
+
+        let entry = serde_json::from_str::<serde_json::Value>(&fs::read_to_string(report).unwrap())
+            .unwrap();
+        assert_eq!(entry["dry_run"], true);
+        assert_eq!(entry["patched"], true);
+        assert_eq!(entry["aborted"], false);
+        assert_eq!(entry["files"][0]["path"], "a.md");
+        assert_eq!(entry["files"][0]["status"], "patched");
+    }
+
+    #[test]
+    fn report_records_an_aborted_atomic_run() {
+        let (vault, files) = vault_with_files(&["a.md"], "original\n");
+        let unreadable = vault.path().join("dir.md");
+        fs::create_dir(&unreadable).unwrap();
+        let report = vault.path().join("report.jsonl");
+        let args = Cli::parse_from([
+            "marksage",
+            "--vault-path",
+            vault.path().to_str().unwrap(),
+            "--report",
+            report.to_str().unwrap(),
+            "--atomic-run",
+            "format",
+        ]);
+
+        let changes = vec![
+            (files[0].clone(), "changed\n".to_string()),
+            (unreadable, "new\n".to_string()),
+        ];
+        assert_eq!(apply_plan(&args, changes, "Formatted"), Some(1));
+        assert_eq!(fs::read_to_string(&files[0]).unwrap(), "original\n");
+
+        let entry = serde_json::from_str::<serde_json::Value>(&fs::read_to_string(report).unwrap())
+            .unwrap();
+        assert_eq!(entry["aborted"], true);
+        assert_eq!(entry["files"][0]["status"], "aborted");
+        assert_eq!(entry["files"][1]["status"], "aborted");
+    }
+}
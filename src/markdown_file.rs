@@ -1,32 +1,94 @@
-use std::{fs, io, path::PathBuf};
+use std::{
+    borrow::Cow,
+    fs,
+    path::{Path, PathBuf},
+};
 
+use clap::ValueEnum;
+use encoding_rs::WINDOWS_1252;
+use lazy_static::lazy_static;
 use markdown::{
-    mdast::{self, Node},
+    mdast::{self, Node, ReferenceKind},
     Constructs, ParseOptions,
 };
-use unicode_width::UnicodeWidthStr;
+use regex::Regex;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthChar;
+
+use crate::error::{Error, Result};
+
+lazy_static! {
+    static ref IS_CHECKBOX: Regex = Regex::new(r"^\[[ xX]\](?:\s|$)").unwrap();
+    static ref BLOCK_ID: Regex = Regex::new(r"(?:^|\s)\^[A-Za-z0-9-]+\s*$").unwrap();
+}
+
+/// Split the text at the end of a block into its content and an obsidian block id like `^abc123`
+///
+/// The id is an anchor for links to the block, so it must stay exactly as written.
+pub fn split_block_id(text: &str) -> (&str, &str) {
+    match BLOCK_ID.find(text) {
+        Some(id) => text.split_at(id.start()),
+        None => (text, ""),
+    }
+}
 
 pub struct File {
     pub path: PathBuf,
     pub content: String,
 }
 
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// Files that aren't valid UTF-8 are skipped
+    #[default]
+    Utf8,
+    /// ISO 8859-1, decoded as its windows-1252 superset like browsers do
+    Latin1,
+}
+
+impl Encoding {
+    pub fn read(self, path: &Path) -> Result<String> {
+        match self {
+            Encoding::Utf8 => String::from_utf8(fs::read(path)?)
+                .map_err(|_| Error::Encoding("stream did not contain valid UTF-8".to_string())),
+            Encoding::Latin1 => Ok(WINDOWS_1252
+                .decode_without_bom_handling(&fs::read(path)?)
+                .0
+                .into_owned()),
+        }
+    }
+
+    /// Encode `content`, failing rather than writing replacements for chars the encoding lacks
+    pub fn encode(self, content: &str) -> Result<Cow<'_, [u8]>> {
+        match self {
+            Encoding::Utf8 => Ok(Cow::Borrowed(content.as_bytes())),
+            Encoding::Latin1 => match WINDOWS_1252.encode(content) {
+                (_, _, true) => Err(Error::Encoding(
+                    "content has characters that can't be encoded as latin1".to_string(),
+                )),
+                (bytes, _, false) => Ok(bytes),
+            },
+        }
+    }
+}
+
 impl File {
-    pub fn at_path(path: PathBuf) -> io::Result<Self> {
-        let content = fs::read_to_string(&path)?;
+    pub fn at_path(path: PathBuf, encoding: Encoding) -> Result<Self> {
+        let content = encoding.read(&path)?;
         Ok(Self { path, content })
     }
 
-    pub fn atomic_overwrite(path: &PathBuf, content: String) -> io::Result<()> {
+    pub fn atomic_overwrite(path: &PathBuf, content: String, encoding: Encoding) -> Result<()> {
+        // padded to a fixed width, so the walk can tell these apart from notes named like them
         let tmp_path = path.with_extension(format!(
-            "tmp{}{}",
+            "tmp{:020}{}",
             rand::random::<u64>(),
             path.extension()
                 .unwrap_or_default()
                 .to_str()
                 .map_or_else(String::new, |s| format!(".{s}"))
         ));
-        fs::write(&tmp_path, content)?;
+        fs::write(&tmp_path, encoding.encode(&content)?)?;
         fs::rename(tmp_path, path)?;
         Ok(())
     }
@@ -64,17 +126,56 @@ impl MdastDocument {
     }
 
     pub fn render(&self) -> String {
-        self.root
-            .children
-            .iter()
-            .map(|n| mdast_string(n, Context::default()))
+        self.render_with(RenderOptions::default())
+    }
+
+    /// Render the document back to markdown, with exactly one blank line between top level blocks
+    /// other than those around the headings `heading_spacing` picks out
+    pub fn render_with(&self, options: RenderOptions) -> String {
+        let ctx = Context {
+            options,
+            ..Context::default()
+        };
+        let is_spaced = |node: &Node| {
+            matches!(options.heading_spacing, Some((title, _))
+                if matches!(node, Node::Heading(_)) && node.to_string() == title)
+        };
+        let mut rendered = String::new();
+        for (index, node) in self.root.children.iter().enumerate() {
+            if index > 0 {
+                let blank_lines = match options.heading_spacing {
+                    Some((_, blank_lines))
+                        if is_spaced(node) || is_spaced(&self.root.children[index - 1]) =>
+                    {
+                        blank_lines
+                    }
+                    _ => 1,
+                };
+                rendered.push_str(&"\n".repeat(blank_lines));
+            }
+            let block = mdast_string(node, ctx);
+            rendered.push_str(&block);
             // handles root level html
-            .map(|s| format!("{}{}", s, if s.ends_with('\n') { "" } else { "\n" }))
-            .collect::<Vec<String>>()
-            .join("\n")
+            if !block.ends_with('\n') {
+                rendered.push('\n');
+            }
+        }
+        rendered
     }
 }
 
+/// Render the content of a list item without its checkbox or any nested lists
+pub fn list_item_text(list_item: &mdast::ListItem) -> String {
+    list_item
+        .children
+        .iter()
+        .filter(|n| !matches!(n, Node::List(_)))
+        .map(|n| mdast_string(n, Context::default()))
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
 fn count_longest_sequential_chars(s: &str, c: char) -> usize {
     let mut longest = 0;
     let mut count = 0;
@@ -91,10 +192,149 @@ fn count_longest_sequential_chars(s: &str, c: char) -> usize {
     longest
 }
 
+/// The display width of a string, measured per grapheme cluster
+///
+/// Summing the width of every char overcounts emoji zwj sequences (👨‍👩‍👧 is three wide emoji
+/// joined into one glyph), so each cluster is as wide as its widest char instead.
+fn display_width(s: &str) -> usize {
+    s.graphemes(true)
+        .map(|g| g.chars().filter_map(|c| c.width()).max().unwrap_or(0))
+        .sum()
+}
+
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OrderedDelimiter {
+    /// `1.`
+    #[default]
+    Dot,
+    /// `1)`
+    Paren,
+    /// Keep the delimiter each list was written with
+    Preserve,
+}
+
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RenderFlavor {
+    /// Github flavored markdown, with tables, todos and strikethrough
+    #[default]
+    Gfm,
+    /// Strict CommonMark, with tables and strikethrough as html and todos as plain list items
+    Commonmark,
+}
+
 #[derive(Default, Clone, Copy)]
-struct Context {
+pub struct RenderOptions<'a> {
+    pub ordered_delimiter: OrderedDelimiter,
+    /// The markdown flavor to render, downgrading gfm constructs for a strict CommonMark target
+    pub flavor: RenderFlavor,
+    /// The markdown the document was parsed from, used to preserve the original syntax
+    pub source: Option<&'a str>,
+    /// Symbols to render unchecked and checked todos with, instead of gfm `[ ]` and `[x]`
+    pub checkbox_symbols: Option<(&'a str, &'a str)>,
+    /// Keep the bullet each unordered list was written with, instead of `-`
+    pub preserve_bullets: bool,
+    /// Keep code blocks that were written indented by four spaces indented, instead of fencing them
+    pub preserve_indented_code: bool,
+    /// Languages of code blocks to write exactly as in the source, fences and info string included
+    pub protected_langs: &'a [String],
+    /// Top level headings with this text get this many blank lines before and after them, instead
+    /// of one
+    pub heading_spacing: Option<(&'a str, usize)>,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Context<'a> {
     pub list_index: Option<u32>,
+    /// The column the markers of a list start at, past the markers of the items it's nested in
     pub list_indent: Option<usize>,
+    pub list_delimiter: Option<char>,
+    pub list_bullet: Option<char>,
+    pub options: RenderOptions<'a>,
+}
+
+/// The delimiter an ordered list was written with, read from the source at the list's position
+fn source_ordered_delimiter(list: &mdast::List, source: Option<&str>) -> Option<char> {
+    let offset = list.position.as_ref()?.start.offset;
+    source?
+        .get(offset..)?
+        .chars()
+        .find(|c| !c.is_ascii_digit())
+        .filter(|c| matches!(c, '.' | ')'))
+}
+
+/// The bullet an unordered list was written with, read from the source at the list's position
+fn source_bullet(list: &mdast::List, source: Option<&str>) -> Option<char> {
+    let offset = list.position.as_ref()?.start.offset;
+    source?
+        .get(offset..)?
+        .chars()
+        .find(|c| !c.is_whitespace())
+        .filter(|c| matches!(c, '-' | '*' | '+'))
+}
+
+/// Whether a code block was written indented rather than fenced, read from the source at its
+/// position
+fn is_indented_code(code: &mdast::Code, source: Option<&str>) -> bool {
+    let Some(offset) = code.position.as_ref().map(|p| p.start.offset) else {
+        return false;
+    };
+    source
+        .and_then(|source| source.get(offset..))
+        .map_or(false, |code| {
+            !code.trim_start().starts_with("```") && !code.trim_start().starts_with("~~~")
+        })
+}
+
+/// Whether a code block is in one of the languages to leave exactly as written
+pub fn is_protected_code(code: &mdast::Code, protected_langs: &[String]) -> bool {
+    code.lang.as_ref().map_or(false, |lang| {
+        protected_langs
+            .iter()
+            .any(|protected| protected.eq_ignore_ascii_case(lang))
+    })
+}
+
+/// A fenced code block as written in the source, read from the source at its position, without
+/// the indentation of the container it's in
+fn source_code(code: &mdast::Code, source: Option<&str>) -> Option<String> {
+    let position = code.position.as_ref()?;
+    let written = source?.get(position.start.offset..position.end.offset)?;
+    let (open, rest) = written.split_once('\n')?;
+    let open = open.trim_start();
+    let fence_char = open.chars().next().filter(|c| matches!(c, '`' | '~'))?;
+    let fence = &open[..open.len() - open.trim_start_matches(fence_char).len()];
+    // a block left open runs to the end of its container, and is closed here instead
+    let close = rest
+        .lines()
+        .last()
+        .map(str::trim_start)
+        .filter(|line| line.trim_end().starts_with(fence))
+        .filter(|line| line.trim_end().chars().all(|c| c == fence_char))
+        .unwrap_or(fence);
+    Some(match code.value.as_str() {
+        "" => format!("{open}\n{close}\n"),
+        value => format!("{open}\n{value}\n{close}\n"),
+    })
+}
+
+fn fenced_code(code: &mdast::Code) -> String {
+    // the info string after the language, like highlighted lines, is kept in `meta`
+    let info = match (&code.lang, &code.meta) {
+        (Some(lang), Some(meta)) => format!("{lang} {meta}"),
+        (Some(lang), None) => lang.clone(),
+        (None, _) => String::new(),
+    };
+    format!("```{}\n{}\n```\n", info, code.value)
+}
+
+/// The bullet to render an unordered list with, `None` for the default `-`
+///
+/// Every item of a list shares its bullet, since changing bullets starts a new list.
+fn list_bullet(list: &mdast::List, options: &RenderOptions) -> Option<char> {
+    options
+        .preserve_bullets
+        .then(|| source_bullet(list, options.source))
+        .flatten()
 }
 
 fn recursive_mdast_string(ctx: Context, nodes: &[Node], sep: &str) -> String {
@@ -105,8 +345,8 @@ fn recursive_mdast_string(ctx: Context, nodes: &[Node], sep: &str) -> String {
         .join(sep)
 }
 
-fn recursive_contextual_mdast_string<'a>(
-    nodes: impl IntoIterator<Item = (&'a Node, Context)>,
+fn recursive_contextual_mdast_string<'a, 'o>(
+    nodes: impl IntoIterator<Item = (&'a Node, Context<'o>)>,
 ) -> String {
     nodes
         .into_iter()
@@ -114,6 +354,77 @@ fn recursive_contextual_mdast_string<'a>(
         .collect::<String>()
 }
 
+/// Escape text for html, so it can't be read as markup
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Inline markdown as html, since markdown isn't parsed inside an html block
+fn inline_html(nodes: &[Node]) -> String {
+    nodes
+        .iter()
+        .map(|node| match node {
+            Node::Text(text) => escape_html(&text.value),
+            Node::Emphasis(e) => format!("<em>{}</em>", inline_html(&e.children)),
+            Node::Strong(s) => format!("<strong>{}</strong>", inline_html(&s.children)),
+            Node::Delete(d) => format!("<del>{}</del>", inline_html(&d.children)),
+            Node::InlineCode(code) => format!("<code>{}</code>", escape_html(&code.value)),
+            Node::Link(link) => format!(
+                "<a href=\"{}\">{}</a>",
+                escape_html(&link.url),
+                inline_html(&link.children)
+            ),
+            Node::Image(image) => format!(
+                "<img src=\"{}\" alt=\"{}\" />",
+                escape_html(&image.url),
+                escape_html(&image.alt)
+            ),
+            Node::Break(_) => "<br />".to_string(),
+            Node::Html(html) => html.value.clone(),
+            _ => escape_html(&node.to_string()),
+        })
+        .collect()
+}
+
+/// A table as an html block, for markdown flavors without tables
+///
+/// The block can't contain blank lines, or CommonMark would end it early.
+fn html_table(table: &mdast::Table) -> String {
+    let row = |row: &Node, tag: &str| {
+        let cells = row
+            .children()
+            .into_iter()
+            .flatten()
+            .zip(table.align.iter())
+            .map(|(cell, align)| {
+                let align = match align {
+                    mdast::AlignKind::Left => " align=\"left\"",
+                    mdast::AlignKind::Center => " align=\"center\"",
+                    mdast::AlignKind::Right => " align=\"right\"",
+                    mdast::AlignKind::None => "",
+                };
+                let content = inline_html(cell.children().unwrap_or(&vec![]));
+                format!("<{tag}{align}>{content}</{tag}>\n")
+            })
+            .collect::<String>();
+        format!("<tr>\n{cells}</tr>\n")
+    };
+
+    let mut s = "<table>\n".to_string();
+    if let Some((head, body)) = table.children.split_first() {
+        s += &format!("<thead>\n{}</thead>\n", row(head, "th"));
+        if !body.is_empty() {
+            s += "<tbody>\n";
+            s += &body.iter().map(|r| row(r, "td")).collect::<String>();
+            s += "</tbody>\n";
+        }
+    }
+    s + "</table>\n"
+}
+
 macro_rules! format_mdast {
     ($ctx:ident sep=$sep:expr; s = $mdast:expr, $template:expr, $($arg:expr),*) => {
         format!($template, $($arg),*, s = recursive_mdast_string($ctx, $mdast, $sep))
@@ -126,6 +437,45 @@ macro_rules! format_mdast {
     };
 }
 
+/// The identifier a reference or definition label is matched by, which like in CommonMark ignores
+/// case and collapses whitespace, so `[Foo  Bar]` refers to `[foo bar]: url`
+pub fn normalize_identifier(label: &str) -> String {
+    label
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// A reference written the way it was, unless its text no longer refers to its definition, like
+/// after a pass changed it, when its label is written out
+fn reference(text: &str, kind: ReferenceKind, identifier: &str, label: Option<&str>) -> String {
+    // the parser's identifiers can lose whitespace, so match by the label as it was written
+    let label = label.unwrap_or(identifier);
+    let refers = normalize_identifier(text) == normalize_identifier(label);
+    match kind {
+        ReferenceKind::Shortcut if refers => format!("[{text}]"),
+        ReferenceKind::Collapsed if refers => format!("[{text}][]"),
+        _ => format!("[{text}][{label}]"),
+    }
+}
+
+/// Wrap emphasis in its delimiters, moving whitespace at either end outside of them, since
+/// `* text *` is literal asterisks rather than emphasis
+fn delimit(content: &str, delimiter: &str) -> String {
+    let inner = content.trim();
+    if inner.is_empty() {
+        return content.to_string();
+    }
+    let start = content.len() - content.trim_start().len();
+    let end = content.trim_end().len();
+    format!(
+        "{}{delimiter}{inner}{delimiter}{}",
+        &content[..start],
+        &content[end..]
+    )
+}
+
 fn mdast_string(node: &Node, ctx: Context) -> String {
     match node {
         Node::Root(_) => recursive_mdast_string(ctx, node.children().unwrap(), ""),
@@ -139,17 +489,26 @@ fn mdast_string(node: &Node, ctx: Context) -> String {
         Node::Text(t) => t.value.clone(),
         Node::Paragraph(p) => format_mdast!(ctx; &p.children, "{}\n"),
         Node::List(l) => {
-            let list_indent = Some(ctx.list_indent.map_or(0, |i| i + 1));
+            let list_indent = Some(ctx.list_indent.unwrap_or(0));
             match l.start {
                 None => recursive_mdast_string(
                     Context {
                         list_index: None,
                         list_indent,
+                        list_bullet: list_bullet(l, &ctx.options),
+                        ..ctx
                     },
                     &l.children,
                     "",
                 ),
                 Some(start) => {
+                    let list_delimiter = Some(match ctx.options.ordered_delimiter {
+                        OrderedDelimiter::Dot => '.',
+                        OrderedDelimiter::Paren => ')',
+                        OrderedDelimiter::Preserve => {
+                            source_ordered_delimiter(l, ctx.options.source).unwrap_or('.')
+                        }
+                    });
                     let mut i = start;
                     let mut inc = || {
                         let old = i;
@@ -162,6 +521,8 @@ fn mdast_string(node: &Node, ctx: Context) -> String {
                             Context {
                                 list_index: Some(inc()),
                                 list_indent,
+                                list_delimiter,
+                                ..ctx
                             },
                         ),
                         _ => (
@@ -169,44 +530,103 @@ fn mdast_string(node: &Node, ctx: Context) -> String {
                             Context {
                                 list_index: None,
                                 list_indent,
+                                ..ctx
                             },
                         ),
                     }))
                 }
             }
         }
-        Node::ListItem(li) => format!(
-            "{}{} {}{}",
-            " ".repeat(ctx.list_indent.unwrap_or(0) * 4),
-            match ctx.list_index {
-                Some(i) => format!("{i}."),
-                None => "-".to_string(),
-            },
-            match li.checked {
-                Some(true) => "[x] ",
-                Some(false) => "[ ] ",
-                None => "",
-            },
-            recursive_mdast_string(
-                Context {
-                    list_index: None,
-                    ..ctx
+        Node::ListItem(li) => {
+            let indent = ctx.list_indent.unwrap_or(0);
+            let marker = match ctx.list_index {
+                Some(i) => format!("{i}{}", ctx.list_delimiter.unwrap_or('.')),
+                None => ctx.list_bullet.unwrap_or('-').to_string(),
+            };
+            format!(
+                "{}{marker} {}{}",
+                " ".repeat(indent),
+                match (li.checked, ctx.options.checkbox_symbols) {
+                    (Some(true), Some((_, checked))) => format!("{checked} "),
+                    (Some(false), Some((unchecked, _))) => format!("{unchecked} "),
+                    (Some(true), None) if ctx.options.flavor == RenderFlavor::Commonmark => {
+                        "✓ ".to_string()
+                    }
+                    (Some(false), None) if ctx.options.flavor == RenderFlavor::Commonmark => {
+                        String::new()
+                    }
+                    (Some(true), None) => "[x] ".to_string(),
+                    (Some(false), None) => "[ ] ".to_string(),
+                    (None, _) => String::new(),
                 },
-                &li.children,
-                ""
+                {
+                    // nested lists are indented by four, or past the marker if it's wider, like `100.`
+                    let item_ctx = Context {
+                        list_index: None,
+                        list_indent: Some(indent + (marker.len() + 1).max(4)),
+                        ..ctx
+                    };
+                    let content = li
+                        .children
+                        .iter()
+                        .enumerate()
+                        .map(|(i, n)| match n {
+                            // a rule right under a paragraph would underline it as a setext heading,
+                            // and one left of the content column would end the list
+                            Node::ThematicBreak(_) => format!(
+                                "{}{}{}",
+                                if i > 0 && matches!(li.children[i - 1], Node::Paragraph(_)) {
+                                    "\n"
+                                } else {
+                                    ""
+                                },
+                                " ".repeat(indent + marker.len() + 1),
+                                mdast_string(n, item_ctx)
+                            ),
+                            // only an ordered list starting at 1 can interrupt a paragraph, or it
+                            // would be read as more of the paragraph
+                            Node::List(l)
+                                if i > 0
+                                    && l.start.map_or(false, |start| start != 1)
+                                    && matches!(li.children[i - 1], Node::Paragraph(_)) =>
+                            {
+                                format!("\n{}", mdast_string(n, item_ctx))
+                            }
+                            _ => mdast_string(n, item_ctx),
+                        })
+                        .collect::<String>();
+                    // a non task item starting with a literal checkbox must stay escaped, or it becomes a task
+                    match (li.checked, IS_CHECKBOX.is_match(&content)) {
+                        (None, true) => format!("\\{content}"),
+                        _ => content,
+                    }
+                }
             )
-        ),
-        Node::Code(c) => format!(
-            "```{}\n{}\n```\n",
-            c.lang.as_ref().unwrap_or(&String::new()),
+        }
+        Node::Code(c)
+            if ctx.options.preserve_indented_code && is_indented_code(c, ctx.options.source) =>
+        {
             c.value
-        ),
+                .lines()
+                .map(|line| match line {
+                    "" => "\n".to_string(),
+                    line => format!("    {line}\n"),
+                })
+                .collect()
+        }
+        Node::Code(c) if is_protected_code(c, ctx.options.protected_langs) => {
+            source_code(c, ctx.options.source).unwrap_or_else(|| fenced_code(c))
+        }
+        Node::Code(c) => fenced_code(c),
         Node::InlineCode(c) => {
             let backtick = "`".repeat(count_longest_sequential_chars(&c.value, '`') + 1);
             format!("{}{}{}", backtick, c.value, backtick)
         }
-        Node::Emphasis(e) => format_mdast!(ctx; &e.children, "*{}*"),
-        Node::Strong(s) => format_mdast!(ctx; &s.children, "**{}**"),
+        Node::Emphasis(e) => delimit(&recursive_mdast_string(ctx, &e.children, ""), "*"),
+        Node::Strong(s) => delimit(&recursive_mdast_string(ctx, &s.children, ""), "**"),
+        Node::Delete(d) if ctx.options.flavor == RenderFlavor::Commonmark => {
+            format_mdast!(ctx; &d.children, "<del>{}</del>")
+        }
         Node::Delete(d) => format_mdast!(ctx; &d.children, "~~{}~~"),
         Node::Break(_) => "\n".to_string(),
         Node::Link(l) => {
@@ -218,14 +638,46 @@ fn mdast_string(node: &Node, ctx: Context) -> String {
             }
         }
         Node::Image(i) => format!("![{}]({})", i.alt, i.url),
-        Node::BlockQuote(b) => recursive_mdast_string(ctx, &b.children, "")
+        // blocks are separated by a blank line, like at the root, so a paragraph after a table
+        // isn't taken as another row of it
+        Node::BlockQuote(b) => b
+            .children
+            .iter()
+            .map(|n| mdast_string(n, ctx))
+            .map(|s| format!("{}{}", s, if s.ends_with('\n') { "" } else { "\n" }))
+            .collect::<Vec<String>>()
+            .join("\n")
             .lines()
-            .map(|l| format!("> {l}\n"))
+            .map(|l| {
+                if l.is_empty() {
+                    ">\n".to_string()
+                } else {
+                    format!("> {l}\n")
+                }
+            })
             .collect::<String>(),
         Node::ThematicBreak(_) => "---\n".to_string(),
         Node::Html(h) => h.value.clone(),
-        Node::ImageReference(ir) => format!("![{}][{}]", ir.alt, ir.identifier),
-        Node::Definition(d) => format!("[{}]: {}", d.identifier, d.url),
+        Node::LinkReference(r) => reference(
+            &recursive_mdast_string(ctx, &r.children, ""),
+            r.reference_kind,
+            &r.identifier,
+            r.label.as_deref(),
+        ),
+        Node::ImageReference(ir) => format!(
+            "!{}",
+            reference(
+                &ir.alt,
+                ir.reference_kind,
+                &ir.identifier,
+                ir.label.as_deref()
+            )
+        ),
+        Node::Definition(d) => format!(
+            "[{}]: {}",
+            d.label.as_deref().unwrap_or(&d.identifier),
+            d.url
+        ),
         Node::FootnoteReference(f) => format!("[^{}]", f.identifier),
         Node::FootnoteDefinition(f) => {
             format!(
@@ -242,6 +694,7 @@ fn mdast_string(node: &Node, ctx: Context) -> String {
                     .join("\n")
             )
         }
+        Node::Table(t) if ctx.options.flavor == RenderFlavor::Commonmark => html_table(t),
         Node::Table(t) => {
             let mut s = String::new();
             // A 1d vector of (Cell render, width) pairs, omitting overrun cells
@@ -264,7 +717,7 @@ fn mdast_string(node: &Node, ctx: Context) -> String {
                     for (column_index, cell) in r.children.iter().enumerate().take(t.align.len()) {
                         if let Node::TableCell(c) = cell {
                             let cell_string = recursive_mdast_string(ctx, &c.children, "");
-                            let cell_width = UnicodeWidthStr::width(cell_string.as_str());
+                            let cell_width = display_width(cell_string.as_str());
                             longest[column_index] = longest[column_index].max(cell_width);
                             table_skeleton[row_index * t.align.len() + column_index] =
                                 Some((cell_string, cell_width));
@@ -323,6 +776,7 @@ fn mdast_string(node: &Node, ctx: Context) -> String {
         }
         Node::Math(math) => format!("$$\n{}\n$$", math.value),
         Node::InlineMath(math) => format!("${}$", math.value),
+        Node::Yaml(yaml) if yaml.value.is_empty() => "---\n---\n".to_string(),
         Node::Yaml(yaml) => format!("---\n{}\n---\n", yaml.value),
         _ => panic!("Unexpected node type {node:#?}"),
     }
@@ -380,6 +834,23 @@ mod tests {
         - [x] item 2
         "#
 
+        mdast_checkbox_mid_item_is_literal r#"
+        - text [ ] more
+        - text [x] more
+        - [ ] task with [ ] inside
+        - \[ ] escaped checkbox
+        "#
+
+        mdast_checkbox_at_start_is_task r#"
+        - [ ] open
+        - [x] done
+        - [X] also done
+        "# => r#"
+        - [ ] open
+        - [x] done
+        - [x] also done
+        "#
+
         mdast_deep_nested_list r#"
         - [ ] item 1
             - [ ] item 1.1
@@ -415,6 +886,46 @@ mod tests {
         Here is more with ```a `` backticks inside```.
         "#
 
+        mdast_emphasis_round_trips r#"
+        *a* and **b**, *nested **strong** text*
+        "#
+
+        mdast_spaced_asterisks_stay_literal r#"
+        a * text * b and a ** text ** b
+        "#
+
+        mdast_code_block_meta r#"
+        ```js {1,3-4}
+        let a = 1;
+        ```
+
+        ```rust,ignore
+        fn main() {}
+        ```
+        "#
+
+        mdast_code_block_title_attribute r#"
+        ```python title="hello world.py" linenums="1"
+        print("hi")
+        ```
+        "#
+
+        mdast_paragraph_then_dashes_is_setext_heading r#"
+        para
+        ---
+        "# => r#"
+        ## para
+        "#
+
+        mdast_rule_after_paragraph_in_list_item_has_blank_line r#"
+        - item
+          ***
+        "# => r#"
+        - item
+
+          ---
+        "#
+
         mdast_lists r#"
         # Heading
 
@@ -437,6 +948,60 @@ mod tests {
         - item 1
         "#
 
+        mdast_deep_ordered_in_unordered r#"
+        - a
+            1. b
+                - c
+                    1. d
+                    2. e
+                - f
+            2. g
+        - h
+        "#
+
+        mdast_deep_unordered_in_ordered_with_wide_numbers r#"
+        9. a
+        10. b
+            - c
+
+                8. d
+                9. e
+                10. f
+                    - g
+        11. h
+        "#
+
+        mdast_deep_lists_nest_past_wide_markers r#"
+        - a
+
+            99. b
+                - c
+            100. d
+                 - e
+                     1. f
+                 - g
+        "#
+
+        mdast_deep_mixed_todos r#"
+        - [ ] a
+            1. [x] b
+                - [ ] c
+                    1. d
+                        - [x] e
+            2. [ ] f
+        "#
+
+        mdast_deep_ordered_not_starting_at_one r#"
+        1. a
+            - b
+
+                3. c
+                4. d
+                    - e
+
+                        7. f
+        "#
+
         mdast_links r#"
         [Google](https://www.google.com)
         ![Image](https://via.placeholder.com/150)
@@ -501,6 +1066,20 @@ mod tests {
         | ƒoo | bar |
         "#
 
+        mdast_table_with_zwj_emoji r#"
+        | Family | Bar |
+        | ------ | --- |
+        | 👨‍👩‍👧     | bar |
+        | 👩‍💻 dev | baz |
+        "#
+
+        mdast_table_with_combining_characters r#"
+        | Word  | Bar |
+        | ----- | --- |
+        | Café  | bar |
+        | naïve | baz |
+        "#
+
         mdast_table_with_alignment r#"
         | Left | Center | Right |
         | :--- | :----: | ----: |
@@ -588,6 +1167,63 @@ mod tests {
         | C |   | C |
         "#
 
+        mdast_table_followed_by_paragraph r#"
+        | a | b |
+        | - | - |
+        | c | d |
+
+        after the table
+        "#
+
+        mdast_table_directly_followed_by_paragraph r#"
+        | a | b |
+        | - | - |
+        | c | d |
+        after the table
+        "# => r#"
+        | a               | b |
+        | --------------- | - |
+        | c               | d |
+        | after the table |
+        "#
+
+        mdast_table_at_end_of_file r#"
+        before the table
+
+        | a | b |
+        | - | - |
+        | c | d |
+        "#
+
+        mdast_table_without_trailing_newline "| a |\n| - |\n| b |" => "| a |\n| - |\n| b |\n"
+
+        mdast_table_followed_by_paragraph_in_quote r#"
+        > | a |
+        > | - |
+        > | b |
+        >
+        > after the table
+        "#
+
+        mdast_quote_with_paragraphs r#"
+        > first paragraph
+        >
+        > second paragraph
+        "#
+
+        mdast_tables_separated_by_blank_lines r#"
+        | a |
+        | - |
+
+        # heading
+
+        | b |
+        | - |
+        | c |
+
+        - list
+        "#
+
         mdast_auto_links r#"
         <https://www.google.com>
         <mailto:test@example.com>
@@ -620,6 +1256,22 @@ mod tests {
         [1]: https://via.placeholder.com/150
         "#
 
+        mdast_link_references r#"
+        A [full][Foo  Bar], a [collapsed][], a [long shortcut] and a [Case Differing][foo bar].
+
+        [FOO BAR]: https://example.com/foo
+        [Collapsed]: https://example.com/collapsed
+        [Long  Shortcut]: https://example.com/shortcut
+        "# => r#"
+        A [full][Foo  Bar], a [collapsed][], a [long shortcut] and a [Case Differing][foo bar].
+
+        [FOO BAR]: https://example.com/foo
+
+        [Collapsed]: https://example.com/collapsed
+
+        [Long  Shortcut]: https://example.com/shortcut
+        "#
+
         mdast_frontmatter r#"
         ---
         title: "Hello, world!"
@@ -649,6 +1301,18 @@ mod tests {
         more stuff
         "#
 
+        mdast_horizontal_rule_after_heading r#"
+        # Heading
+        ---
+        text
+        "# => r#"
+        # Heading
+
+        ---
+
+        text
+        "#
+
         mdast_many_horizontal_rules r#"
         # rules!
     
@@ -676,6 +1340,371 @@ mod tests {
         \end{aligned}
         $$
         "#
+
+        mdast_frontmatter_only r#"
+        ---
+        title: stub
+        tags: [a, b]
+        ---
+        "#
+
+        mdast_empty_frontmatter_only r#"
+        ---
+        ---
+        "#
+
+        mdast_frontmatter_only_with_trailing_blank_lines r#"
+        ---
+        title: stub
+        ---
+
+
+        "# => r#"
+        ---
+        title: stub
+        ---
+        "#
+
+        mdast_paragraph_block_id r#"
+        Some *text* with a block id ^abc123
+        "#
+
+        mdast_list_item_block_id r#"
+        - [ ] task ^task-1
+        - item
+            - nested ^nested-2
+        "#
+
+        mdast_block_id_after_list r#"
+        - item
+
+        ^list-id
+        "#
+
+        mdast_highlight r#"
+        ==foo== and a ==multi word highlight==, then more
+        "#
+
+        mdast_highlight_nested_in_emphasis r#"
+        **==bold highlight==** *==emphasis highlight==* ==**bold inside**==
+        "#
+
+        mdast_highlight_around_code_and_links r#"
+        ==see `a == b` and [[note]]== in==tra==word ==[link](https://example.com)==
+        "#
+
+        mdast_highlight_in_heading_and_quote r#"
+        # A ==highlighted== heading
+
+        > ==quoted **bold**==
+        "#
+
+        mdast_highlight_in_list_and_table r#"
+        - [ ] ==task==
+            - ==nested== item
+
+        | ==a== | b   |
+        | ----- | --- |
+        | c     | ==d== |
+        "# => r#"
+        - [ ] ==task==
+            - ==nested== item
+
+        | ==a== | b     |
+        | ----- | ----- |
+        | c     | ==d== |
+        "#
+
+        mdast_seven_hashes_are_text r#"
+        ####### seven hashes
+
+        ###### six hashes
+        "#
+    }
+
+    #[test]
+    fn seven_hashes_stay_a_paragraph() {
+        let render = MdastDocument::parse("####### seven hashes\n").render();
+        let reparsed = MdastDocument::parse(&render);
+
+        assert!(matches!(
+            reparsed.root.children.as_slice(),
+            [Node::Paragraph(_)]
+        ));
+    }
+
+    #[test]
+    fn split_block_ids() {
+        assert_eq!(split_block_id("text ^abc123"), ("text", " ^abc123"));
+        assert_eq!(split_block_id("line\n^my--id"), ("line", "\n^my--id"));
+        assert_eq!(split_block_id("^abc"), ("", "^abc"));
+        assert_eq!(split_block_id("x^abc"), ("x^abc", ""));
+        assert_eq!(split_block_id("^abc text"), ("^abc text", ""));
+    }
+
+    #[test]
+    fn latin1_file_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("café.md");
+        fs::write(&path, b"# Caf\xe9\n\nna\xefve r\xe9sum\xe9\n").unwrap();
+
+        assert!(File::at_path(path.clone(), Encoding::Utf8).is_err());
+
+        let file = File::at_path(path.clone(), Encoding::Latin1).unwrap();
+        assert_eq!(file.content, "# Café\n\nnaïve résumé\n");
+
+        File::atomic_overwrite(&path, file.content, Encoding::Latin1).unwrap();
+        assert_eq!(
+            fs::read(&path).unwrap(),
+            b"# Caf\xe9\n\nna\xefve r\xe9sum\xe9\n"
+        );
+
+        assert!(File::atomic_overwrite(&path, "日本".to_string(), Encoding::Latin1).is_err());
+    }
+
+    #[test]
+    fn mdast_render_commonmark_table() {
+        let document = MdastDocument::parse(indoc! {r#"
+            | Left | Center | **Plain** |
+            | :--- | :----: | --------- |
+            | a    | ~~b~~  | c < d & `<e>` |
+        "#});
+
+        assert_eq!(
+            document.render_with(RenderOptions {
+                flavor: RenderFlavor::Commonmark,
+                ..Default::default()
+            }),
+            indoc! {r#"
+                <table>
+                <thead>
+                <tr>
+                <th align="left">Left</th>
+                <th align="center">Center</th>
+                <th><strong>Plain</strong></th>
+                </tr>
+                </thead>
+                <tbody>
+                <tr>
+                <td align="left">a</td>
+                <td align="center"><del>b</del></td>
+                <td>c &lt; d &amp; <code>&lt;e&gt;</code></td>
+                </tr>
+                </tbody>
+                </table>
+            "#}
+        );
+    }
+
+    #[test]
+    fn mdast_render_commonmark_tasks() {
+        let document = MdastDocument::parse(indoc! {r#"
+            - [ ] open
+            - [x] done
+                - [ ] nested
+            - not a task
+
+            some ~~struck~~ text
+        "#});
+
+        assert_eq!(
+            document.render_with(RenderOptions {
+                flavor: RenderFlavor::Commonmark,
+                ..Default::default()
+            }),
+            indoc! {r#"
+                - open
+                - ✓ done
+                    - nested
+                - not a task
+
+                some <del>struck</del> text
+            "#}
+        );
+    }
+
+    #[test]
+    fn mdast_indented_code_is_preserved_when_asked() {
+        let input = indoc! {r#"
+            intro
+
+                let a = 1;
+
+                let b = 2;
+
+            ```rust
+            fenced();
+            ```
+        "#};
+        let document = MdastDocument::parse(input);
+
+        pretty_assert_eq!(
+            document.render_with(RenderOptions {
+                source: Some(input),
+                preserve_indented_code: true,
+                ..Default::default()
+            }),
+            input
+        );
+        assert!(document.render().starts_with("intro\n\n```\nlet a = 1;"));
+    }
+
+    #[test]
+    fn mdast_protected_code_is_written_as_in_the_source() {
+        let input = indoc! {r#"
+            ~~~  mermaid   {theme: dark}
+            graph TD
+              A-->B
+            ~~~~
+
+            ```Mermaid
+            B-->C
+            ```
+
+            ~~~python
+            print()
+            ~~~
+        "#};
+        let document = MdastDocument::parse(input);
+        let protected_langs = ["mermaid".to_string()];
+
+        pretty_assert_eq!(
+            document.render_with(RenderOptions {
+                source: Some(input),
+                protected_langs: &protected_langs,
+                ..Default::default()
+            }),
+            indoc! {r#"
+            ~~~  mermaid   {theme: dark}
+            graph TD
+              A-->B
+            ~~~~
+
+            ```Mermaid
+            B-->C
+            ```
+
+            ```python
+            print()
+            ```
+            "#}
+        );
+    }
+
+    #[test]
+    fn mdast_emphasis_never_gains_inner_spaces() {
+        let text = |value: &str| {
+            Node::Text(mdast::Text {
+                value: value.to_string(),
+                position: None,
+            })
+        };
+        let paragraph = Node::Paragraph(mdast::Paragraph {
+            children: vec![
+                text("a"),
+                Node::Emphasis(mdast::Emphasis {
+                    children: vec![text(" spaced ")],
+                    position: None,
+                }),
+                text("b"),
+                Node::Strong(mdast::Strong {
+                    children: vec![text("\tstrong")],
+                    position: None,
+                }),
+            ],
+            position: None,
+        });
+        let document = MdastDocument::of(mdast::Root {
+            children: vec![paragraph],
+            position: None,
+        });
+
+        let render = document.render();
+        pretty_assert_eq!(render, "a *spaced* b\t**strong**\n");
+        assert_eq!(MdastDocument::parse(&render).render(), render);
+    }
+
+    #[test]
+    fn mdast_render_with_checkbox_symbols() {
+        let document = MdastDocument::parse(indoc! {r#"
+            - [ ] open
+            - [x] done
+                - [ ] nested
+            - not a task
+        "#});
+
+        pretty_assert_eq!(
+            document.render_with(RenderOptions {
+                checkbox_symbols: Some(("☐", "☑")),
+                ..Default::default()
+            }),
+            indoc! {r#"
+            - ☐ open
+            - ☑ done
+                - ☐ nested
+            - not a task
+            "#}
+        );
+    }
+
+    #[test]
+    fn mdast_consecutive_rules_are_stable_on_reparse() {
+        let count_rules = |document: &MdastDocument| {
+            document
+                .root
+                .children
+                .iter()
+                .filter(|n| matches!(n, Node::ThematicBreak(_)))
+                .count()
+        };
+        let input = indoc! {r#"
+            stuff
+
+            ---
+            ---
+
+            more stuff
+        "#};
+
+        let document = MdastDocument::parse(input);
+        let render = document.render();
+        let reparsed = MdastDocument::parse(&render);
+
+        assert_eq!(count_rules(&document), 2);
+        assert_eq!(count_rules(&reparsed), 2);
+        assert!(!reparsed
+            .root
+            .children
+            .iter()
+            .any(|n| matches!(n, Node::Heading(_))));
+        pretty_assert_eq!(render, reparsed.render());
+    }
+
+    #[test]
+    fn mdast_rule_in_list_item_stays_in_the_item_on_reparse() {
+        let input = indoc! {r#"
+            - item
+              ***
+            - other
+        "#};
+
+        let render = MdastDocument::parse(input).render();
+        let reparsed = MdastDocument::parse(&render);
+
+        match reparsed.root.children.as_slice() {
+            [Node::List(list)] => {
+                assert_eq!(list.children.len(), 2);
+                assert!(matches!(
+                    &list.children[0],
+                    Node::ListItem(li) if matches!(
+                        li.children.as_slice(),
+                        [Node::Paragraph(_), Node::ThematicBreak(_)]
+                    )
+                ));
+            }
+            other => panic!("expected a single list, got {other:?}"),
+        }
+        pretty_assert_eq!(render, reparsed.render());
     }
 
     proptest! {
@@ -692,4 +1721,45 @@ mod tests {
             pretty_assert_eq!(render, render2);
         }
     }
+
+    #[test]
+    fn identifiers_match_ignoring_case_and_whitespace() {
+        for (reference, definition) in [
+            ("Foo", "foo"),
+            ("FOO BAR", "foo bar"),
+            ("foo  bar", "foo bar"),
+            (" foo\n\tbar ", "Foo Bar"),
+            ("ÄÖ", "äö"),
+        ] {
+            assert_eq!(
+                normalize_identifier(reference),
+                normalize_identifier(definition),
+                "{reference:?} should match {definition:?}"
+            );
+        }
+        assert_ne!(
+            normalize_identifier("foo bar"),
+            normalize_identifier("foobar")
+        );
+    }
+
+    #[test]
+    fn reference_whose_text_changed_keeps_its_label() {
+        let mut document = MdastDocument::parse("[Some Note]\n\n[some  note]: note.md\n");
+        let Some(Node::Paragraph(paragraph)) = document.root.children.first_mut() else {
+            panic!("expected a paragraph")
+        };
+        let Some(Node::LinkReference(reference)) = paragraph.children.first_mut() else {
+            panic!("expected a reference, got {:?}", paragraph.children)
+        };
+        reference.children = vec![Node::Text(mdast::Text {
+            value: "Some other note".to_string(),
+            position: None,
+        })];
+
+        assert_eq!(
+            document.render(),
+            "[Some other note][Some Note]\n\n[some  note]: note.md\n"
+        );
+    }
 }
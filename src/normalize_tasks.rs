@@ -0,0 +1,229 @@
+use std::path::PathBuf;
+
+use clap::{Args, ValueEnum};
+use rayon::prelude::*;
+
+use markdown::mdast::{self, Node};
+
+use crate::{
+    markdown_file::MdastDocument,
+    util::{iterate_markdown_files, WalkOptions},
+};
+
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Bullet {
+    #[default]
+    #[value(name = "-")]
+    Dash,
+    #[value(name = "*")]
+    Star,
+    #[value(name = "+")]
+    Plus,
+}
+
+impl Bullet {
+    fn char(self) -> char {
+        match self {
+            Bullet::Dash => '-',
+            Bullet::Star => '*',
+            Bullet::Plus => '+',
+        }
+    }
+}
+
+#[derive(Args, Debug, Default, Clone)]
+pub struct NormalizeTasksOptions {
+    /// The bullet to use for every list of todos
+    #[arg(long, value_enum, default_value_t = Bullet::Dash)]
+    pub bullet: Bullet,
+
+    /// Also use the bullet for lists without any todos, instead of leaving them as written
+    #[arg(long, default_value = "false")]
+    pub all_lists: bool,
+}
+
+/// Whether any item of the list is a todo
+fn has_task(list: &mdast::List) -> bool {
+    list.children
+        .iter()
+        .any(|n| matches!(n, Node::ListItem(li) if li.checked.is_some()))
+}
+
+/// The byte offsets of list item markers and checkboxes to rewrite, with the character to write
+/// at each
+///
+/// The checkbox of a todo is the first `[` after its item's marker.
+fn task_edits(
+    nodes: &[Node],
+    source: &str,
+    options: &NormalizeTasksOptions,
+    edits: &mut Vec<(usize, u8)>,
+) {
+    for node in nodes {
+        if let Node::List(list) = node {
+            let bullet = (!list.ordered && (options.all_lists || has_task(list)))
+                .then_some(options.bullet.char() as u8);
+            for item in &list.children {
+                let (Node::ListItem(li), Some(position)) = (item, item.position()) else {
+                    continue;
+                };
+                // nested items can start at their indentation rather than their marker
+                let start = position.start.offset
+                    + source[position.start.offset..]
+                        .bytes()
+                        .take_while(|b| matches!(b, b' ' | b'\t'))
+                        .count();
+                if let Some(bullet) = bullet {
+                    if matches!(source.as_bytes().get(start), Some(b'-' | b'*' | b'+')) {
+                        edits.push((start, bullet));
+                    }
+                }
+                if li.checked == Some(true) {
+                    let checkbox = source[start..]
+                        .find('[')
+                        .map(|index| start + index + 1)
+                        .filter(|&index| source.as_bytes().get(index) == Some(&b'X'));
+                    edits.extend(checkbox.map(|index| (index, b'x')));
+                }
+            }
+        }
+        if let Some(children) = node.children() {
+            task_edits(children, source, options, edits);
+        }
+    }
+}
+
+/// Give the todo lists of the content one bullet and `[ ]`/`[x]` checkboxes, leaving every other
+/// byte as written
+fn normalize_content(content: &str, options: &NormalizeTasksOptions) -> String {
+    let mut edits = vec![];
+    task_edits(
+        &MdastDocument::parse(content).root.children,
+        content,
+        options,
+        &mut edits,
+    );
+    let mut bytes = content.as_bytes().to_vec();
+    for (offset, byte) in edits {
+        bytes[offset] = byte;
+    }
+    // only ascii bytes are replaced with others, so the content stays utf-8
+    String::from_utf8(bytes).unwrap()
+}
+
+/// Rewrite every list of todos in the vault with the same bullet and `[ ]`/`[x]` checkboxes
+pub fn normalize_tasks<'a>(
+    vault_path: &PathBuf,
+    walk: &WalkOptions,
+    options: &'a NormalizeTasksOptions,
+) -> impl ParallelIterator<Item = (PathBuf, String)> + 'a {
+    iterate_markdown_files(vault_path, walk).filter_map(|file| {
+        let normalized = normalize_content(&file.content, options);
+        if file.content == normalized {
+            None
+        } else {
+            Some((file.path, normalized))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    macro_rules! test_normalize_tasks {
+        ($($name:ident $options:expr; $input:expr => $expected:expr)*) => {
+            $(
+                #[test]
+                fn $name() {
+                    let input = indoc!($input);
+                    let options = $options;
+                    assert_eq!(indoc!($expected), normalize_content(input, &options));
+                }
+            )*
+        }
+    }
+
+    test_normalize_tasks! {
+        mixed_task_markers_converge NormalizeTasksOptions::default(); r#"
+        - [ ] dash
+
+        * [X] star
+
+        + [ ] plus
+            * [x] nested star
+
+        * not a task
+        * also not a task
+        "# => r#"
+        - [ ] dash
+
+        - [x] star
+
+        - [ ] plus
+            - [x] nested star
+
+        * not a task
+        * also not a task
+        "#
+
+        task_markers_converge_on_configured_bullet NormalizeTasksOptions { bullet: Bullet::Star, ..Default::default() }; r#"
+        - [ ] dash
+            - nested
+        + not a task
+        "# => r#"
+        * [ ] dash
+            - nested
+        + not a task
+        "#
+
+        all_lists_are_normalized NormalizeTasksOptions { all_lists: true, ..Default::default() }; r#"
+        * [ ] task
+        + not a task
+            * nested
+        "# => r#"
+        - [ ] task
+        - not a task
+            - nested
+        "#
+
+        only_task_lines_change NormalizeTasksOptions::default(); r#"
+        #  odd   spacing
+
+
+        |a|b|
+        |-|-|
+        |1|2|
+
+        * [X] done
+        *   [ ] spaced  todo
+        ~~~
+        * [X] in code
+        ~~~
+        "# => r#"
+        #  odd   spacing
+
+
+        |a|b|
+        |-|-|
+        |1|2|
+
+        - [x] done
+        -   [ ] spaced  todo
+        ~~~
+        * [X] in code
+        ~~~
+        "#
+
+        ordered_tasks_are_untouched NormalizeTasksOptions::default(); r#"
+        1) [ ] first
+        2) [x] second
+        "# => r#"
+        1) [ ] first
+        2) [x] second
+        "#
+    }
+}
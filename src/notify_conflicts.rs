@@ -1,12 +1,192 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, thread, time::Duration};
 
+use clap::{Args, ValueEnum};
+use lazy_static::lazy_static;
 use ntfy::{Dispatcher, Payload};
+use rayon::prelude::*;
+use regex::Regex;
+use unicode_segmentation::UnicodeSegmentation;
 use url::Url;
 use walkdir::WalkDir;
 
-use crate::util::is_sync_conflict;
+use crate::{
+    error::{NotifyError, Result},
+    util::is_sync_conflict,
+};
 
-pub fn notify_conflicts(vault_path: &PathBuf, ntfy_url: Url, topic: String) -> Option<i32> {
+lazy_static! {
+    static ref STATUS_CODE: Regex = Regex::new(r": status code (\d{3})$").unwrap();
+}
+
+fn parse_url(arg: &str) -> Result<Url> {
+    Ok(Url::parse(arg)?)
+}
+
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NotifyPriority {
+    Min,
+    Low,
+    Default,
+    #[default]
+    High,
+    Max,
+}
+
+impl From<NotifyPriority> for ntfy::Priority {
+    fn from(priority: NotifyPriority) -> Self {
+        match priority {
+            NotifyPriority::Min => ntfy::Priority::Min,
+            NotifyPriority::Low => ntfy::Priority::Low,
+            NotifyPriority::Default => ntfy::Priority::Default,
+            NotifyPriority::High => ntfy::Priority::High,
+            NotifyPriority::Max => ntfy::Priority::Max,
+        }
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct NotifyOptions {
+    /// The ntfy.sh url to send the notification to, repeat it to send to several servers
+    #[arg(short, long)]
+    #[clap(value_parser = parse_url, default_value = "https://ntfy.sh")]
+    pub ntfy_url: Vec<Url>,
+
+    /// The topic to send the notification to, repeat it to send to several topics on every server
+    #[arg(short, long, required = true)]
+    pub topic: Vec<String>,
+
+    /// A url to open when the notification is tapped, like `obsidian://open?vault=notes`
+    #[arg(long)]
+    #[clap(value_parser = parse_url)]
+    pub click_url: Option<Url>,
+
+    /// The priority of the notification
+    #[arg(long, value_enum, default_value_t = NotifyPriority::High)]
+    pub priority: NotifyPriority,
+
+    /// Retry sending this many times, waiting twice as long after each failure
+    #[arg(long, default_value = "0")]
+    pub notify_retries: u32,
+
+    /// Cut the list of conflicts short to fit in this many bytes, ending it with how many were
+    /// left out, since ntfy turns longer messages into attachments
+    #[arg(long, value_name = "BYTES", default_value = "4096")]
+    pub max_body_bytes: usize,
+}
+
+/// Somewhere to send the notification
+trait Sink: Sync {
+    /// The topic the notification is sent to
+    fn topic(&self) -> &str;
+
+    /// Where the notification goes, for reporting failures
+    fn destination(&self) -> String;
+
+    fn send(&self, payload: &Payload) -> Result<(), NotifyError>;
+}
+
+/// A topic on an ntfy server
+struct NtfySink<'a> {
+    url: &'a Url,
+    dispatcher: &'a Dispatcher,
+    topic: &'a str,
+}
+
+impl Sink for NtfySink<'_> {
+    fn topic(&self) -> &str {
+        self.topic
+    }
+
+    fn destination(&self) -> String {
+        format!("{} on {}", self.topic, self.url)
+    }
+
+    fn send(&self, payload: &Payload) -> Result<(), NotifyError> {
+        self.dispatcher
+            .send(payload)
+            .map_err(|e| notify_error(&e.to_string()))
+    }
+}
+
+/// The error of a failed send, by the status the server answered with
+///
+/// ntfy only gives the status of an error response in its message, which ends with the
+/// `<url>: status code <code>` of the http client.
+fn notify_error(message: &str) -> NotifyError {
+    STATUS_CODE
+        .captures(message)
+        .and_then(|status| status[1].parse().ok())
+        .map_or_else(
+            || NotifyError::Unanswered(message.to_string()),
+            NotifyError::Status,
+        )
+}
+
+/// The conflicts one per line, cut short at a whole character to fit in `max_bytes` with
+/// `... (+N more)` after, where N counts the conflicts that weren't listed in full
+fn truncated_body(sync_conflicts: &[String], max_bytes: usize) -> String {
+    let body = sync_conflicts.join("\n");
+    if body.len() <= max_bytes {
+        return body;
+    }
+    // leave room for the longest the count could be, so the cut doesn't depend on it, and for
+    // the newline after the last conflict if the cut is right after it
+    let room = format!("\n... (+{} more)", sync_conflicts.len()).len();
+    let mut end = body
+        .grapheme_indices(true)
+        .map(|(start, grapheme)| start + grapheme.len())
+        .take_while(|&end| end <= max_bytes.saturating_sub(room))
+        .last()
+        .unwrap_or(0);
+    if end > 0 && body[end..].starts_with('\n') {
+        end += 1;
+    }
+    let listed = body[..end].matches('\n').count();
+    format!(
+        "{}... (+{} more)",
+        &body[..end],
+        sync_conflicts.len() - listed
+    )
+}
+
+/// The notification for a list of sync conflicts
+fn conflicts_payload(options: &NotifyOptions, topic: &str, sync_conflicts: &[String]) -> Payload {
+    let payload = Payload::new(topic)
+        .title(format!("{} sync conflicts found", sync_conflicts.len()))
+        .message(truncated_body(sync_conflicts, options.max_body_bytes))
+        .priority(options.priority.into());
+    match &options.click_url {
+        Some(url) => payload.click(url.clone()),
+        None => payload,
+    }
+}
+
+/// Whether a failed send is worth retrying, a 4xx status means the server rejected the
+/// notification itself and would reject it again
+fn is_transient(error: &NotifyError) -> bool {
+    !matches!(error, NotifyError::Status(400..=499))
+}
+
+fn send_with_retries(
+    send: impl Fn() -> Result<(), NotifyError>,
+    retries: u32,
+    backoff: Duration,
+) -> Result<()> {
+    let mut attempt = 0;
+    loop {
+        match send() {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                let delay = backoff.saturating_mul(2u32.saturating_pow(attempt));
+                eprintln!("Failed to send notification, retrying in {delay:?}: {e}");
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            result => return Ok(result?),
+        }
+    }
+}
+
+pub fn notify_conflicts(vault_path: &PathBuf, options: &NotifyOptions) -> Option<i32> {
     let sync_conflicts = WalkDir::new(vault_path.clone())
         .into_iter()
         .map(Result::unwrap)
@@ -26,19 +206,282 @@ pub fn notify_conflicts(vault_path: &PathBuf, ntfy_url: Url, topic: String) -> O
         return None;
     }
 
-    match Dispatcher::builder(ntfy_url).build().unwrap().send(
-        &Payload::new(topic)
-            .title(format!("{} sync conflicts found", sync_conflicts.len()))
-            .message(sync_conflicts.join("\n"))
-            .priority(ntfy::Priority::High),
-    ) {
-        Ok(_) => {
-            println!("Successfully sent notification");
-            None
+    let dispatchers = options
+        .ntfy_url
+        .iter()
+        .map(|url| (url, Dispatcher::builder(url.clone()).build().unwrap()))
+        .collect::<Vec<_>>();
+    let sinks = dispatchers
+        .iter()
+        .flat_map(|(url, dispatcher)| {
+            options.topic.iter().map(move |topic| NtfySink {
+                url,
+                dispatcher,
+                topic,
+            })
+        })
+        .collect::<Vec<_>>();
+    send_to_sinks(&sinks, options, &sync_conflicts, Duration::from_secs(1))
+}
+
+/// Send the notification to every sink in parallel, each with its own retries, exiting with 2 if
+/// any of them failed
+fn send_to_sinks(
+    sinks: &[impl Sink],
+    options: &NotifyOptions,
+    sync_conflicts: &[String],
+    backoff: Duration,
+) -> Option<i32> {
+    let failures = sinks
+        .par_iter()
+        .map(|sink| {
+            let payload = conflicts_payload(options, sink.topic(), sync_conflicts);
+            send_with_retries(|| sink.send(&payload), options.notify_retries, backoff)
+                .map_err(|e| println!("{e} to {}", sink.destination()))
+        })
+        .filter(Result::is_err)
+        .count();
+
+    if failures == 0 {
+        println!("Successfully sent notification");
+        None
+    } else {
+        println!("Failed to send {failures} of {} notifications", sinks.len());
+        Some(2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::error::Error;
+
+    fn options(args: &[&str]) -> NotifyOptions {
+        #[derive(clap::Parser)]
+        struct Cli {
+            #[command(flatten)]
+            options: NotifyOptions,
+        }
+        <Cli as clap::Parser>::parse_from([&["notify", "--topic", "topic"], args].concat()).options
+    }
+
+    #[test]
+    fn payload_opens_click_url() {
+        let payload = conflicts_payload(
+            &options(&["--click-url", "obsidian://open?vault=notes"]),
+            "topic",
+            &["a.sync-conflict-20230101-120000-ABCDEF.md".to_string()],
+        );
+        let json = serde_json::to_value(&payload).unwrap();
+
+        assert_eq!(json["click"], "obsidian://open?vault=notes");
+        assert_eq!(json["title"], "1 sync conflicts found");
+    }
+
+    #[test]
+    fn payload_without_click_url() {
+        let payload = conflicts_payload(&options(&[]), "topic", &[]);
+        let json = serde_json::to_value(&payload).unwrap();
+
+        assert!(json.get("click").map_or(true, |click| click.is_null()));
+    }
+
+    #[test]
+    fn payload_has_chosen_priority() {
+        let priority = |args: &[&str]| {
+            serde_json::to_value(conflicts_payload(&options(args), "topic", &[])).unwrap()
+                ["priority"]
+                .clone()
+        };
+
+        assert_eq!(
+            priority(&[]),
+            serde_json::to_value(ntfy::Priority::High).unwrap()
+        );
+        assert_eq!(
+            priority(&["--priority", "low"]),
+            serde_json::to_value(ntfy::Priority::Low).unwrap()
+        );
+    }
+
+    #[test]
+    fn short_bodies_are_not_truncated() {
+        let conflicts = ["a.md".to_string(), "b.md".to_string()];
+        assert_eq!(truncated_body(&conflicts, 9), "a.md\nb.md");
+    }
+
+    #[test]
+    fn truncation_keeps_whole_characters() {
+        // the thumbs up and its skin tone are one character made of two code points
+        let conflicts = ["notes 👍🏽 日本.md".to_string(), "b.md".to_string()];
+        for max_bytes in 0..29 {
+            let body = truncated_body(&conflicts, max_bytes);
+            let cut = body.strip_suffix("... (+2 more)").unwrap();
+            assert!(max_bytes < 14 || body.len() <= max_bytes, "{body}");
+            assert!(
+                conflicts[0].starts_with(cut) && !cut.ends_with('👍'),
+                "{max_bytes}: {body}"
+            );
         }
-        Err(e) => {
-            println!("Failed to send notification: {e}");
+        assert_eq!(truncated_body(&conflicts, 25), "notes ... (+2 more)");
+        assert_eq!(truncated_body(&conflicts, 28), "notes 👍🏽... (+2 more)");
+    }
+
+    #[test]
+    fn truncation_counts_the_conflicts_left_out() {
+        let conflicts = ["a.md", "b.md", "c.md", "d.md", "e.md", "f.md"].map(str::to_string);
+        assert_eq!(truncated_body(&conflicts, 18), "a.md\n... (+5 more)");
+        assert_eq!(truncated_body(&conflicts, 22), "a.md\nb.m... (+5 more)");
+        assert_eq!(truncated_body(&conflicts, 23), "a.md\nb.md\n... (+4 more)");
+        assert_eq!(truncated_body(&conflicts, 14), "... (+6 more)");
+        assert_eq!(truncated_body(&conflicts, 29), conflicts.join("\n"));
+    }
+
+    #[test]
+    fn payload_body_is_truncated() {
+        let conflicts = (0..100)
+            .map(|n| format!("{n}.sync-conflict-20230101-120000-ABCDEF.md"))
+            .collect::<Vec<_>>();
+        let payload =
+            conflicts_payload(&options(&["--max-body-bytes", "200"]), "topic", &conflicts);
+        let json = serde_json::to_value(&payload).unwrap();
+        let message = json["message"].as_str().unwrap();
+
+        assert!(message.len() <= 200);
+        assert!(message.ends_with("... (+96 more)"), "{message}");
+    }
+
+    #[test]
+    fn transient_failures_are_retried() {
+        let attempts = std::cell::Cell::new(0);
+        let result = send_with_retries(
+            || {
+                attempts.set(attempts.get() + 1);
+                match attempts.get() {
+                    1 | 2 => Err(NotifyError::Unanswered(
+                        "connection reset by peer".to_string(),
+                    )),
+                    _ => Ok(()),
+                }
+            },
+            3,
+            Duration::ZERO,
+        );
+
+        assert!(result.is_ok());
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[test]
+    fn retries_run_out() {
+        let attempts = std::cell::Cell::new(0);
+        let result = send_with_retries(
+            || {
+                attempts.set(attempts.get() + 1);
+                Err(NotifyError::Unanswered("timed out".to_string()))
+            },
+            2,
+            Duration::ZERO,
+        );
+
+        assert!(matches!(
+            result,
+            Err(Error::Notify(NotifyError::Unanswered(e))) if e == "timed out"
+        ));
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        let attempts = std::cell::Cell::new(0);
+        let result = send_with_retries(
+            || {
+                attempts.set(attempts.get() + 1);
+                Err(NotifyError::Status(403))
+            },
+            3,
+            Duration::ZERO,
+        );
+
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[test]
+    fn only_the_status_code_decides_a_client_error() {
+        assert_eq!(
+            notify_error("https://ntfy.sh/: status code 403"),
+            NotifyError::Status(403)
+        );
+        for message in [
+            "https://ntfy.sh:443/404-alerts: status code 503",
+            "https://ntfy.sh:443/alerts: Connection Failed: Connect error: connection refused",
+            "https://ntfy.sh/alerts?id=418: timed out reading response",
+        ] {
+            assert!(is_transient(&notify_error(message)), "{message}");
+        }
+    }
+
+    #[test]
+    fn invalid_urls_are_url_errors() {
+        assert!(matches!(parse_url("not a url"), Err(Error::Url(_))));
+    }
+
+    /// A sink that keeps every payload it's sent, and fails if it's down
+    struct MockSink {
+        topic: String,
+        down: bool,
+        received: std::sync::Mutex<Vec<Payload>>,
+    }
+
+    impl Sink for MockSink {
+        fn topic(&self) -> &str {
+            &self.topic
+        }
+
+        fn destination(&self) -> String {
+            self.topic.clone()
+        }
+
+        fn send(&self, payload: &Payload) -> Result<(), NotifyError> {
+            self.received.lock().unwrap().push(payload.clone());
+            if self.down {
+                Err(NotifyError::Unanswered("connection refused".to_string()))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn every_sink_is_sent_the_notification() {
+        let sink = |topic: &str, down| MockSink {
+            topic: topic.to_string(),
+            down,
+            received: Default::default(),
+        };
+        let conflicts = ["a.sync-conflict-20230101-120000-ABCDEF.md".to_string()];
+        let options = options(&["--topic", "team"]);
+        assert_eq!(options.topic, vec!["topic", "team"]);
+
+        let sinks = [sink("phone", false), sink("team", false)];
+        assert_eq!(
+            send_to_sinks(&sinks, &options, &conflicts, Duration::ZERO),
+            None
+        );
+
+        let sinks = [sink("phone", false), sink("team", true)];
+        assert_eq!(
+            send_to_sinks(&sinks, &options, &conflicts, Duration::ZERO),
             Some(2)
+        );
+        for sink in &sinks {
+            let received = sink.received.lock().unwrap();
+            assert_eq!(received.len(), 1);
+            let json = serde_json::to_value(&received[0]).unwrap();
+            assert_eq!(json["topic"], sink.topic);
+            assert_eq!(json["message"], conflicts[0]);
         }
     }
 }
@@ -0,0 +1,393 @@
+use std::path::Path;
+
+use markdown::mdast::Node;
+use regex::Regex;
+
+use crate::{
+    error::{Error, Result},
+    frontmatter,
+    markdown_file::MdastDocument,
+    util::{markdown_contains_heading, TagMatcher, WalkOptions},
+};
+
+/// A `--query` selecting files, like `tag:todo and not path:Templates/*`
+///
+/// Terms are `tag:TAG`, `heading:PATTERN`, `path:GLOB` and `frontmatter.KEY=VALUE`, combined with
+/// `and`, `or`, `not` and parentheses. `not` binds tightest and `or` loosest. Double quotes keep
+/// spaces and parentheses in a term, like `heading:"(TODO|Tasks)"` or `frontmatter.title="My Note"`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    /// Files with the tag, found the same way as for other commands
+    Tag(String),
+    /// Files with a heading matching the regex
+    Heading(String),
+    /// Files whose path relative to the vault, or one of its folders, matches the glob
+    Path(String),
+    /// Files whose frontmatter has the top level field set to the value
+    Frontmatter {
+        key: String,
+        value: String,
+    },
+    Not(Box<Query>),
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+}
+
+/// A compiled query, taking a file's path relative to the vault and its content
+pub type Predicate = Box<dyn Fn(&Path, &str) -> bool + Send + Sync>;
+
+pub fn parse_query(arg: &str) -> Result<Query> {
+    let tokens = tokenize(arg)?;
+    let mut parser = Parser {
+        tokens: &tokens,
+        position: 0,
+    };
+    let query = parser.or()?;
+    match parser.tokens.get(parser.position) {
+        None => Ok(query),
+        Some(token) => Err(Error::Parse(format!(
+            "unexpected `{}` in query",
+            token.text
+        ))),
+    }
+}
+
+/// A word or parenthesis of a query, quoted if any of it was, so it's never taken for an operator
+#[derive(Debug, PartialEq, Eq)]
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+/// Split a query into words and parentheses, keeping what's in double quotes in its word
+fn tokenize(query: &str) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut word: Option<Token> = None;
+    let mut chars = query.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                let word = word.get_or_insert_with(|| Token {
+                    text: String::new(),
+                    quoted: true,
+                });
+                word.quoted = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => word.text.push(c),
+                        None => return Err(Error::Parse("unclosed `\"` in query".to_string())),
+                    }
+                }
+            }
+            '(' | ')' => {
+                tokens.extend(word.take());
+                tokens.push(Token {
+                    text: c.to_string(),
+                    quoted: false,
+                });
+            }
+            c if c.is_whitespace() => tokens.extend(word.take()),
+            c => word
+                .get_or_insert_with(|| Token {
+                    text: String::new(),
+                    quoted: false,
+                })
+                .text
+                .push(c),
+        }
+    }
+    tokens.extend(word);
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+}
+
+impl Parser<'_> {
+    /// The next token if it's an unquoted operator or parenthesis
+    fn peek(&self) -> Option<&str> {
+        self.tokens
+            .get(self.position)
+            .filter(|token| !token.quoted)
+            .map(|token| token.text.as_str())
+    }
+
+    fn next(&mut self) -> Option<&Token> {
+        self.position += 1;
+        self.tokens.get(self.position - 1)
+    }
+
+    fn or(&mut self) -> Result<Query> {
+        let mut query = self.and()?;
+        while self.peek() == Some("or") {
+            self.next();
+            query = Query::Or(Box::new(query), Box::new(self.and()?));
+        }
+        Ok(query)
+    }
+
+    fn and(&mut self) -> Result<Query> {
+        let mut query = self.not()?;
+        while self.peek() == Some("and") {
+            self.next();
+            query = Query::And(Box::new(query), Box::new(self.not()?));
+        }
+        Ok(query)
+    }
+
+    fn not(&mut self) -> Result<Query> {
+        if self.peek() == Some("not") {
+            self.next();
+            return Ok(Query::Not(Box::new(self.not()?)));
+        }
+        self.term()
+    }
+
+    fn term(&mut self) -> Result<Query> {
+        let (token, quoted) = self
+            .next()
+            .map(|token| (token.text.clone(), token.quoted))
+            .ok_or_else(|| Error::Parse("query ended early".to_string()))?;
+        if token == "(" && !quoted {
+            let query = self.or()?;
+            return match self.next() {
+                Some(Token {
+                    text,
+                    quoted: false,
+                }) if text == ")" => Ok(query),
+                _ => Err(Error::Parse("expected `)` in query".to_string())),
+            };
+        }
+
+        if let Some(tag) = token.strip_prefix("tag:") {
+            let tag = tag.trim_start_matches('#');
+            TagMatcher::new(tag, &WalkOptions::default())?;
+            Ok(Query::Tag(tag.to_string()))
+        } else if let Some(pattern) = token.strip_prefix("heading:") {
+            markdown_contains_heading(pattern)?;
+            Ok(Query::Heading(pattern.to_string()))
+        } else if let Some(glob) = token.strip_prefix("path:") {
+            Ok(Query::Path(glob.to_string()))
+        } else if let Some((key, value)) = token
+            .strip_prefix("frontmatter.")
+            .and_then(|field| field.split_once('='))
+        {
+            Ok(Query::Frontmatter {
+                key: key.to_string(),
+                value: value.to_string(),
+            })
+        } else {
+            Err(Error::Parse(format!(
+                "unknown query term `{token}`, expected tag:, heading:, path: or frontmatter.KEY="
+            )))
+        }
+    }
+}
+
+/// A regex for a glob where `*` and `?` stay within a folder and `**` matches across them
+fn glob_regex(glob: &str) -> Regex {
+    let mut pattern = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                pattern.push_str(".*");
+            }
+            '*' => pattern.push_str("[^/]*"),
+            '?' => pattern.push_str("[^/]"),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    Regex::new(&pattern).expect("every glob character is escaped or translated")
+}
+
+impl Query {
+    /// Compile the query for a walk, so tags are matched the way `--tag-anywhere` says
+    pub fn predicate(&self, walk: &WalkOptions) -> Predicate {
+        match self {
+            Query::Tag(tag) => {
+                let matcher = TagMatcher::new(tag, walk).expect("checked when parsed");
+                Box::new(move |_, content| matcher.is_match(content))
+            }
+            Query::Heading(pattern) => {
+                let heading = markdown_contains_heading(pattern).expect("checked when parsed");
+                Box::new(move |_, content| heading.is_match(content))
+            }
+            Query::Path(glob) => {
+                let glob = glob_regex(glob);
+                Box::new(move |path, _| {
+                    path.ancestors()
+                        .filter(|folder| !folder.as_os_str().is_empty())
+                        .any(|folder| {
+                            let folder = folder
+                                .components()
+                                .map(|c| c.as_os_str().to_string_lossy())
+                                .collect::<Vec<_>>()
+                                .join("/");
+                            glob.is_match(&folder)
+                        })
+                })
+            }
+            Query::Frontmatter { key, value } => {
+                let (key, value) = (key.clone(), value.clone());
+                Box::new(move |_, content| {
+                    match MdastDocument::parse(content).root.children.first() {
+                        Some(Node::Yaml(yaml)) => {
+                            frontmatter::field(&yaml.value, &key).as_deref() == Some(&value)
+                        }
+                        _ => false,
+                    }
+                })
+            }
+            Query::Not(query) => {
+                let query = query.predicate(walk);
+                Box::new(move |path, content| !query(path, content))
+            }
+            Query::And(a, b) => {
+                let (a, b) = (a.predicate(walk), b.predicate(walk));
+                Box::new(move |path, content| a(path, content) && b(path, content))
+            }
+            Query::Or(a, b) => {
+                let (a, b) = (a.predicate(walk), b.predicate(walk));
+                Box::new(move |path, content| a(path, content) || b(path, content))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+    use rayon::prelude::*;
+
+    use crate::util::{iterate_markdown_files, vault_with};
+
+    fn selected(query: &str) -> Vec<PathBuf> {
+        let vault = vault_with(&[
+            (
+                "projects/garden.md",
+                indoc! {r#"
+                ---
+                status: active
+                ---
+                #todo
+
+                - [ ] water
+                "#},
+            ),
+            (
+                "projects/house.md",
+                indoc! {r#"
+                ---
+                status: "done"
+                ---
+                #todo
+                "#},
+            ),
+            ("Templates/todo.md", "#todo\n\n# Tasks\n"),
+            ("Templates/nested/daily.md", "# Tasks\n"),
+            ("inbox.md", "# Tasks\n\nstuff\n"),
+        ]);
+
+        let walk = WalkOptions {
+            query: Some(parse_query(query).unwrap()),
+            ..Default::default()
+        };
+        let mut files = iterate_markdown_files(&vault.path().to_path_buf(), &walk)
+            .map(|f| f.path.strip_prefix(vault.path()).unwrap().to_path_buf())
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn queries_are_parsed_with_precedence() {
+        let tag = |tag: &str| Box::new(Query::Tag(tag.to_string()));
+        assert_eq!(
+            parse_query("tag:a or not tag:b and tag:c").unwrap(),
+            Query::Or(
+                tag("a"),
+                Box::new(Query::And(Box::new(Query::Not(tag("b"))), tag("c")))
+            )
+        );
+        assert_eq!(
+            parse_query("(tag:a or tag:b) and tag:c").unwrap(),
+            Query::And(Box::new(Query::Or(tag("a"), tag("b"))), tag("c"))
+        );
+    }
+
+    #[test]
+    fn invalid_queries_are_parse_errors() {
+        for query in [
+            "",
+            "tag:a and",
+            "(tag:a",
+            "tag:a tag:b",
+            "title:a",
+            "heading:(",
+            "heading:\"(\"",
+            "tag:\"a",
+            "\"(\" tag:a )",
+        ] {
+            assert!(parse_query(query).is_err(), "{query}");
+        }
+    }
+
+    #[test]
+    fn quoted_terms_keep_spaces_and_parentheses() {
+        assert_eq!(
+            parse_query(r#"heading:"(TODO|Tasks)" and not frontmatter.title="My Note""#).unwrap(),
+            Query::And(
+                Box::new(Query::Heading("(TODO|Tasks)".to_string())),
+                Box::new(Query::Not(Box::new(Query::Frontmatter {
+                    key: "title".to_string(),
+                    value: "My Note".to_string(),
+                })))
+            )
+        );
+        // a quoted operator is a term, not an operator
+        assert!(parse_query(r#"tag:a "and" tag:b"#).is_err());
+        assert_eq!(
+            selected(r#"heading:"(TODO|Tasks)" and not path:"Templates/**""#),
+            paths(&["inbox.md"])
+        );
+    }
+
+    #[test]
+    fn compound_queries_select_files() {
+        assert_eq!(
+            selected("tag:todo and not path:Templates/*"),
+            paths(&["projects/garden.md", "projects/house.md"])
+        );
+        assert_eq!(
+            selected("tag:todo and frontmatter.status=active"),
+            paths(&["projects/garden.md"])
+        );
+        assert_eq!(
+            selected("heading:Tasks and not (path:Templates/** or path:inbox.md)"),
+            paths(&[])
+        );
+        assert_eq!(
+            selected("frontmatter.status=done or (heading:Tasks and path:Templates)"),
+            paths(&[
+                "Templates/nested/daily.md",
+                "Templates/todo.md",
+                "projects/house.md"
+            ])
+        );
+    }
+}
@@ -0,0 +1,324 @@
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use lazy_static::lazy_static;
+use markdown::mdast::Node;
+use rayon::prelude::*;
+use regex::{Captures, Regex};
+use replace_with::replace_with_or_abort;
+
+use crate::{
+    format_files::{map_text, map_urls},
+    frontmatter,
+    markdown_file::{MdastDocument, OrderedDelimiter, RenderOptions},
+    util::{is_relative_url, iterate_markdown_files, WalkOptions},
+};
+
+lazy_static! {
+    /// `[[target#heading|alias]]`, with the heading or block and the alias optional
+    static ref WIKILINK: Regex =
+        Regex::new(r"\[\[(?P<target>[^\[\]|#^]+)(?P<rest>(?:[#^][^\[\]|]*)?(?:\|[^\[\]]*)?)\]\]")
+            .unwrap();
+}
+
+/// The files to rename, and the changes to the notes linking to them
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RenamePlan {
+    pub renames: Vec<(PathBuf, PathBuf)>,
+    pub changes: Vec<(PathBuf, String)>,
+}
+
+/// The title of a note, from the `title` field of its frontmatter or else its first H1
+fn note_title(content: &str) -> Option<String> {
+    let document = MdastDocument::parse(content);
+    let title = match document.root.children.first() {
+        Some(Node::Yaml(yaml)) => frontmatter::field(&yaml.value, "title"),
+        _ => None,
+    };
+    title.filter(|title| !title.is_empty()).or_else(|| {
+        document.root.children.iter().find_map(|node| match node {
+            Node::Heading(heading) if heading.depth == 1 => Some(node.to_string()),
+            _ => None,
+        })
+    })
+}
+
+/// The title in lowercase, with every run of other characters than letters and numbers
+/// replaced by a single `-`
+pub fn slug(title: &str) -> String {
+    title
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// The path relative to the vault with `/` separators and without its extension, as wikilinks
+/// write it
+fn link_name(vault_path: &Path, path: &Path) -> String {
+    path.strip_prefix(vault_path)
+        .unwrap_or(path)
+        .with_extension("")
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Resolve `.` and `..` in a path without touching the file system
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Every note to rename to the slug of its title, skipping notes whose new name would clash with
+/// another note or an existing file
+fn plan_renames(vault_path: &Path, walk: &WalkOptions) -> Vec<(PathBuf, PathBuf)> {
+    let mut by_target: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+    for (from, to) in iterate_markdown_files(&vault_path.to_path_buf(), walk)
+        .filter_map(|file| {
+            let slug = slug(&note_title(&file.content)?);
+            let extension = file.path.extension().unwrap_or_default().to_string_lossy();
+            let to = file.path.with_file_name(format!("{slug}.{extension}"));
+            (!slug.is_empty() && to != file.path).then_some((file.path, to))
+        })
+        .collect::<Vec<_>>()
+    {
+        by_target.entry(to).or_default().push(from);
+    }
+
+    let mut renames = vec![];
+    for (to, mut from) in by_target {
+        from.sort();
+        let exists = to.exists() && fs::canonicalize(&to).ok() != fs::canonicalize(&from[0]).ok();
+        if from.len() > 1 || exists {
+            let from = from
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            eprintln!("not renaming {from}, {} would be taken", to.display());
+        } else {
+            renames.push((from.remove(0), to));
+        }
+    }
+    renames.sort();
+    renames
+}
+
+/// The new target of a wikilink to a renamed note, written the same way, by name or by path
+fn renamed_wikilink(
+    vault_path: &Path,
+    target: &str,
+    renames: &[(PathBuf, PathBuf)],
+) -> Option<String> {
+    let (name, extension) = match target.trim().strip_suffix(".md") {
+        Some(name) => (name, ".md"),
+        None => (target.trim(), ""),
+    };
+    renames.iter().find_map(|(from, to)| {
+        let (from_name, to_name) = (link_name(vault_path, from), link_name(vault_path, to));
+        let stem = |path: &Path| path.file_stem().map(|s| s.to_string_lossy().to_string());
+        if name == from_name {
+            Some(format!("{to_name}{extension}"))
+        } else if !name.contains('/') && Some(name.to_string()) == stem(from) {
+            Some(format!("{}{extension}", stem(to)?))
+        } else {
+            None
+        }
+    })
+}
+
+/// The new url of a relative markdown link from the note at `path` to a renamed note
+fn renamed_url(path: &Path, url: &str, renames: &[(PathBuf, PathBuf)]) -> Option<String> {
+    if !is_relative_url(url) {
+        return None;
+    }
+    let (file, fragment) = url.split_once('#').unwrap_or((url, ""));
+    let linked = normalize(&path.parent()?.join(file.replace("%20", " ")));
+    // links can leave out the extension of the note, like `[text](note)`
+    let extensionless = Path::new(file).extension().is_none();
+    let (_, to) = renames.iter().find(|(from, _)| {
+        normalize(from) == linked
+            || (extensionless && normalize(&from.with_extension("")) == linked)
+    })?;
+    let new_name = if extensionless {
+        to.file_stem()?.to_string_lossy()
+    } else {
+        to.file_name()?.to_string_lossy()
+    };
+    let folder = file.rsplit_once('/').map_or("", |(folder, _)| folder);
+    let separator = if folder.is_empty() { "" } else { "/" };
+    let hash = if fragment.is_empty() { "" } else { "#" };
+    Some(format!("{folder}{separator}{new_name}{hash}{fragment}"))
+}
+
+/// The note with its links to renamed notes updated, or `None` if it has none
+fn relink(
+    vault_path: &Path,
+    path: &Path,
+    content: &str,
+    renames: &[(PathBuf, PathBuf)],
+) -> Option<String> {
+    let mut document = MdastDocument::parse(content);
+    let relinked = Cell::new(false);
+    replace_with_or_abort(&mut document.root, |root| {
+        let mut root = map_text(Node::Root(root), &|text: String| {
+            WIKILINK
+                .replace_all(&text, |link: &Captures| {
+                    match renamed_wikilink(vault_path, &link["target"], renames) {
+                        Some(target) => {
+                            relinked.set(true);
+                            format!("[[{target}{}]]", &link["rest"])
+                        }
+                        None => link[0].to_string(),
+                    }
+                })
+                .to_string()
+        });
+        map_urls(&mut root, &|url| {
+            let url = renamed_url(path, url, renames)?;
+            relinked.set(true);
+            Some(url)
+        });
+        let Node::Root(root) = root else {
+            unreachable!()
+        };
+        root
+    });
+
+    relinked.get().then(|| {
+        document.render_with(RenderOptions {
+            ordered_delimiter: OrderedDelimiter::Preserve,
+            source: Some(content),
+            preserve_bullets: true,
+            preserve_indented_code: true,
+            ..Default::default()
+        })
+    })
+}
+
+/// Rename every note to the slug of its title, and the links to it in other notes to match
+///
+/// Links are changed in place, so the changes are to notes at their old path, and should be
+/// written before the notes are renamed.
+#[must_use]
+pub fn rename_to_title(vault_path: &Path, walk: &WalkOptions) -> RenamePlan {
+    let renames = plan_renames(vault_path, walk);
+    if renames.is_empty() {
+        return RenamePlan::default();
+    }
+    let mut changes = iterate_markdown_files(&vault_path.to_path_buf(), walk)
+        .filter_map(|file| {
+            relink(vault_path, &file.path, &file.content, &renames)
+                .map(|content| (file.path, content))
+        })
+        .collect::<Vec<_>>();
+    changes.sort();
+    RenamePlan { renames, changes }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    use crate::util::vault_with;
+
+    #[test]
+    fn titles_are_slugged() {
+        assert_eq!(slug("My Great Note!"), "my-great-note");
+        assert_eq!(slug("  Café -- ünïcode  "), "café-ünïcode");
+        assert_eq!(slug("???"), "");
+    }
+
+    #[test]
+    fn notes_are_renamed_and_links_to_them_updated() {
+        let vault = vault_with(&[
+            (
+                "projects/Untitled 1.md",
+                indoc! {r#"
+                ---
+                title: Garden Plans
+                ---
+                notes
+                "#},
+            ),
+            ("house.md", "# House Work\n"),
+            (
+                "index.md",
+                indoc! {r#"
+                # index
+
+                See [[Untitled 1]], [[projects/Untitled 1#Beds|the garden]] and [[house]].
+                Also [the plans](projects/Untitled%201.md#beds) and [other](other.md).
+                And [the house](house) without its extension.
+                "#},
+            ),
+        ]);
+        let path = |path: &str| vault.path().join(path);
+
+        let plan = rename_to_title(vault.path(), &WalkOptions::default());
+        assert_eq!(
+            plan.renames,
+            vec![
+                (path("house.md"), path("house-work.md")),
+                (
+                    path("projects/Untitled 1.md"),
+                    path("projects/garden-plans.md")
+                ),
+            ]
+        );
+        assert_eq!(
+            plan.changes,
+            vec![(
+                path("index.md"),
+                indoc! {r#"
+                # index
+
+                See [[garden-plans]], [[projects/garden-plans#Beds|the garden]] and [[house-work]].
+                Also [the plans](projects/garden-plans.md#beds) and [other](other.md).
+                And [the house](house-work) without its extension.
+                "#}
+                .to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn clashing_renames_are_skipped() {
+        let vault = vault_with(&[
+            ("a.md", "# Same\n"),
+            ("b.md", "# Same\n"),
+            ("c.md", "# Taken\n"),
+            ("taken.md", "# Taken\n"),
+            ("d.md", "# Dee\n"),
+            ("linked.md", "[[a]] [[b]] [[c]] [[d]]\n"),
+        ]);
+        let path = |path: &str| vault.path().join(path);
+
+        assert_eq!(
+            rename_to_title(vault.path(), &WalkOptions::default()),
+            RenamePlan {
+                renames: vec![(path("d.md"), path("dee.md"))],
+                changes: vec![(path("linked.md"), "[[a]] [[b]] [[c]] [[dee]]\n".to_string())],
+            }
+        );
+    }
+}
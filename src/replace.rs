@@ -0,0 +1,220 @@
+use std::{borrow::Cow, ops::Range, path::PathBuf};
+
+use clap::{Args, ValueEnum};
+use markdown::mdast::{self, Node};
+use rayon::prelude::*;
+use regex::{NoExpand, Regex};
+
+use crate::{
+    error::Result,
+    format_files::is_autolink,
+    markdown_file::{split_block_id, MdastDocument},
+    util::{iterate_markdown_files, WalkOptions},
+};
+
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ReplaceScope {
+    /// Prose, skipping code, urls, frontmatter and block ids
+    #[default]
+    Text,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ReplaceOptions {
+    /// Where in each file to replace
+    #[arg(long = "in", value_enum, default_value_t = ReplaceScope::Text)]
+    pub scope: ReplaceScope,
+
+    /// The text to find
+    #[arg(long)]
+    pub from: String,
+
+    /// The text to replace it with, which can refer to capture groups like `$1` with --regex
+    #[arg(long)]
+    pub to: String,
+
+    /// Treat --from as a regular expression
+    #[arg(long, default_value = "false")]
+    pub regex: bool,
+
+    /// Write the replacements, instead of only showing what would change
+    #[arg(long, default_value = "false")]
+    pub apply: bool,
+}
+
+impl ReplaceOptions {
+    pub fn pattern(&self) -> Result<Regex> {
+        let pattern = if self.regex {
+            Regex::new(&self.from)
+        } else {
+            Regex::new(&regex::escape(&self.from))
+        };
+        Ok(pattern?)
+    }
+
+    fn replace<'t>(&self, pattern: &Regex, text: &'t str) -> Cow<'t, str> {
+        if self.regex {
+            pattern.replace_all(text, self.to.as_str())
+        } else {
+            pattern.replace_all(text, NoExpand(&self.to))
+        }
+    }
+}
+
+/// The source range of every text node that `pattern` matches, with its replacement, leaving
+/// code, urls and the text of autolinks alone
+fn text_edits(
+    nodes: &[Node],
+    source: &str,
+    pattern: &Regex,
+    options: &ReplaceOptions,
+    edits: &mut Vec<(Range<usize>, String)>,
+) {
+    for node in nodes {
+        match node {
+            Node::Text(mdast::Text {
+                position: Some(position),
+                ..
+            }) => {
+                let range = position.start.offset..position.end.offset;
+                let (body, block_id) = split_block_id(&source[range.clone()]);
+                if let Cow::Owned(body) = options.replace(pattern, body) {
+                    edits.push((range, body + block_id));
+                }
+            }
+            Node::Link(link) if is_autolink(link) => {}
+            _ => {
+                if let Some(children) = node.children() {
+                    text_edits(children, source, pattern, options, edits);
+                }
+            }
+        }
+    }
+}
+
+/// Replace in the text of a document, or `None` if nothing matched
+///
+/// Each replacement is spliced into the source where its text was written, so the rest of the
+/// note is left byte for byte the same.
+fn replace_document(content: &str, pattern: &Regex, options: &ReplaceOptions) -> Option<String> {
+    let mut edits = vec![];
+    text_edits(
+        &MdastDocument::parse(content).root.children,
+        content,
+        pattern,
+        options,
+        &mut edits,
+    );
+    if edits.is_empty() {
+        return None;
+    }
+
+    let mut replaced = content.to_string();
+    edits.sort_by_key(|(range, _)| range.start);
+    for (range, text) in edits.into_iter().rev() {
+        replaced.replace_range(range, &text);
+    }
+    Some(replaced)
+}
+
+/// Replace `from` with `to` in the prose of every file in the vault
+pub fn replace<'a>(
+    vault_path: &PathBuf,
+    walk: &WalkOptions,
+    options: &'a ReplaceOptions,
+    pattern: Regex,
+) -> impl ParallelIterator<Item = (PathBuf, String)> + 'a {
+    iterate_markdown_files(vault_path, walk).filter_map(move |file| {
+        replace_document(&file.content, &pattern, options).map(|content| (file.path, content))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    fn options(from: &str, to: &str, regex: bool) -> ReplaceOptions {
+        ReplaceOptions {
+            scope: ReplaceScope::Text,
+            from: from.to_string(),
+            to: to.to_string(),
+            regex,
+            apply: false,
+        }
+    }
+
+    fn replaced(input: &str, options: &ReplaceOptions) -> Option<String> {
+        replace_document(input, &options.pattern().unwrap(), options)
+    }
+
+    #[test]
+    fn replacement_only_changes_prose() {
+        let input = indoc! {r#"
+            ---
+            project: OldName
+            ---
+            # OldName
+
+            Working on OldName, see `OldName::new` and [the OldName docs](https://example.com/OldName).
+            <https://example.com/OldName>
+
+            ```rust
+            let name = "OldName";
+            ```
+
+            * OldName task ^OldName-id
+        "#};
+        assert_eq!(
+            replaced(input, &options("OldName", "NewName", false)).unwrap(),
+            indoc! {r#"
+            ---
+            project: OldName
+            ---
+            # NewName
+
+            Working on NewName, see `OldName::new` and [the NewName docs](https://example.com/OldName).
+            <https://example.com/OldName>
+
+            ```rust
+            let name = "OldName";
+            ```
+
+            * NewName task ^OldName-id
+            "#}
+        );
+    }
+
+    #[test]
+    fn unmatched_documents_are_unchanged() {
+        assert_eq!(
+            replaced(
+                "* untouched `OldName`\n",
+                &options("OldName", "NewName", false)
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn literal_replacement_ignores_regex_syntax() {
+        assert_eq!(
+            replaced("costs $5 (maybe)\n", &options("$5 (maybe)", "$1", false)).unwrap(),
+            "costs $1\n"
+        );
+    }
+
+    #[test]
+    fn regex_replacement_uses_capture_groups() {
+        assert_eq!(
+            replaced(
+                "meet on 2024-01-05, then `2024-02-01`\n",
+                &options(r"(\d{4})-(\d{2})-(\d{2})", "$3.$2.$1", true)
+            )
+            .unwrap(),
+            "meet on 05.01.2024, then `2024-02-01`\n"
+        );
+    }
+}
@@ -0,0 +1,151 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use clap::Args;
+use markdown::mdast::Node;
+use rayon::prelude::*;
+
+use crate::{
+    frontmatter,
+    markdown_file::MdastDocument,
+    util::{body_tags, iterate_markdown_files, WalkOptions},
+};
+
+#[derive(Args, Debug, Clone)]
+pub struct TagsOptions {
+    /// Only list tags used at least this many times
+    #[arg(long, value_name = "N", default_value = "1")]
+    pub min_count: usize,
+
+    /// Also count the tags listed in the `tags` field of the frontmatter
+    #[arg(long, default_value = "false")]
+    pub frontmatter: bool,
+}
+
+/// Every tag used in a note, once per use, including the frontmatter if asked
+fn note_tags(content: &str, options: &TagsOptions) -> Vec<String> {
+    let document = MdastDocument::parse(content);
+    let mut tags = vec![];
+    body_tags(&document.root.children, &mut tags);
+    if options.frontmatter {
+        if let Some(Node::Yaml(yaml)) = document.root.children.first() {
+            tags.extend(frontmatter::tags(&yaml.value));
+        }
+    }
+    tags
+}
+
+/// How often each tag is used, counting every use of a nested tag like `#a/b` towards `a` too
+fn count_tags(tags: impl IntoIterator<Item = String>) -> HashMap<String, usize> {
+    let mut counts = HashMap::new();
+    for tag in tags {
+        let parents = tag.match_indices('/').map(|(i, _)| tag[..i].to_string());
+        for tag in parents.chain([tag.clone()]) {
+            *counts.entry(tag).or_insert(0) += 1;
+        }
+    }
+    counts
+}
+
+/// Tags used at least `min_count` times, most used first and then alphabetically, so nested
+/// tags with the same count follow their parent
+fn frequent_tags(counts: HashMap<String, usize>, min_count: usize) -> Vec<(String, usize)> {
+    let mut tags = counts
+        .into_iter()
+        .filter(|(_, count)| *count >= min_count)
+        .collect::<Vec<_>>();
+    tags.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    tags
+}
+
+/// Print every tag in the vault with how often it's used
+pub fn print_tags(vault_path: &PathBuf, walk: &WalkOptions, options: &TagsOptions) -> Option<i32> {
+    let tags = iterate_markdown_files(vault_path, walk)
+        .flat_map_iter(|file| note_tags(&file.content, options))
+        .collect::<Vec<_>>();
+
+    for (tag, count) in frequent_tags(count_tags(tags), options.min_count) {
+        println!("{count:>6} #{tag}");
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    use crate::util::vault_with;
+
+    fn vault_tags(options: &TagsOptions) -> Vec<(String, usize)> {
+        let vault = vault_with(&[
+            (
+                "a.md",
+                indoc! {r#"
+                ---
+                tags: [project/garden, meta]
+                ---
+                #todo #project/garden
+
+                - [ ] plant #project/garden/beds
+                - [ ] issue #1 isn't a tag
+                "#},
+            ),
+            (
+                "b.md",
+                indoc! {r#"
+                #todo #project/house
+
+                `#code` isn't counted
+
+                ```
+                #nor here
+                ```
+                "#},
+            ),
+        ]);
+
+        let tags = iterate_markdown_files(&vault.path().to_path_buf(), &WalkOptions::default())
+            .flat_map_iter(|file| note_tags(&file.content, options))
+            .collect::<Vec<_>>();
+        frequent_tags(count_tags(tags), options.min_count)
+    }
+
+    fn tags(expected: &[(&str, usize)]) -> Vec<(String, usize)> {
+        expected
+            .iter()
+            .map(|(tag, count)| (tag.to_string(), *count))
+            .collect()
+    }
+
+    #[test]
+    fn tags_are_counted_with_nested_tags_grouped() {
+        let options = TagsOptions {
+            min_count: 1,
+            frontmatter: false,
+        };
+        assert_eq!(
+            vault_tags(&options),
+            tags(&[
+                ("project", 3),
+                ("project/garden", 2),
+                ("todo", 2),
+                ("project/garden/beds", 1),
+                ("project/house", 1),
+            ])
+        );
+    }
+
+    #[test]
+    fn frontmatter_tags_are_counted_when_asked() {
+        let options = TagsOptions {
+            min_count: 2,
+            frontmatter: true,
+        };
+        assert_eq!(
+            vault_tags(&options),
+            tags(&[("project", 4), ("project/garden", 3), ("todo", 2)])
+        );
+    }
+}
@@ -1,14 +1,180 @@
-use std::path::PathBuf;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
+use chrono::{Locale, NaiveDate};
+use clap::Args;
 use lazy_static::lazy_static;
+use markdown::mdast::Node;
 use rayon::prelude::*;
 use regex::Regex;
 use walkdir::{DirEntry, WalkDir};
 
-use crate::markdown_file;
+use crate::{
+    error::{Error, Result},
+    markdown_file::{self, Encoding, MdastDocument},
+    query::{parse_query, Query},
+};
 
 lazy_static! {
     static ref IS_SYNC_CONFLICT: Regex = Regex::new(r"\.sync-conflict-\d+-\d+-").unwrap();
+    static ref IS_ATOMIC_WRITE_TMP: Regex = Regex::new(r"\.tmp\d{20}(\.[^.]+)?$").unwrap();
+    static ref TAG: Regex = Regex::new(r"(?:^|\s)\#([\w\-/]+)").unwrap();
+}
+
+#[derive(Args, Debug, Default, Clone)]
+pub struct WalkOptions {
+    /// Also process hidden files and folders, except for .obsidian
+    #[arg(long, default_value = "false", global = true)]
+    pub include_hidden: bool,
+
+    /// Also process markdown files in the attachment folder set in .obsidian/app.json
+    #[arg(long, default_value = "false", global = true)]
+    pub include_attachments: bool,
+
+    /// Match the tag anywhere in the body of a note, not just before its content
+    #[arg(long, default_value = "false", global = true)]
+    pub tag_anywhere: bool,
+
+    /// The encoding to read and write markdown files with
+    #[arg(long, value_enum, default_value_t = Encoding::Utf8, global = true)]
+    pub encoding: Encoding,
+
+    /// Select notes with a heading matching this regex, like `TODO`, instead of by their tag
+    #[arg(long, value_name = "PATTERN", global = true, value_parser = parse_heading_pattern)]
+    pub select_heading: Option<String>,
+
+    /// Also treat files with this extension as markdown notes, like `txt`
+    #[arg(long = "ext", value_name = "EXT", global = true, value_parser = parse_extension)]
+    pub extensions: Vec<String>,
+
+    /// Only process files matching a query, like `tag:todo and not path:Templates/*`
+    #[arg(long, value_name = "QUERY", global = true, value_parser = parse_query)]
+    pub query: Option<Query>,
+
+    /// The notes the walk has read so far, shared by its clones
+    #[arg(skip)]
+    pub read: Arc<ReadCount>,
+}
+
+/// How many notes, and how many bytes of them, have been read
+#[derive(Debug, Default)]
+pub struct ReadCount {
+    files: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+impl ReadCount {
+    fn add(&self, content: &str) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(content.len(), Ordering::Relaxed);
+    }
+
+    /// The files and bytes read
+    pub fn totals(&self) -> (usize, usize) {
+        (
+            self.files.load(Ordering::Relaxed),
+            self.bytes.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// How dates are shown, in the due listing and the headings of archived todos
+#[derive(Args, Debug, Default, Clone)]
+pub struct DateOptions {
+    /// The strftime format to show dates with, instead of ISO 8601 (`%Y-%m-%d`)
+    #[arg(long, value_name = "FORMAT", global = true)]
+    pub date_format: Option<String>,
+
+    /// The locale of month and weekday names in dates, like `de_DE`, which also names the months
+    /// archived todos are grouped by
+    #[arg(long, value_parser = parse_locale, global = true)]
+    pub locale: Option<Locale>,
+}
+
+fn parse_locale(arg: &str) -> Result<Locale> {
+    Locale::try_from(arg)
+        .map_err(|_| Error::Parse(format!("unknown locale `{arg}`, expected one like `de_DE`")))
+}
+
+impl DateOptions {
+    pub fn format_date(&self, date: NaiveDate) -> String {
+        let format = self.date_format.as_deref().unwrap_or("%Y-%m-%d");
+        date.format_localized(format, self.locale.unwrap_or(Locale::POSIX))
+            .to_string()
+    }
+
+    /// `2024-01`, or with a locale the month's name, like `Januar 2024`
+    pub fn format_month(&self, date: NaiveDate) -> String {
+        match self.locale {
+            Some(locale) => date.format_localized("%B %Y", locale).to_string(),
+            None => date.format("%Y-%m").to_string(),
+        }
+    }
+}
+
+fn parse_extension(arg: &str) -> Result<String> {
+    match arg.trim().trim_start_matches('.') {
+        "" => Err(Error::Parse(
+            "expected an extension, like `txt`".to_string(),
+        )),
+        ext => Ok(ext.to_string()),
+    }
+}
+
+fn parse_heading_pattern(arg: &str) -> Result<String> {
+    markdown_contains_heading(arg).map(|_| arg.to_string())
+}
+
+/// The attachment folder configured in the vault's `.obsidian/app.json`, if it is a fixed folder
+///
+/// Obsidian also allows the vault root (`/`) and paths relative to each note (`./`), which are
+/// full of notes and so are never excluded.
+pub fn attachment_folder(vault_path: &Path) -> Option<PathBuf> {
+    let app_json = fs::read_to_string(vault_path.join(".obsidian").join("app.json")).ok()?;
+    let app: serde_json::Value = serde_json::from_str(&app_json).ok()?;
+    let folder = app
+        .get("attachmentFolderPath")?
+        .as_str()?
+        .trim_end_matches('/');
+
+    if folder.is_empty() || folder.starts_with('.') || folder.starts_with('/') {
+        None
+    } else {
+        Some(vault_path.join(folder))
+    }
+}
+
+/// An obsidian link to the note at `path`, by its path relative to the vault
+///
+/// Wikilinks can't contain `[`, `]`, `|`, `#` or `^`, so notes with those in their path get a
+/// markdown link instead.
+pub fn note_link(vault_path: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(vault_path).unwrap_or(path);
+    let to_slashes = |path: &Path| {
+        path.components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/")
+    };
+    let name = to_slashes(&relative.with_extension(""));
+    if name.contains(['[', ']', '|', '#', '^']) {
+        let text = name.replace('[', "\\[").replace(']', "\\]");
+        format!("[{text}](<{}>)", to_slashes(relative))
+    } else {
+        format!("[[{name}]]")
+    }
+}
+
+/// Whether a link's url is relative, to another file in the vault rather than a website or an
+/// absolute path
+pub fn is_relative_url(url: &str) -> bool {
+    !(url.contains("://") || url.starts_with("mailto:") || url.starts_with('/'))
 }
 
 /// Returns a regex that matches markdown files if they contain the given tag
@@ -16,8 +182,8 @@ lazy_static! {
 /// # Arguments
 ///
 /// * `tag` - The tag to match
-pub fn markdown_contains_tag(tag: &str) -> Result<Regex, regex::Error> {
-    Regex::new(
+pub fn markdown_contains_tag(tag: &str) -> Result<Regex> {
+    Ok(Regex::new(
         format!(
             r"(?sx)^
         (?:                 # match the optional frontmatter section
@@ -31,7 +197,86 @@ pub fn markdown_contains_tag(tag: &str) -> Result<Regex, regex::Error> {
         \#{tag}             # match the arbitrary tag"
         )
         .as_str(),
-    )
+    )?)
+}
+
+/// Returns a matcher for markdown files that have a heading matching the pattern
+///
+/// # Arguments
+///
+/// * `pattern` - A regex the whole text of the heading must match
+pub fn markdown_contains_heading(pattern: &str) -> Result<HeadingMatcher> {
+    Ok(HeadingMatcher {
+        is_heading: Regex::new(format!("^(?:{pattern})$").as_str())?,
+    })
+}
+
+/// Matches markdown files with a heading whose whole text matches a pattern
+///
+/// Only parsed headings count, so heading-like lines in code blocks and the frontmatter never
+/// match.
+pub struct HeadingMatcher {
+    is_heading: Regex,
+}
+
+impl HeadingMatcher {
+    pub fn is_match(&self, content: &str) -> bool {
+        body_contains_heading(
+            &MdastDocument::parse(content).root.children,
+            &self.is_heading,
+        )
+    }
+}
+
+/// Whether the text of any heading in the document matches `is_heading`
+fn body_contains_heading(nodes: &[Node], is_heading: &Regex) -> bool {
+    nodes.iter().any(|node| match node {
+        Node::Heading(_) => is_heading.is_match(&node.to_string()),
+        _ => node.children().map_or(false, |children| {
+            body_contains_heading(children, is_heading)
+        }),
+    })
+}
+
+/// Returns a regex that matches the given tag within a run of text
+pub fn text_contains_tag(tag: &str) -> Result<Regex> {
+    Ok(Regex::new(
+        format!(r"(?:^|\s)\#{}(?:[/\s]|$)", regex::escape(tag)).as_str(),
+    )?)
+}
+
+/// Whether any text in the body of the document matches `is_tag`
+///
+/// Only text nodes are searched, so tags in the frontmatter, code blocks and inline code never
+/// match.
+pub fn body_contains_tag(nodes: &[Node], is_tag: &Regex) -> bool {
+    nodes.iter().any(|node| match node {
+        Node::Text(text) => is_tag.is_match(&text.value),
+        _ => node
+            .children()
+            .map_or(false, |children| body_contains_tag(children, is_tag)),
+    })
+}
+
+/// Every tag in the text nodes of the document, without its `#`
+///
+/// Like `body_contains_tag`, tags in code and the frontmatter are skipped.
+pub fn body_tags(nodes: &[Node], tags: &mut Vec<String>) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => tags.extend(
+                TAG.captures_iter(&text.value)
+                    .map(|c| c[1].trim_end_matches('/').to_string())
+                    // obsidian doesn't treat numbers like #1 as tags
+                    .filter(|tag| !tag.chars().all(|c| c.is_ascii_digit())),
+            ),
+            _ => {
+                if let Some(children) = node.children() {
+                    body_tags(children, tags);
+                }
+            }
+        }
+    }
 }
 
 pub fn is_visible(entry: &DirEntry) -> bool {
@@ -41,6 +286,18 @@ pub fn is_visible(entry: &DirEntry) -> bool {
         .map_or(false, |s| !s.starts_with('.'))
 }
 
+pub fn is_obsidian_config(entry: &DirEntry) -> bool {
+    entry.file_name() == ".obsidian"
+}
+
+/// Matches the temporary files left behind by an interrupted `File::atomic_overwrite`
+pub fn is_atomic_write_tmp(entry: &DirEntry) -> bool {
+    entry
+        .file_name()
+        .to_str()
+        .map_or(false, |s| IS_ATOMIC_WRITE_TMP.is_match(s))
+}
+
 pub fn is_sync_conflict(entry: &DirEntry) -> bool {
     entry
         .file_name()
@@ -48,33 +305,215 @@ pub fn is_sync_conflict(entry: &DirEntry) -> bool {
         .map_or(false, |s| IS_SYNC_CONFLICT.is_match(s))
 }
 
+/// Matches markdown files containing a tag, either before their content or anywhere with
+/// `--tag-anywhere`
+pub struct TagMatcher {
+    is_tagged: Regex,
+    is_tag: Regex,
+    tag_anywhere: bool,
+}
+
+impl TagMatcher {
+    pub fn new(tag: &str, walk: &WalkOptions) -> Result<Self> {
+        Ok(Self {
+            is_tagged: markdown_contains_tag(tag)?,
+            is_tag: text_contains_tag(tag)?,
+            tag_anywhere: walk.tag_anywhere,
+        })
+    }
+
+    pub fn is_match(&self, content: &str) -> bool {
+        if self.tag_anywhere {
+            body_contains_tag(&MdastDocument::parse(content).root.children, &self.is_tag)
+        } else {
+            self.is_tagged.is_match(content)
+        }
+    }
+}
+
+/// Whether `iterate_tagged_markdown_files` walks a file with this content
+pub fn tagged_file_matcher(walk: &WalkOptions, tag: &str) -> impl Fn(&str) -> bool + Send + Sync {
+    let matcher = TagMatcher::new(tag, walk).unwrap();
+    let heading = walk
+        .select_heading
+        .as_deref()
+        .map(|pattern| markdown_contains_heading(pattern).unwrap());
+
+    move |content| match &heading {
+        Some(heading) => heading.is_match(content),
+        None => matcher.is_match(content),
+    }
+}
+
 pub fn iterate_tagged_markdown_files(
     vault_path: &PathBuf,
+    walk: &WalkOptions,
     tag: &str,
 ) -> impl ParallelIterator<Item = markdown_file::File> {
-    let is_tagged = markdown_contains_tag(tag).unwrap();
+    let is_tagged = tagged_file_matcher(walk, tag);
+    iterate_markdown_files(vault_path, walk).filter(move |f| is_tagged(&f.content))
+}
+
+/// What the walk does with a markdown file or folder in the vault
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WalkStatus {
+    Read,
+    Skipped(&'static str),
+    Failed(String),
+}
+
+/// Why the walk skips an entry of the vault, or `None` if it is walked
+fn skip_reason(
+    entry: &DirEntry,
+    walk: &WalkOptions,
+    attachments: Option<&Path>,
+) -> Option<&'static str> {
+    // the vault root itself may be a hidden folder
+    if !(entry.depth() == 0 || walk.include_hidden || is_visible(entry)) {
+        Some("hidden")
+    } else if attachments.map_or(false, |a| entry.path() == a) {
+        Some("attachment folder")
+    } else if is_obsidian_config(entry) {
+        Some("obsidian config")
+    } else if is_sync_conflict(entry) {
+        Some("sync conflict")
+    } else if is_atomic_write_tmp(entry) {
+        Some("interrupted write")
+    } else {
+        None
+    }
+}
+
+/// Whether the file is a note, by being markdown or having one of the `--ext` extensions
+fn is_markdown(path: &Path, extensions: &[String]) -> bool {
+    path.extension()
+        .map(|ext| ext == "md" || extensions.iter().any(|e| ext == e.as_str()))
+        .unwrap_or(false)
+}
+
+fn is_excalidraw(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|f| f.to_str())
+        .map(|s| s.ends_with(".excalidraw.md"))
+        .unwrap_or(false)
+}
+
+fn walked_attachment_folder(vault_path: &Path, walk: &WalkOptions) -> Option<PathBuf> {
+    if walk.include_attachments {
+        None
+    } else {
+        attachment_folder(vault_path)
+    }
+}
 
-    iterate_markdown_files(vault_path).filter(move |f| is_tagged.is_match(f.content.as_str()))
+/// Every markdown file and skipped folder in the vault with what the walk does with it, sorted
+/// by path
+pub fn walk_vault(vault_path: &Path, walk: &WalkOptions) -> Vec<(PathBuf, WalkStatus)> {
+    let attachments = walked_attachment_folder(vault_path, walk);
+    let mut statuses = vec![];
+    let mut entries = WalkDir::new(vault_path).into_iter();
+    while let Some(entry) = entries.next() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                let path = e.path().unwrap_or(vault_path).to_path_buf();
+                statuses.push((path, WalkStatus::Failed(e.to_string())));
+                continue;
+            }
+        };
+        let path = entry.path().to_path_buf();
+        let is_dir = entry.file_type().is_dir();
+
+        if let Some(reason) = skip_reason(&entry, walk, attachments.as_deref()) {
+            if is_dir {
+                entries.skip_current_dir();
+            }
+            if is_dir || is_markdown(&path, &walk.extensions) {
+                statuses.push((path, WalkStatus::Skipped(reason)));
+            }
+        } else if entry.file_type().is_file() && is_markdown(&path, &walk.extensions) {
+            let status = if is_excalidraw(&path) {
+                WalkStatus::Skipped("excalidraw drawing")
+            } else {
+                match walk.encoding.read(&path) {
+                    Ok(_) => WalkStatus::Read,
+                    Err(e) => WalkStatus::Failed(e.to_string()),
+                }
+            };
+            statuses.push((path, status));
+        }
+    }
+    statuses.sort_by(|a, b| a.0.cmp(&b.0));
+    statuses
+}
+
+/// Whether the walk would read the markdown file at this path, checking the same filters as
+/// `iterate_markdown_files` without walking the rest of the vault
+///
+/// The `--query` is left to the caller, since it needs the file's content.
+pub fn is_walked(vault_path: &Path, path: &Path, walk: &WalkOptions) -> bool {
+    let attachments = walked_attachment_folder(vault_path, walk);
+    is_markdown(path, &walk.extensions)
+        && !is_excalidraw(path)
+        && WalkDir::new(vault_path)
+            .into_iter()
+            .filter_entry(|e| {
+                path.starts_with(e.path()) && skip_reason(e, walk, attachments.as_deref()).is_none()
+            })
+            .any(|e| e.map_or(false, |e| e.path() == path && e.file_type().is_file()))
 }
 
 pub fn iterate_markdown_files(
     vault_path: &PathBuf,
+    walk: &WalkOptions,
 ) -> impl ParallelIterator<Item = markdown_file::File> {
+    let attachments = walked_attachment_folder(vault_path, walk);
+    let encoding = walk.encoding;
+    let query = walk.query.as_ref().map(|query| query.predicate(walk));
+    let vault_path_buf = vault_path.clone();
+    let walk = walk.clone();
+    let extensions = walk.extensions.clone();
+    let read = walk.read.clone();
     WalkDir::new(vault_path)
         .into_iter()
-        .filter_entry(|e| is_visible(e) && !is_sync_conflict(e))
+        .filter_entry(move |e| skip_reason(e, &walk, attachments.as_deref()).is_none())
         .map(Result::unwrap)
         .par_bridge()
         .filter(|e| e.file_type().is_file())
-        .filter(|e| e.path().extension().map(|ext| ext == "md").unwrap_or(false))
-        .filter(|e| {
-            !e.path()
-                .file_name()
-                .and_then(|f| f.to_str())
-                .map(|s| s.ends_with(".excalidraw.md"))
-                .unwrap_or(false)
+        .filter(move |e| is_markdown(e.path(), &extensions) && !is_excalidraw(e.path()))
+        .filter_map(
+            move |e| match markdown_file::File::at_path(e.path().to_path_buf(), encoding) {
+                Ok(file) => {
+                    read.add(&file.content);
+                    Some(file)
+                }
+                Err(err) => {
+                    eprintln!("Skipping {}: {err}", e.path().display());
+                    None
+                }
+            },
+        )
+        .filter(move |file| {
+            query.as_ref().map_or(true, |query| {
+                let relative = file
+                    .path
+                    .strip_prefix(&vault_path_buf)
+                    .unwrap_or(&file.path);
+                query(relative, &file.content)
+            })
         })
-        .map(|e| markdown_file::File::at_path(e.path().to_path_buf()).unwrap())
+}
+
+/// A temporary vault with these files, by path relative to it
+#[cfg(test)]
+pub(crate) fn vault_with(files: &[(&str, &str)]) -> tempfile::TempDir {
+    let vault = tempfile::tempdir().unwrap();
+    for (path, content) in files {
+        let path = vault.path().join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+    vault
 }
 
 #[cfg(test)]
@@ -83,9 +522,11 @@ mod tests {
 
     use indoc::indoc;
     use lazy_static::lazy_static;
+    use tempfile::TempDir;
 
     lazy_static! {
         static ref IS_TAGGED_TODO: Regex = markdown_contains_tag("todo").unwrap();
+        static ref IS_TODO_TAG: Regex = text_contains_tag("todo").unwrap();
     }
 
     macro_rules! markdown_contains_tag_tests {
@@ -104,6 +545,199 @@ mod tests {
       }
     }
 
+    macro_rules! body_contains_tag_tests {
+      ($($name:ident $document:expr)*) => {
+      $(
+          #[test]
+          fn $name() {
+              assert!(stringify!($name).starts_with("tagged") || stringify!($name).starts_with("untagged"));
+
+              let document = MdastDocument::parse(indoc!($document));
+              let is_tagged = stringify!($name).starts_with("tagged");
+              assert_eq!(is_tagged, body_contains_tag(&document.root.children, &IS_TODO_TAG));
+          }
+      )*
+      }
+    }
+
+    fn walked_files(vault: &TempDir, walk: &WalkOptions) -> Vec<PathBuf> {
+        let mut files = iterate_markdown_files(&vault.path().to_path_buf(), walk)
+            .map(|f| f.path.strip_prefix(vault.path()).unwrap().to_path_buf())
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn hidden_files_are_skipped_by_default() {
+        let vault = vault_with(&[("note.md", "note"), (".templates/foo.md", "foo")]);
+        assert_eq!(
+            walked_files(&vault, &WalkOptions::default()),
+            vec![PathBuf::from("note.md")]
+        );
+    }
+
+    #[test]
+    fn hidden_files_are_walked_with_include_hidden() {
+        let vault = vault_with(&[
+            ("note.md", "note"),
+            (".templates/foo.md", "foo"),
+            (".obsidian/snippet.md", "config"),
+            ("note.sync-conflict-20230101-120000-ABCDEF.md", "conflict"),
+            ("note.tmp00000000000000001234.md", "tmp"),
+        ]);
+        assert_eq!(
+            walked_files(
+                &vault,
+                &WalkOptions {
+                    include_hidden: true,
+                    ..Default::default()
+                }
+            ),
+            vec![PathBuf::from(".templates/foo.md"), PathBuf::from("note.md")]
+        );
+    }
+
+    #[test]
+    fn attachment_folder_is_skipped() {
+        let vault = vault_with(&[
+            (
+                ".obsidian/app.json",
+                r#"{"attachmentFolderPath": "Files/Attachments"}"#,
+            ),
+            ("note.md", "note"),
+            ("Files/other.md", "other"),
+            ("Files/Attachments/stray.md", "stray"),
+        ]);
+        assert_eq!(
+            walked_files(&vault, &WalkOptions::default()),
+            vec![PathBuf::from("Files/other.md"), PathBuf::from("note.md")]
+        );
+        assert_eq!(
+            walked_files(
+                &vault,
+                &WalkOptions {
+                    include_attachments: true,
+                    ..Default::default()
+                }
+            ),
+            vec![
+                PathBuf::from("Files/Attachments/stray.md"),
+                PathBuf::from("Files/other.md"),
+                PathBuf::from("note.md")
+            ]
+        );
+    }
+
+    #[test]
+    fn dates_are_formatted_for_the_locale() {
+        let date = NaiveDate::from_ymd_opt(2024, 3, 5).unwrap();
+        assert_eq!(DateOptions::default().format_date(date), "2024-03-05");
+        assert_eq!(DateOptions::default().format_month(date), "2024-03");
+
+        let options = DateOptions {
+            date_format: Some("%A %e %B %Y".to_string()),
+            locale: Some(parse_locale("de_DE").unwrap()),
+        };
+        assert_eq!(options.format_date(date), "Dienstag  5 März 2024");
+        assert_eq!(options.format_month(date), "März 2024");
+
+        let options = DateOptions {
+            locale: None,
+            ..options
+        };
+        assert_eq!(options.format_date(date), "Tuesday  5 March 2024");
+        assert!(parse_locale("not_A_locale").is_err());
+    }
+
+    #[test]
+    fn walk_counts_the_notes_it_reads() {
+        let vault = vault_with(&[
+            ("note.md", "note"),
+            ("other.md", "other"),
+            (".templates/foo.md", "foo"),
+            ("image.png", "png"),
+        ]);
+        let walk = WalkOptions::default();
+        walked_files(&vault, &walk.clone());
+
+        assert_eq!(walk.read.totals(), (2, "note".len() + "other".len()));
+    }
+
+    #[test]
+    fn files_that_fail_to_decode_are_skipped() {
+        let vault = vault_with(&[("note.md", "note")]);
+        fs::write(vault.path().join("latin1.md"), b"caf\xe9").unwrap();
+
+        assert_eq!(
+            walked_files(&vault, &WalkOptions::default()),
+            vec![PathBuf::from("note.md")]
+        );
+        assert_eq!(
+            walked_files(
+                &vault,
+                &WalkOptions {
+                    encoding: Encoding::Latin1,
+                    ..Default::default()
+                }
+            ),
+            vec![PathBuf::from("latin1.md"), PathBuf::from("note.md")]
+        );
+    }
+
+    #[test]
+    fn is_walked_matches_the_walk() {
+        let vault = vault_with(&[
+            ("note.md", "note"),
+            ("folder/nested.md", "nested"),
+            (".templates/foo.md", "foo"),
+            ("note.tmp00000000000000001234.md", "tmp"),
+            ("report.tmp2024.md", "report"),
+            ("drawing.excalidraw.md", "drawing"),
+            ("image.png", "png"),
+        ]);
+        let is_walked = |path: &str| {
+            is_walked(
+                vault.path(),
+                &vault.path().join(path),
+                &WalkOptions::default(),
+            )
+        };
+        assert!(is_walked("note.md"));
+        assert!(is_walked("folder/nested.md"));
+        assert!(!is_walked(".templates/foo.md"));
+        assert!(!is_walked("note.tmp00000000000000001234.md"));
+        assert!(is_walked("report.tmp2024.md"));
+        assert!(!is_walked("drawing.excalidraw.md"));
+        assert!(!is_walked("image.png"));
+        assert!(!is_walked("missing.md"));
+    }
+
+    #[test]
+    fn note_links() {
+        let vault = Path::new("/vault");
+        let link = |path: &str| note_link(vault, &vault.join(path));
+        assert_eq!(link("note.md"), "[[note]]");
+        assert_eq!(link("folder/my note.md"), "[[folder/my note]]");
+        assert_eq!(link("Issue #1.md"), "[Issue #1](<Issue #1.md>)");
+        assert_eq!(
+            link("a [draft] | b.md"),
+            r"[a \[draft\] | b](<a [draft] | b.md>)"
+        );
+    }
+
+    #[test]
+    fn relative_attachment_folder_is_not_skipped() {
+        let vault = vault_with(&[
+            (".obsidian/app.json", r#"{"attachmentFolderPath": "./"}"#),
+            ("note.md", "note"),
+        ]);
+        assert_eq!(
+            walked_files(&vault, &WalkOptions::default()),
+            vec![PathBuf::from("note.md")]
+        );
+    }
+
     markdown_contains_tag_tests! {
       untagged_document r#"
         - [ ] test
@@ -141,6 +775,16 @@ mod tests {
       tagged_document_with_sub_tag r#"
         #todo/sub-tag
         - [ ] test
+    "#
+      untagged_document_with_tag_in_code_block r#"
+        ```
+        #todo
+        ```
+        - [ ] test
+    "#
+      untagged_document_with_tag_in_inline_code r#"
+        `#todo`
+        - [ ] test
     "#
       untagged_document_with_tag_after_header r#"
         # Header
@@ -148,4 +792,144 @@ mod tests {
         #todo some stuff
       "#
     }
+
+    body_contains_tag_tests! {
+      tagged_anywhere_after_header r#"
+        # Header
+
+        #todo some stuff
+      "#
+      tagged_anywhere_at_bottom r#"
+        - [ ] test
+
+        #other #todo
+      "#
+      tagged_anywhere_with_sub_tag r#"
+        some stuff #todo/sub-tag
+      "#
+      untagged_anywhere_with_longer_tag r#"
+        #todolist
+      "#
+      untagged_anywhere_with_tag_in_frontmatter r#"
+        ---
+        title: #todo test
+        ---
+        - [ ] test
+      "#
+      untagged_anywhere_with_tag_in_code_block r#"
+        ```
+        #todo
+        ```
+      "#
+      untagged_anywhere_with_tag_in_indented_code r#"
+        some stuff
+
+            #todo
+      "#
+      untagged_anywhere_with_tag_in_inline_code r#"
+        use `#todo` to mark a note as a todo list
+      "#
+      tagged_anywhere_next_to_inline_code r#"
+        use `#todo` to mark a note, like this one #todo
+      "#
+    }
+
+    #[test]
+    fn select_heading_selects_files_by_heading() {
+        let vault = vault_with(&[
+            (
+                "heading.md",
+                indoc! {r#"
+                # Notes
+
+                ## TODO
+
+                - [ ] a
+                "#},
+            ),
+            (
+                "closed.md",
+                indoc! {r#"
+                # TODO #
+
+                - [ ] b
+                "#},
+            ),
+            (
+                "tagged.md",
+                indoc! {r#"
+                #todo
+
+                - [ ] c
+                "#},
+            ),
+            (
+                "prose.md",
+                indoc! {r#"
+                a TODO in prose
+
+                # TODO later
+                "#},
+            ),
+            (
+                "code.md",
+                indoc! {r#"
+                ---
+                # TODO
+                ---
+
+                ```
+                # TODO
+                ```
+                "#},
+            ),
+        ]);
+        let mut files = iterate_tagged_markdown_files(
+            &vault.path().to_path_buf(),
+            &WalkOptions {
+                select_heading: Some("TODO".to_string()),
+                ..Default::default()
+            },
+            "todo",
+        )
+        .map(|f| f.path.strip_prefix(vault.path()).unwrap().to_path_buf())
+        .collect::<Vec<_>>();
+        files.sort();
+
+        assert_eq!(
+            files,
+            vec![PathBuf::from("closed.md"), PathBuf::from("heading.md")]
+        );
+        assert!(parse_heading_pattern("TODO|Tasks").is_ok());
+        assert!(parse_heading_pattern("(unclosed").is_err());
+    }
+
+    #[test]
+    fn tag_anywhere_selects_tagged_files() {
+        let vault = vault_with(&[
+            ("top.md", "#todo\n\n- [ ] a\n"),
+            ("bottom.md", "- [ ] b\n\n#todo\n"),
+            ("none.md", "- [ ] c\n"),
+        ]);
+        let tagged = |walk: &WalkOptions| {
+            let mut files =
+                iterate_tagged_markdown_files(&vault.path().to_path_buf(), walk, "todo")
+                    .map(|f| f.path.strip_prefix(vault.path()).unwrap().to_path_buf())
+                    .collect::<Vec<_>>();
+            files.sort();
+            files
+        };
+
+        assert_eq!(
+            tagged(&WalkOptions::default()),
+            vec![PathBuf::from("top.md")]
+        );
+        assert_eq!(
+            tagged(&WalkOptions {
+                tag_anywhere: true,
+                ..Default::default()
+            }),
+            vec![PathBuf::from("bottom.md"), PathBuf::from("top.md")]
+        );
+    }
 }
@@ -0,0 +1,419 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+
+use crate::{
+    error::Result,
+    markdown_file::File,
+    util::{is_walked, WalkOptions},
+};
+
+/// Collects the paths of file events until each has gone `delay` without another event
+pub struct Debouncer {
+    delay: Duration,
+    pending: HashMap<PathBuf, Instant>,
+}
+
+impl Debouncer {
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn event(&mut self, path: PathBuf, now: Instant) {
+        self.pending.insert(path, now);
+    }
+
+    /// Remove and return every path that has been quiet for the delay, sorted
+    pub fn ready(&mut self, now: Instant) -> Vec<PathBuf> {
+        let mut ready = self
+            .pending
+            .iter()
+            .filter(|(_, &last)| now.duration_since(last) >= self.delay)
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+        for path in &ready {
+            self.pending.remove(path);
+        }
+        ready.sort();
+        ready
+    }
+
+    /// Remove and return every pending path, regardless of the delay
+    pub fn flush(&mut self) -> Vec<PathBuf> {
+        let mut paths = self
+            .pending
+            .drain()
+            .map(|(path, _)| path)
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+
+    /// When the next pending path will be ready, if there is one
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().min().map(|&last| last + self.delay)
+    }
+}
+
+/// The files marksage wrote itself, so the events of its own atomic writes aren't taken for edits
+///
+/// An atomic write shows up as a create of the temporary file and a rename over the original,
+/// and the rename can be reported well after the write. Events for a written path are ignored for
+/// `window` after the write, and an event that arrives later only causes a reformat that finds
+/// nothing left to change, so it can't start a loop.
+pub struct RecentWrites {
+    window: Duration,
+    written: HashMap<PathBuf, Instant>,
+}
+
+impl RecentWrites {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            written: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, path: &Path, now: Instant) {
+        self.written.insert(canonical(path), now);
+    }
+
+    /// Whether an event for the path at `now` is from a write recorded within the window,
+    /// forgetting writes older than the window
+    pub fn is_own_write(&mut self, path: &Path, now: Instant) -> bool {
+        let window = self.window;
+        self.written
+            .retain(|_, &mut written| now.saturating_duration_since(written) < window);
+        self.written.contains_key(&canonical(path))
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// The markdown files an event may have changed, ignoring the temporary files of atomic writes and
+/// anything else the walk would skip, short of the `--query` that needs their content
+fn changed_paths<'a>(
+    event: &'a Event,
+    vault_path: &'a Path,
+    walk: &'a WalkOptions,
+) -> impl Iterator<Item = &'a PathBuf> + 'a {
+    let is_change = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_));
+    event
+        .paths
+        .iter()
+        .filter(move |path| is_change && is_walked(vault_path, path, walk))
+}
+
+/// Call `on_change` with each markdown file from the events once it stops changing, and it
+/// matches the `--query`, until the sender of the events is dropped
+///
+/// `on_change` returns whether it wrote the file, so the events of that write can be ignored for
+/// `suppress_window`.
+pub fn handle_events(
+    events: Receiver<notify::Result<Event>>,
+    vault_path: &Path,
+    walk: &WalkOptions,
+    delay: Duration,
+    suppress_window: Duration,
+    mut on_change: impl FnMut(File) -> bool,
+) {
+    let mut debouncer = Debouncer::new(delay);
+    let mut recent_writes = RecentWrites::new(suppress_window);
+    let query = walk.query.as_ref().map(|query| query.predicate(walk));
+    let mut change = |path: PathBuf, recent_writes: &mut RecentWrites| {
+        let file = match File::at_path(path.clone(), walk.encoding) {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("Skipping {}: {e}", path.display());
+                return;
+            }
+        };
+        let relative = path.strip_prefix(vault_path).unwrap_or(&path);
+        if query
+            .as_ref()
+            .map_or(true, |query| query(relative, &file.content))
+            && on_change(file)
+        {
+            recent_writes.record(&path, Instant::now());
+        }
+    };
+    loop {
+        let received = match debouncer.next_deadline() {
+            Some(deadline) => {
+                events.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            }
+            None => events.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(Ok(event)) => {
+                let now = Instant::now();
+                for path in changed_paths(&event, vault_path, walk) {
+                    if !recent_writes.is_own_write(path, now) {
+                        debouncer.event(path.clone(), now);
+                    }
+                }
+            }
+            Ok(Err(e)) => eprintln!("Watch error: {e}"),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                for path in debouncer.flush() {
+                    change(path, &mut recent_writes);
+                }
+                return;
+            }
+        }
+        for path in debouncer.ready(Instant::now()) {
+            change(path, &mut recent_writes);
+        }
+    }
+}
+
+/// Watch the vault, calling `on_change` with each markdown file the walk would read once it has
+/// gone `delay` without changing, ignoring the files it wrote for `suppress_window`
+pub fn watch(
+    vault_path: &Path,
+    walk: &WalkOptions,
+    delay: Duration,
+    suppress_window: Duration,
+    on_change: impl FnMut(File) -> bool,
+) -> Result<()> {
+    // events are reported with canonical paths, so match the vault against them the same way
+    let vault_path = vault_path.canonicalize()?;
+    let (sender, events) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    watcher.watch(&vault_path, RecursiveMode::Recursive)?;
+    handle_events(events, &vault_path, walk, delay, suppress_window, on_change);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use notify::event::{CreateKind, DataChange, ModifyKind, RemoveKind, RenameMode};
+    use tempfile::TempDir;
+
+    use crate::{
+        format_files::{format_file, FormatOptions},
+        markdown_file::{Encoding, File},
+        query::parse_query,
+    };
+
+    fn event(kind: EventKind, paths: &[&Path]) -> notify::Result<Event> {
+        Ok(Event {
+            kind,
+            paths: paths.iter().map(|p| p.to_path_buf()).collect(),
+            attrs: Default::default(),
+        })
+    }
+
+    #[test]
+    fn debouncer_waits_for_quiet() {
+        let start = Instant::now();
+        let delay = Duration::from_millis(100);
+        let mut debouncer = Debouncer::new(delay);
+        debouncer.event(PathBuf::from("a.md"), start);
+        debouncer.event(PathBuf::from("a.md"), start + Duration::from_millis(80));
+        debouncer.event(PathBuf::from("b.md"), start);
+
+        assert_eq!(
+            debouncer.ready(start + Duration::from_millis(120)),
+            vec![PathBuf::from("b.md")]
+        );
+        assert_eq!(
+            debouncer.next_deadline(),
+            Some(start + Duration::from_millis(180))
+        );
+        assert_eq!(
+            debouncer.ready(start + Duration::from_millis(180)),
+            vec![PathBuf::from("a.md")]
+        );
+        assert_eq!(debouncer.next_deadline(), None);
+    }
+
+    #[test]
+    fn an_edit_is_reformatted_once() {
+        let vault = TempDir::new().unwrap();
+        let vault_path = vault.path().canonicalize().unwrap();
+        let note = vault_path.join("note.md");
+        let tmp = vault_path.join("note.tmp00000000000000001234.md");
+        fs::write(&note, "a--b\n").unwrap();
+
+        let (sender, events) = mpsc::channel();
+        let modify = EventKind::Modify(ModifyKind::Data(DataChange::Content));
+        // a burst of saves from the editor
+        for _ in 0..3 {
+            sender.send(event(modify, &[&note])).unwrap();
+        }
+        // the events of a write by marksage itself, to the temp file and the rename over the note
+        sender
+            .send(event(EventKind::Create(CreateKind::File), &[&tmp]))
+            .unwrap();
+        sender.send(event(modify, &[&tmp])).unwrap();
+        drop(sender);
+
+        let options = FormatOptions::default();
+        let mut reformats = vec![];
+        handle_events(
+            events,
+            &vault_path,
+            &WalkOptions::default(),
+            Duration::from_millis(10),
+            Duration::from_secs(1),
+            |file| {
+                format_file(file, &options, &[])
+                    .map(|(path, content)| {
+                        File::atomic_overwrite(&path, content, Encoding::Utf8).unwrap();
+                        reformats.push(path);
+                    })
+                    .is_some()
+            },
+        );
+
+        assert_eq!(reformats, vec![note.clone()]);
+        assert_eq!(fs::read_to_string(&note).unwrap(), "a—b\n");
+    }
+
+    #[test]
+    fn events_the_walk_would_skip_are_ignored() {
+        let vault = TempDir::new().unwrap();
+        let vault_path = vault.path().canonicalize().unwrap();
+        fs::create_dir(vault_path.join(".templates")).unwrap();
+        let paths = [
+            vault_path.join(".templates/hidden.md"),
+            vault_path.join("note.tmp00000000000000001234.md"),
+            vault_path.join("image.png"),
+            vault_path.join("note.md"),
+        ];
+        for path in &paths {
+            fs::write(path, "content").unwrap();
+        }
+
+        let (sender, events) = mpsc::channel();
+        for path in &paths {
+            sender
+                .send(event(
+                    EventKind::Modify(ModifyKind::Name(RenameMode::To)),
+                    &[path],
+                ))
+                .unwrap();
+        }
+        sender
+            .send(event(EventKind::Remove(RemoveKind::File), &[&paths[3]]))
+            .unwrap();
+        drop(sender);
+
+        let mut changed = vec![];
+        handle_events(
+            events,
+            &vault_path,
+            &WalkOptions::default(),
+            Duration::ZERO,
+            Duration::ZERO,
+            |file| {
+                changed.push(file.path);
+                false
+            },
+        );
+        assert_eq!(changed, vec![vault_path.join("note.md")]);
+    }
+
+    #[test]
+    fn events_outside_the_query_are_ignored() {
+        let vault = TempDir::new().unwrap();
+        let vault_path = vault.path().canonicalize().unwrap();
+        fs::create_dir(vault_path.join("Templates")).unwrap();
+        let paths = [
+            vault_path.join("Templates/daily.md"),
+            vault_path.join("note.md"),
+        ];
+        for path in &paths {
+            fs::write(path, "a--b\n").unwrap();
+        }
+
+        let (sender, events) = mpsc::channel();
+        let modify = EventKind::Modify(ModifyKind::Data(DataChange::Content));
+        for path in &paths {
+            sender.send(event(modify, &[path])).unwrap();
+        }
+        drop(sender);
+
+        let mut changed = vec![];
+        handle_events(
+            events,
+            &vault_path,
+            &WalkOptions {
+                query: Some(parse_query("not path:Templates/*").unwrap()),
+                ..Default::default()
+            },
+            Duration::ZERO,
+            Duration::ZERO,
+            |file| {
+                changed.push(file.path);
+                false
+            },
+        );
+        assert_eq!(changed, vec![vault_path.join("note.md")]);
+    }
+
+    /// Format the note whenever `handle_events` reports it, sending the rename event of the write
+    /// the way the watcher would, and return how many times it was reported and written
+    fn reformat_with_rename_event(suppress_window: Duration) -> (usize, usize) {
+        let vault = TempDir::new().unwrap();
+        let vault_path = vault.path().canonicalize().unwrap();
+        let note = vault_path.join("note.md");
+        fs::write(&note, "a--b\n").unwrap();
+
+        let (sender, events) = mpsc::channel();
+        let modify = EventKind::Modify(ModifyKind::Data(DataChange::Content));
+        sender.send(event(modify, &[&note])).unwrap();
+
+        let mut sender = Some(sender);
+        let (mut reported, mut written) = (0, 0);
+        handle_events(
+            events,
+            &vault_path,
+            &WalkOptions::default(),
+            Duration::ZERO,
+            suppress_window,
+            |file| {
+                reported += 1;
+                let wrote = format_file(file, &FormatOptions::default(), &[])
+                    .map(|(path, content)| {
+                        File::atomic_overwrite(&path, content, Encoding::Utf8).unwrap();
+                        written += 1;
+                    })
+                    .is_some();
+                if let Some(sender) = sender.take() {
+                    let rename = EventKind::Modify(ModifyKind::Name(RenameMode::Both));
+                    let tmp = vault_path.join("note.tmp00000000000000001234.md");
+                    sender.send(event(rename, &[&tmp, &note])).unwrap();
+                }
+                wrote
+            },
+        );
+        (reported, written)
+    }
+
+    #[test]
+    fn own_writes_are_not_reported() {
+        assert_eq!(reformat_with_rename_event(Duration::from_secs(60)), (1, 1));
+    }
+
+    #[test]
+    fn own_writes_after_the_window_are_reported_without_another_write() {
+        assert_eq!(reformat_with_rename_event(Duration::ZERO), (2, 1));
+    }
+}